@@ -1,47 +1,1602 @@
-use tokio::time::{sleep, Duration};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration, Instant};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+// 快照格式的版本号，格式不兼容时递增
+const SNAPSHOT_VERSION: u32 = 3;
+
+// 离散程度的下限（相对期望值的比例，期望值小于 1 时按 1 计算），
+// 避免恒定序列上的浮点误差被放大成无穷大的分数
+const MIN_RELATIVE_SPREAD: f32 = 1e-3;
+
+// 可选的异常检测算法
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum DetectionAlgorithm {
+    // 滚动均值 / 标准差的 z-score 检验
+    ZScore,
+    // 中位数绝对偏差（对离群点更稳健）
+    MedianAbsoluteDeviation,
+    // 指数加权移动平均，alpha 为平滑系数
+    Ewma { alpha: f32 },
+    // 四分位距围栏（Tukey fences）
+    InterquartileRange,
+    // Holt-Winters 季节性残差
+    HoltWinters { alpha: f32, beta: f32, gamma: f32, season_length: usize },
+}
+
+// 需要检测的偏离方向
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum ThresholdMode {
+    // 只检测高于期望范围的值
+    Upper,
+    // 只检测低于期望范围的值
+    Lower,
+    // 双侧检测
+    Both,
+}
 
 // 定义一个结构体来封装异常检测的配置
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 struct AnomalyDetectorConfig {
     threshold: f32,
     // 异常检测的阈值
     window_size: usize,
-    // 滑动窗口大小
+    // 滑动窗口最多保留的样本数
+    window_duration: Option<Duration>,
+    // 按时间的窗口长度，设置后按样本时间戳淘汰过期样本
+    max_lateness: Duration,
+    // 允许乱序到达的最大延迟，更早的样本会被丢弃
+    algorithm: DetectionAlgorithm,
+    // 使用的检测算法
+    mode: ThresholdMode,
+    // 阈值的检测方向
+}
+
+// 检测模型给出的基线：期望值与离散程度
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Baseline {
+    center: f32,
+    spread: f32,
+}
+
+impl Baseline {
+    // 评分使用的离散程度，不小于 MIN_RELATIVE_SPREAD 规定的下限
+    fn effective_spread(&self) -> f32 {
+        self.spread.max(MIN_RELATIVE_SPREAD * self.center.abs().max(1.0))
+    }
+
+    // 给定阈值下的期望范围 (下界, 上界)
+    fn expected_range(&self, threshold: f32) -> (f32, f32) {
+        let spread = self.effective_spread();
+        (self.center - threshold * spread, self.center + threshold * spread)
+    }
+
+    // 以离散程度为单位的有符号偏离，正值表示高于期望
+    fn score(&self, value: f32) -> f32 {
+        (value - self.center) / self.effective_spread()
+    }
+}
+
+// 异常值相对期望范围的方向
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+enum Direction {
+    Above,
+    Below,
+}
+
+// 正常样本的处理结果
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SampleStatus {
+    // 已加入窗口
+    Accepted,
+    // 超出延迟上限，已丢弃
+    Late,
+}
+
+// 检测时窗口的统计信息
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+struct WindowStats {
+    mean: f32,
+    std_dev: f32,
+    count: usize,
+}
+
+// 单个异常点的结构化报告，供下游告警排序和解释
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct AnomalyReport {
+    value: f32,
+    // 被判定为异常的值
+    expected_range: (f32, f32),
+    // 期望范围 (下界, 上界)
+    score: f32,
+    // 以离散程度为单位的有符号偏离
+    direction: Direction,
+    // 偏离方向
+    window: WindowStats,
+    // 检测时窗口的统计信息
+    timestamp: SystemTime,
+    // 检测时间
+}
+
+impl fmt::Display for AnomalyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let direction = match self.direction {
+            Direction::Above => "above",
+            Direction::Below => "below",
+        };
+        write!(
+            f,
+            "value {} is {} expected range [{:.3}, {:.3}] (score {:.2}, window mean {:.3}, std dev {:.3}, n = {})",
+            self.value,
+            direction,
+            self.expected_range.0,
+            self.expected_range.1,
+            self.score,
+            self.window.mean,
+            self.window.std_dev,
+            self.window.count,
+        )
+    }
+}
+
+impl ThresholdMode {
+    // 根据检测方向判断分数是否越界，返回越界方向
+    fn check(&self, score: f32, threshold: f32) -> Option<Direction> {
+        let above = score > threshold;
+        let below = score < -threshold;
+        match self {
+            ThresholdMode::Upper if above => Some(Direction::Above),
+            ThresholdMode::Lower if below => Some(Direction::Below),
+            ThresholdMode::Both if above => Some(Direction::Above),
+            ThresholdMode::Both if below => Some(Direction::Below),
+            _ => None,
+        }
+    }
+}
+
+// 可替换的异常检测模型
+//
+// 基于窗口的模型直接使用检测器维护的滑动窗口；
+// 有状态的模型（EWMA、Holt-Winters）在 observe 中更新自身状态。
+trait Detector: Send {
+    // 根据当前状态给出基线，样本不足时返回 None
+    fn baseline(&self, window: &SampleWindow) -> Option<Baseline>;
+
+    // 将新值纳入模型状态
+    fn observe(&mut self, _value: f32) {}
+
+    // 按时间顺序到达但被判为异常、未纳入模型的样本，季节性模型需要据此推进相位
+    fn skip(&mut self) {}
+
+    // 导出模型内部状态，只依赖窗口的模型无需保存
+    fn save_state(&self) -> serde_json::Value {
+        serde_json::Value::Null
+    }
+
+    // 从快照恢复模型内部状态
+    fn load_state(&mut self, _state: serde_json::Value) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// 滚动均值 / 标准差
+struct ZScoreDetector;
+
+impl Detector for ZScoreDetector {
+    fn baseline(&self, window: &SampleWindow) -> Option<Baseline> {
+        if window.len() < 2 {
+            return None;
+        }
+        Some(Baseline { center: window.stats.mean(), spread: window.stats.std_dev() })
+    }
+}
+
+// 中位数绝对偏差，按 1.4826 缩放以便与标准差可比
+struct MadDetector;
+
+impl Detector for MadDetector {
+    fn baseline(&self, window: &SampleWindow) -> Option<Baseline> {
+        if window.len() < 3 {
+            return None;
+        }
+        let mut sorted: Vec<f32> = window.values().collect();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        let median = quantile(&sorted, 0.5);
+        let mut deviations: Vec<f32> = sorted.iter().map(|v| (v - median).abs()).collect();
+        deviations.sort_by(|a, b| a.total_cmp(b));
+        let mad = quantile(&deviations, 0.5);
+        Some(Baseline { center: median, spread: 1.4826 * mad })
+    }
+}
+
+// 指数加权均值与方差
+#[derive(Serialize, Deserialize)]
+struct EwmaDetector {
+    alpha: f32,
+    mean: Option<f32>,
+    variance: f32,
+    count: usize,
+}
+
+impl EwmaDetector {
+    fn new(alpha: f32) -> Self {
+        EwmaDetector { alpha: alpha.clamp(f32::EPSILON, 1.0), mean: None, variance: 0.0, count: 0 }
+    }
+}
+
+impl Detector for EwmaDetector {
+    fn baseline(&self, _window: &SampleWindow) -> Option<Baseline> {
+        // 热身期约为 1 / alpha 个样本，之前的方差估计不可靠
+        let warmup = (1.0 / self.alpha).ceil().max(2.0) as usize;
+        match self.mean {
+            Some(mean) if self.count >= warmup => Some(Baseline { center: mean, spread: self.variance.sqrt() }),
+            _ => None,
+        }
+    }
+
+    fn observe(&mut self, value: f32) {
+        self.count += 1;
+        match self.mean {
+            None => self.mean = Some(value),
+            Some(mean) => {
+                let diff = value - mean;
+                let increment = self.alpha * diff;
+                self.mean = Some(mean + increment);
+                self.variance = (1.0 - self.alpha) * (self.variance + diff * increment);
+            }
+        }
+    }
+
+    fn save_state(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or(serde_json::Value::Null)
+    }
+
+    fn load_state(&mut self, state: serde_json::Value) -> Result<(), String> {
+        *self = serde_json::from_value(state).map_err(|e| format!("Invalid EWMA state: {}", e))?;
+        Ok(())
+    }
+}
+
+// 四分位距围栏：以中枢 (Q1 + Q3) / 2 为中心、IQR 为单位，
+// 经典的 Q3 + 1.5 * IQR 围栏对应阈值 2.0
+struct IqrDetector;
+
+impl Detector for IqrDetector {
+    fn baseline(&self, window: &SampleWindow) -> Option<Baseline> {
+        if window.len() < 4 {
+            return None;
+        }
+        let mut sorted: Vec<f32> = window.values().collect();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        let q1 = quantile(&sorted, 0.25);
+        let q3 = quantile(&sorted, 0.75);
+        Some(Baseline { center: (q1 + q3) / 2.0, spread: q3 - q1 })
+    }
+}
+
+// Holt-Winters 加法模型，以残差的指数加权标准差作为离散程度
+#[derive(Serialize, Deserialize)]
+struct HoltWintersDetector {
+    alpha: f32,
+    beta: f32,
+    gamma: f32,
+    level: f32,
+    trend: f32,
+    seasonals: Vec<f32>,
+    residual_variance: f32,
+    // 已观察的样本数，首个季节用于初始化
+    count: usize,
+    // 季节相位，异常样本虽不参与更新也会推进相位
+    phase: usize,
+}
+
+impl HoltWintersDetector {
+    fn new(alpha: f32, beta: f32, gamma: f32, season_length: usize) -> Self {
+        HoltWintersDetector {
+            alpha,
+            beta,
+            gamma,
+            level: 0.0,
+            trend: 0.0,
+            seasonals: vec![0.0; season_length.max(1)],
+            residual_variance: 0.0,
+            count: 0,
+            phase: 0,
+        }
+    }
+
+    fn season_index(&self) -> usize {
+        self.phase % self.seasonals.len()
+    }
+
+    fn forecast(&self) -> f32 {
+        self.level + self.trend + self.seasonals[self.season_index()]
+    }
+}
+
+impl Detector for HoltWintersDetector {
+    fn baseline(&self, _window: &SampleWindow) -> Option<Baseline> {
+        // 需要一个完整季节初始化，再加一个季节积累残差
+        if self.count < 2 * self.seasonals.len() {
+            return None;
+        }
+        Some(Baseline { center: self.forecast(), spread: self.residual_variance.sqrt() })
+    }
+
+    fn observe(&mut self, value: f32) {
+        let season_length = self.seasonals.len();
+        let index = self.season_index();
+
+        if self.count < season_length {
+            // 第一个季节：累计水平，并暂存原始值作为季节分量
+            self.seasonals[index] = value;
+            self.level += value / season_length as f32;
+            if self.count + 1 == season_length {
+                let level = self.level;
+                for seasonal in self.seasonals.iter_mut() {
+                    *seasonal -= level;
+                }
+            }
+        } else {
+            let residual = value - self.forecast();
+            self.residual_variance = if self.count < 2 * season_length {
+                // 热身期内取残差平方的简单平均
+                let n = (self.count - season_length) as f32;
+                (self.residual_variance * n + residual * residual) / (n + 1.0)
+            } else {
+                (1.0 - self.alpha) * self.residual_variance + self.alpha * residual * residual
+            };
+
+            let previous_level = self.level;
+            let seasonal = self.seasonals[index];
+            self.level = self.alpha * (value - seasonal) + (1.0 - self.alpha) * (self.level + self.trend);
+            self.trend = self.beta * (self.level - previous_level) + (1.0 - self.beta) * self.trend;
+            self.seasonals[index] = self.gamma * (value - self.level) + (1.0 - self.gamma) * seasonal;
+        }
+
+        self.count += 1;
+        self.phase += 1;
+    }
+
+    fn skip(&mut self) {
+        self.phase += 1;
+    }
+
+    fn save_state(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or(serde_json::Value::Null)
+    }
+
+    fn load_state(&mut self, state: serde_json::Value) -> Result<(), String> {
+        let restored: HoltWintersDetector =
+            serde_json::from_value(state).map_err(|e| format!("Invalid Holt-Winters state: {}", e))?;
+        if restored.seasonals.len() != self.seasonals.len() {
+            return Err("Holt-Winters season length does not match the configuration".to_string());
+        }
+        *self = restored;
+        Ok(())
+    }
+}
+
+impl DetectionAlgorithm {
+    // 根据配置创建对应的检测模型
+    fn build(&self) -> Box<dyn Detector> {
+        match *self {
+            DetectionAlgorithm::ZScore => Box::new(ZScoreDetector),
+            DetectionAlgorithm::MedianAbsoluteDeviation => Box::new(MadDetector),
+            DetectionAlgorithm::Ewma { alpha } => Box::new(EwmaDetector::new(alpha)),
+            DetectionAlgorithm::InterquartileRange => Box::new(IqrDetector),
+            DetectionAlgorithm::HoltWinters { alpha, beta, gamma, season_length } => {
+                Box::new(HoltWintersDetector::new(alpha, beta, gamma, season_length))
+            }
+        }
+    }
+}
+
+// Welford 算法维护的滚动均值和方差，支持移除样本，
+// 内部使用 f64 累加以减少长时间运行后的误差
+#[derive(Debug, Clone, Copy, Default)]
+struct RunningStats {
+    count: usize,
+    mean: f64,
+    m2: f64,
+}
+
+impl RunningStats {
+    fn push(&mut self, value: f32) {
+        let value = value as f64;
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    fn remove(&mut self, value: f32) {
+        if self.count <= 1 {
+            // 窗口清空时重置，顺便消除累计误差
+            *self = RunningStats::default();
+            return;
+        }
+        let value = value as f64;
+        let delta = value - self.mean;
+        self.count -= 1;
+        self.mean -= delta / self.count as f64;
+        self.m2 = (self.m2 - delta * (value - self.mean)).max(0.0);
+    }
+
+    fn mean(&self) -> f32 {
+        self.mean as f32
+    }
+
+    // 样本标准差
+    fn std_dev(&self) -> f32 {
+        if self.count < 2 {
+            return 0.0;
+        }
+        (self.m2 / (self.count - 1) as f64).sqrt() as f32
+    }
+}
+
+// 按时间戳排序的滑动窗口
+#[derive(Debug, Clone, Default)]
+struct SampleWindow {
+    samples: VecDeque<(SystemTime, f32)>,
+    stats: RunningStats,
+}
+
+impl SampleWindow {
+    fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    fn values(&self) -> impl Iterator<Item = f32> + '_ {
+        self.samples.iter().map(|(_, value)| *value)
+    }
+
+    // 窗口内最新的时间戳
+    fn newest(&self) -> Option<SystemTime> {
+        self.samples.back().map(|(timestamp, _)| *timestamp)
+    }
+
+    // 按时间戳插入样本，乱序样本插入到对应位置
+    fn insert(&mut self, timestamp: SystemTime, value: f32) {
+        let position = self.samples.partition_point(|(t, _)| *t <= timestamp);
+        self.samples.insert(position, (timestamp, value));
+        self.stats.push(value);
+    }
+
+    // 移除最早的样本
+    fn pop_oldest(&mut self) {
+        if let Some((_, value)) = self.samples.pop_front() {
+            self.stats.remove(value);
+        }
+    }
+
+    // 移除时间戳早于 cutoff 的样本
+    fn expire_before(&mut self, cutoff: SystemTime) {
+        while matches!(self.samples.front(), Some((timestamp, _)) if *timestamp < cutoff) {
+            self.pop_oldest();
+        }
+    }
+}
+
+// 已排序数据的线性插值分位数
+fn quantile(sorted: &[f32], q: f32) -> f32 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let position = q * (sorted.len() - 1) as f32;
+    let lower = position.floor() as usize;
+    let upper = position.ceil() as usize;
+    let fraction = position - lower as f32;
+    sorted[lower] + (sorted[upper] - sorted[lower]) * fraction
+}
+
+// 单个检测器的状态快照
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DetectorSnapshot {
+    version: u32,
+    config: AnomalyDetectorConfig,
+    current_window: Vec<(SystemTime, f32)>,
+    rolling_mean: f32,
+    rolling_std_dev: f32,
+    model_state: serde_json::Value,
+    // 检测模型的内部状态
+    late_samples: u64,
+    // 因超出延迟上限而丢弃的样本数
+}
+
+// 以 JSON 写入快照文件，先写临时文件再重命名，避免留下半个文件
+async fn write_snapshot_file<T: Serialize>(path: &Path, snapshot: &T) -> Result<(), String> {
+    let json = serde_json::to_vec(snapshot).map_err(|e| format!("Failed to serialize snapshot: {}", e))?;
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, json)
+        .await
+        .map_err(|e| format!("Failed to write {}: {}", tmp_path.display(), e))?;
+    fs::rename(&tmp_path, path)
+        .await
+        .map_err(|e| format!("Failed to replace {}: {}", path.display(), e))
+}
+
+// 读取快照文件
+async fn read_snapshot_file<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<T, String> {
+    let json = fs::read(path).await.map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    serde_json::from_slice(&json).map_err(|e| format!("Invalid snapshot {}: {}", path.display(), e))
 }
-anomaly_detection_config!();
 
 // 实现异常检测算法的主要结构体
 struct AnomalyDetector {
     config: AnomalyDetectorConfig,
     // 异常检测的配置
-    current_window: Vec<f32>,
+    detector: Box<dyn Detector>,
+    // 当前使用的检测模型
+    current_window: SampleWindow,
     // 保存当前窗口内的数据
     rolling_mean: f32,
     // 滚动平均值
     rolling_std_dev: f32,
     // 滚动标准差
+    late_samples: u64,
+    // 因超出延迟上限而丢弃的样本数
 }
-anomaly_detector!();
 
 impl AnomalyDetector {
     // 创建一个新的异常检测器实例
     fn new(config: AnomalyDetectorConfig) -> Self {
         AnomalyDetector {
+            detector: config.algorithm.build(),
+            current_window: SampleWindow::default(),
             config,
-            current_window: Vec::with_capacity(config.window_size),
             rolling_mean: 0.0,
             rolling_std_dev: 0.0,
+            late_samples: 0,
         }
     }
 
-    // 添加新的数据点到异常检测器
-    async fn add_data_point(&mut self, value: f32) -> Result<(), String> {
-        // 检查数据是否超出阈值
-        if value > self.rolling_mean + self.config.threshold * self.rolling_std_dev {
-            return Err("Value exceeds the anomaly threshold".to_string());
+    // 添加新的数据点到异常检测器，异常点以报告形式返回且不进入窗口
+    //
+    // 乱序样本只要不早于最新样本 max_lateness 就会插入到窗口的正确位置，
+    // 更早的样本被丢弃并计入 late_samples。
+    async fn add_data_point(&mut self, value: f32, timestamp: SystemTime) -> Result<SampleStatus, AnomalyReport> {
+        if let Some(newest) = self.current_window.newest() {
+            if timestamp + self.config.max_lateness < newest {
+                self.late_samples += 1;
+                return Ok(SampleStatus::Late);
+            }
+        }
+
+        // 检查数据是否超出阈值，异常值不进入模型但仍占据一个时间步
+        if let Some(report) = self.evaluate(value, timestamp) {
+            self.detector.skip();
+            return Err(report);
         }
 
+        // 将新值添加到窗口并淘汰过期或超出容量的样本
+        self.current_window.insert(timestamp, value);
+        self.expire_samples();
+        // 有状态的模型按到达顺序更新
+        self.detector.observe(value);
+
         // 更新滚动平均值和标准差
-        self.update_rolling_stats(value).await;
+        self.update_rolling_stats().await;
+
+        Ok(SampleStatus::Accepted)
+    }
+
+    // 用当前模型评估新值，越界时生成异常报告
+    fn evaluate(&self, value: f32, timestamp: SystemTime) -> Option<AnomalyReport> {
+        let baseline = self.detector.baseline(&self.current_window)?;
+        let score = baseline.score(value);
+        let direction = self.config.mode.check(score, self.config.threshold)?;
+        Some(AnomalyReport {
+            value,
+            expected_range: baseline.expected_range(self.config.threshold),
+            score,
+            direction,
+            window: self.window_stats(),
+            timestamp,
+        })
+    }
+
+    // 当前窗口的统计信息
+    fn window_stats(&self) -> WindowStats {
+        WindowStats {
+            mean: self.rolling_mean,
+            std_dev: self.rolling_std_dev,
+            count: self.current_window.len(),
+        }
+    }
+
+    // 按时间和容量淘汰窗口中的旧样本
+    fn expire_samples(&mut self) {
+        if let (Some(duration), Some(newest)) = (self.config.window_duration, self.current_window.newest()) {
+            if let Some(cutoff) = newest.checked_sub(duration) {
+                self.current_window.expire_before(cutoff);
+            }
+        }
+        while self.current_window.len() > self.config.window_size {
+            self.current_window.pop_oldest();
+        }
+    }
+
+    // 因超出延迟上限而丢弃的样本数
+    fn late_samples(&self) -> u64 {
+        self.late_samples
+    }
+
+    // 导出当前状态的快照
+    fn snapshot(&self) -> DetectorSnapshot {
+        DetectorSnapshot {
+            version: SNAPSHOT_VERSION,
+            config: self.config,
+            current_window: self.current_window.samples.iter().copied().collect(),
+            rolling_mean: self.rolling_mean,
+            rolling_std_dev: self.rolling_std_dev,
+            model_state: self.detector.save_state(),
+            late_samples: self.late_samples,
+        }
+    }
+
+    // 用当前配置从快照重建检测器
+    //
+    // 配置可能在重启之间被修改：算法不变时恢复模型状态，否则模型重新学习；
+    // 窗口只保留在新配置下仍有效的样本，统计量随之重新计算。
+    fn restore(snapshot: DetectorSnapshot, config: AnomalyDetectorConfig) -> Result<Self, String> {
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(format!("Unsupported snapshot version {}", snapshot.version));
+        }
+        let mut detector = AnomalyDetector::new(config);
+        if snapshot.config.algorithm == config.algorithm {
+            detector.detector.load_state(snapshot.model_state)?;
+        }
+        for (timestamp, value) in snapshot.current_window {
+            detector.current_window.insert(timestamp, value);
+        }
+        detector.expire_samples();
+        detector.rolling_mean = detector.current_window.stats.mean();
+        detector.rolling_std_dev = detector.current_window.stats.std_dev();
+        detector.late_samples = snapshot.late_samples;
+        Ok(detector)
+    }
+
+    // 从窗口的增量统计中读取滚动统计量
+    async fn update_rolling_stats(&mut self) {
+        self.rolling_mean = self.current_window.stats.mean();
+        self.rolling_std_dev = self.current_window.stats.std_dev();
+    }
+}
+
+// 流水线中传递的单个样本
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    value: f32,
+    timestamp: SystemTime,
+}
+
+// 发送到告警输出的异常事件
+#[derive(Debug, Clone, Serialize)]
+struct AnomalyEvent {
+    series: String,
+    // 序列名称
+    report: AnomalyReport,
+    // 异常报告
+    suppressed: u32,
+    // 上一次告警之后被抑制的重复异常数
+}
+
+// 告警输出
+#[async_trait]
+trait AlertSink: Send {
+    // 输出一个异常事件
+    async fn emit(&mut self, event: &AnomalyEvent) -> Result<(), String>;
+
+    // 流水线结束时调用，用于刷新缓冲
+    async fn close(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// 输出到标准输出
+struct StdoutSink;
+
+#[async_trait]
+impl AlertSink for StdoutSink {
+    async fn emit(&mut self, event: &AnomalyEvent) -> Result<(), String> {
+        if event.suppressed > 0 {
+            println!("[{}] {} ({} similar alerts suppressed)", event.series, event.report, event.suppressed);
+        } else {
+            println!("[{}] {}", event.series, event.report);
+        }
+        Ok(())
+    }
+}
+
+// 以 JSON Lines 格式追加写入文件
+struct JsonLinesSink {
+    file: File,
+}
+
+impl JsonLinesSink {
+    async fn open(path: &Path) -> Result<Self, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+        Ok(JsonLinesSink { file })
+    }
+}
+
+#[async_trait]
+impl AlertSink for JsonLinesSink {
+    async fn emit(&mut self, event: &AnomalyEvent) -> Result<(), String> {
+        let mut line = serde_json::to_vec(event).map_err(|e| format!("Failed to serialize event: {}", e))?;
+        line.push(b'\n');
+        self.file.write_all(&line).await.map_err(|e| format!("Failed to write event: {}", e))
+    }
+
+    async fn close(&mut self) -> Result<(), String> {
+        self.file.flush().await.map_err(|e| format!("Failed to flush events: {}", e))
+    }
+}
+
+// 以 JSON 请求体 POST 到本地 HTTP 端点
+struct WebhookSink {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl WebhookSink {
+    fn new(url: &str, timeout: Duration) -> Result<Self, String> {
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .build()
+            .map_err(|e| format!("Failed to create HTTP client: {}", e))?;
+        Ok(WebhookSink { client, url: url.to_string() })
+    }
+}
+
+#[async_trait]
+impl AlertSink for WebhookSink {
+    async fn emit(&mut self, event: &AnomalyEvent) -> Result<(), String> {
+        let response = self
+            .client
+            .post(&self.url)
+            .json(event)
+            .send()
+            .await
+            .map_err(|e| format!("Webhook request to {} failed: {}", self.url, e))?;
+        if !response.status().is_success() {
+            return Err(format!("Webhook {} returned {}", self.url, response.status()));
+        }
+        Ok(())
+    }
+}
+
+// 将异常分发到各个输出，并抑制冷却期内同方向的重复告警
+struct AlertDispatcher {
+    sinks: Vec<Box<dyn AlertSink>>,
+    cooldown: Duration,
+    // 同方向告警的最小间隔（按样本时间计算）
+    last_alert: Option<(SystemTime, Direction)>,
+    suppressed: u32,
+    last_suppressed: Option<(String, AnomalyReport)>,
+    // 最近一次被抑制的异常，关闭时作为汇总告警发出
+}
+
+impl AlertDispatcher {
+    fn new(sinks: Vec<Box<dyn AlertSink>>, cooldown: Duration) -> Self {
+        AlertDispatcher { sinks, cooldown, last_alert: None, suppressed: 0, last_suppressed: None }
+    }
+
+    // 分发一个异常报告，被抑制时返回 false
+    async fn dispatch(&mut self, series: &str, report: AnomalyReport) -> bool {
+        if let Some((last_time, last_direction)) = self.last_alert {
+            let elapsed = report.timestamp.duration_since(last_time).unwrap_or_default();
+            if last_direction == report.direction && elapsed < self.cooldown {
+                self.suppressed += 1;
+                self.last_suppressed = Some((series.to_string(), report));
+                return false;
+            }
+        }
+
+        self.last_alert = Some((report.timestamp, report.direction));
+        let event = AnomalyEvent { series: series.to_string(), report, suppressed: self.suppressed };
+        self.suppressed = 0;
+        self.last_suppressed = None;
+        self.emit(&event).await;
+        true
+    }
+
+    // 发送到所有输出，单个输出失败不影响其他输出
+    async fn emit(&mut self, event: &AnomalyEvent) {
+        for sink in self.sinks.iter_mut() {
+            if let Err(e) = sink.emit(event).await {
+                eprintln!("Alert sink error: {}", e);
+            }
+        }
+    }
+
+    // 关闭前发出仍被抑制的异常：以最近一次异常为报告，其余计入抑制数
+    async fn close(&mut self) {
+        if let Some((series, report)) = self.last_suppressed.take() {
+            let event = AnomalyEvent { series, report, suppressed: self.suppressed - 1 };
+            self.suppressed = 0;
+            self.emit(&event).await;
+        }
+        for sink in self.sinks.iter_mut() {
+            if let Err(e) = sink.close().await {
+                eprintln!("Alert sink error: {}", e);
+            }
+        }
+    }
+}
+
+// 流水线运行结束时的统计
+#[derive(Debug, Default, Clone, Copy)]
+struct RunSummary {
+    samples: u64,
+    anomalies: u64,
+    alerts: u64,
+    suppressed: u64,
+    late: u64,
+}
+
+impl AnomalyDetector {
+    // 从通道持续消费样本，直到所有发送端关闭
+    async fn run(&mut self, series: &str, mut rx: mpsc::Receiver<Sample>, dispatcher: &mut AlertDispatcher) -> RunSummary {
+        let mut summary = RunSummary::default();
+        while let Some(sample) = rx.recv().await {
+            summary.samples += 1;
+            match self.add_data_point(sample.value, sample.timestamp).await {
+                Ok(SampleStatus::Accepted) => {}
+                Ok(SampleStatus::Late) => summary.late += 1,
+                Err(report) => {
+                    summary.anomalies += 1;
+                    if dispatcher.dispatch(series, report).await {
+                        summary.alerts += 1;
+                    } else {
+                        summary.suppressed += 1;
+                    }
+                }
+            }
+        }
+        dispatcher.close().await;
+        summary
+    }
+}
+
+// 时间序列的标识：指标名加一组标签
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+struct SeriesKey {
+    metric: String,
+    labels: BTreeMap<String, String>,
+}
+
+impl SeriesKey {
+    fn new(metric: &str) -> Self {
+        SeriesKey { metric: metric.to_string(), labels: BTreeMap::new() }
+    }
+
+    // 追加一个标签
+    fn with_label(mut self, name: &str, value: &str) -> Self {
+        self.labels.insert(name.to_string(), value.to_string());
+        self
+    }
+}
+
+impl fmt::Display for SeriesKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.metric)?;
+        if !self.labels.is_empty() {
+            let labels: Vec<String> = self.labels.iter().map(|(k, v)| format!("{}=\"{}\"", k, v)).collect();
+            write!(f, "{{{}}}", labels.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+// 注册表的快照，包含所有序列的检测器状态
+#[derive(Debug, Serialize, Deserialize)]
+struct RegistrySnapshot {
+    version: u32,
+    series: Vec<(SeriesKey, DetectorSnapshot)>,
+}
+
+// 注册表中每个序列的检测器及其最后活跃时间
+struct SeriesEntry {
+    detector: AnomalyDetector,
+    last_seen: Instant,
+}
 
-        // 将新值添加到窗口并检查窗口大小
-        if self.current_window.len() == self.config.window_size { {
\ No newline at end of file
+// 按 (指标, 标签) 管理多个检测器的注册表
+//
+// 外层锁只在查找或创建序列时短暂持有，每个序列有自己的锁，
+// 因此不同序列的 ingest 可以在多个 tokio 任务中并发执行。
+#[derive(Clone)]
+struct DetectorRegistry {
+    default_config: AnomalyDetectorConfig,
+    // 未单独配置的指标使用的默认配置
+    templates: Arc<HashMap<String, AnomalyDetectorConfig>>,
+    // 按指标名配置的检测器模板
+    series: Arc<Mutex<HashMap<SeriesKey, Arc<Mutex<SeriesEntry>>>>>,
+    // 已创建的序列
+}
+
+impl DetectorRegistry {
+    // 创建注册表，templates 为按指标名的配置模板
+    fn new(default_config: AnomalyDetectorConfig, templates: HashMap<String, AnomalyDetectorConfig>) -> Self {
+        DetectorRegistry {
+            default_config,
+            templates: Arc::new(templates),
+            series: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // 指标对应的配置模板
+    fn config_for(&self, metric: &str) -> AnomalyDetectorConfig {
+        self.templates.get(metric).copied().unwrap_or(self.default_config)
+    }
+
+    // 获取序列的检测器，不存在时按模板惰性创建
+    async fn entry(&self, key: &SeriesKey) -> Arc<Mutex<SeriesEntry>> {
+        let mut series = self.series.lock().await;
+        if let Some(entry) = series.get(key) {
+            return entry.clone();
+        }
+        let entry = Arc::new(Mutex::new(SeriesEntry {
+            detector: AnomalyDetector::new(self.config_for(&key.metric)),
+            last_seen: Instant::now(),
+        }));
+        series.insert(key.clone(), entry.clone());
+        entry
+    }
+
+    // 将一个数据点送入对应序列的检测器
+    async fn ingest(&self, key: &SeriesKey, value: f32, timestamp: SystemTime) -> Result<SampleStatus, AnomalyReport> {
+        let entry = self.entry(key).await;
+        let mut entry = entry.lock().await;
+        entry.last_seen = Instant::now();
+        entry.detector.add_data_point(value, timestamp).await
+    }
+
+    // 当前管理的序列数量
+    async fn len(&self) -> usize {
+        self.series.lock().await.len()
+    }
+
+    // 移除超过 max_idle 未收到数据的序列，返回移除的数量
+    async fn evict_idle(&self, max_idle: Duration) -> usize {
+        let now = Instant::now();
+        let mut series = self.series.lock().await;
+        let before = series.len();
+        let mut idle = Vec::new();
+        for (key, entry) in series.iter() {
+            // 正在被使用的序列视为活跃
+            if let Ok(entry) = entry.try_lock() {
+                if now.duration_since(entry.last_seen) > max_idle {
+                    idle.push(key.clone());
+                }
+            }
+        }
+        for key in idle {
+            series.remove(&key);
+        }
+        before - series.len()
+    }
+
+    // 导出所有序列的快照
+    async fn snapshot(&self) -> RegistrySnapshot {
+        let entries: Vec<(SeriesKey, Arc<Mutex<SeriesEntry>>)> =
+            self.series.lock().await.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        let mut series = Vec::with_capacity(entries.len());
+        for (key, entry) in entries {
+            series.push((key, entry.lock().await.detector.snapshot()));
+        }
+        RegistrySnapshot { version: SNAPSHOT_VERSION, series }
+    }
+
+    // 将所有序列的状态保存到本地文件
+    async fn save_to_file(&self, path: &Path) -> Result<(), String> {
+        write_snapshot_file(path, &self.snapshot().await).await
+    }
+
+    // 从本地文件恢复序列，返回恢复的序列数量，检测器使用当前的配置模板
+    async fn restore_from_file(&self, path: &Path) -> Result<usize, String> {
+        let snapshot: RegistrySnapshot = read_snapshot_file(path).await?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(format!("Unsupported snapshot version {}", snapshot.version));
+        }
+        let mut series = self.series.lock().await;
+        let count = snapshot.series.len();
+        for (key, detector) in snapshot.series {
+            let detector = AnomalyDetector::restore(detector, self.config_for(&key.metric))?;
+            let entry = SeriesEntry { detector, last_seen: Instant::now() };
+            series.insert(key, Arc::new(Mutex::new(entry)));
+        }
+        Ok(count)
+    }
+
+    // 启动后台任务，定期将状态写入检查点文件
+    fn spawn_checkpointer(&self, path: PathBuf, interval: Duration) -> JoinHandle<()> {
+        let registry = self.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(interval).await;
+                if let Err(e) = registry.save_to_file(&path).await {
+                    eprintln!("Checkpoint failed: {}", e);
+                }
+            }
+        })
+    }
+
+    // 启动后台任务，定期清理空闲序列
+    fn spawn_evictor(&self, interval: Duration, max_idle: Duration) -> JoinHandle<()> {
+        let registry = self.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(interval).await;
+                let evicted = registry.evict_idle(max_idle).await;
+                if evicted > 0 {
+                    println!("Evicted {} idle series", evicted);
+                }
+            }
+        })
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let config = AnomalyDetectorConfig {
+        threshold: 3.0,
+        window_size: 1000,
+        window_duration: Some(Duration::from_secs(5 * 60)),
+        max_lateness: Duration::from_secs(10),
+        algorithm: DetectionAlgorithm::MedianAbsoluteDeviation,
+        mode: ThresholdMode::Both,
+    };
+    let mut detector = AnomalyDetector::new(config);
+
+    // 模拟不规则采样的数据流，包含一个突增、一个突降、一个乱序样本和一个过晚的样本
+    let start = SystemTime::now();
+    let samples = [
+        (0, 10.0), (7, 10.5), (9, 9.8), (20, 10.2), (21, 10.1), (35, 9.9), (33, 10.3),
+        (40, 10.0), (41, 42.0), (55, 10.2), (12, 10.4), (60, 1.5),
+    ];
+    for (offset_secs, value) in samples {
+        let timestamp = start + Duration::from_secs(offset_secs);
+        match detector.add_data_point(value, timestamp).await {
+            Ok(SampleStatus::Accepted) => println!("Value {} at +{}s accepted", value, offset_secs),
+            Ok(SampleStatus::Late) => println!("Value {} at +{}s arrived too late", value, offset_secs),
+            Err(report) => println!("Anomaly detected: {}", report),
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+    println!("Dropped {} late samples", detector.late_samples());
+
+    // 通道流水线：持续的高值只在冷却期结束后再次告警
+    let (tx, rx) = mpsc::channel(64);
+    let producer = tokio::spawn(async move {
+        let start = SystemTime::now();
+        for i in 0..60u64 {
+            let value = if (30..45).contains(&i) { 25.0 } else { 10.0 + (i % 3) as f32 * 0.2 };
+            let sample = Sample { value, timestamp: start + Duration::from_secs(i) };
+            if tx.send(sample).await.is_err() {
+                break;
+            }
+        }
+        // tx 在此处被丢弃，流水线随之结束
+    });
+
+    let mut sinks: Vec<Box<dyn AlertSink>> = vec![Box::new(StdoutSink)];
+    match JsonLinesSink::open(Path::new("anomalies.jsonl")).await {
+        Ok(sink) => sinks.push(Box::new(sink)),
+        Err(e) => println!("{}", e),
+    }
+    match WebhookSink::new("http://127.0.0.1:8080/alerts", Duration::from_secs(2)) {
+        Ok(sink) => sinks.push(Box::new(sink)),
+        Err(e) => println!("{}", e),
+    }
+    let mut dispatcher = AlertDispatcher::new(sinks, Duration::from_secs(10));
+    let mut stream_detector = AnomalyDetector::new(AnomalyDetectorConfig { mode: ThresholdMode::Upper, ..config });
+    let summary = stream_detector.run("demo_metric", rx, &mut dispatcher).await;
+    let _ = producer.await;
+    println!("Pipeline finished: {:?}", summary);
+
+    // 多序列：延迟指标使用 EWMA，其余指标使用默认配置
+    let mut templates = HashMap::new();
+    templates.insert(
+        "http_latency_ms".to_string(),
+        AnomalyDetectorConfig { algorithm: DetectionAlgorithm::Ewma { alpha: 0.3 }, mode: ThresholdMode::Upper, ..config },
+    );
+    let registry = DetectorRegistry::new(config, templates);
+    let checkpoint_path = PathBuf::from("anomaly_detector_state.json");
+    match registry.restore_from_file(&checkpoint_path).await {
+        Ok(count) => println!("Restored {} series from {}", count, checkpoint_path.display()),
+        Err(e) => println!("Starting without saved state: {}", e),
+    }
+    let evictor = registry.spawn_evictor(Duration::from_secs(60), Duration::from_secs(300));
+    let checkpointer = registry.spawn_checkpointer(checkpoint_path.clone(), Duration::from_secs(30));
+
+    let mut tasks = Vec::new();
+    for host in ["web-1", "web-2", "web-3"] {
+        let registry = registry.clone();
+        tasks.push(tokio::spawn(async move {
+            let key = SeriesKey::new("http_latency_ms").with_label("host", host);
+            for value in [120.0, 118.0, 125.0, 121.0, 119.0, 122.0, 480.0] {
+                if let Err(report) = registry.ingest(&key, value, SystemTime::now()).await {
+                    println!("Anomaly in {}: {}", key, report);
+                }
+            }
+        }));
+    }
+    for task in tasks {
+        let _ = task.await;
+    }
+    println!("Tracking {} series", registry.len().await);
+    evictor.abort();
+    checkpointer.abort();
+
+    // 退出前保存最终状态
+    if let Err(e) = registry.save_to_file(&checkpoint_path).await {
+        println!("Failed to save state: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(algorithm: DetectionAlgorithm) -> AnomalyDetectorConfig {
+        AnomalyDetectorConfig {
+            threshold: 3.0,
+            window_size: 100,
+            window_duration: None,
+            max_lateness: Duration::from_secs(10),
+            algorithm,
+            mode: ThresholdMode::Both,
+        }
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    // 周期为 4 的方波，加一点确定性的扰动
+    fn seasonal_value(i: u64) -> f32 {
+        let base = if i % 4 < 2 { 0.0 } else { 10.0 };
+        base + (i * 7 % 5) as f32 * 0.1
+    }
+
+    fn window(values: &[f32]) -> SampleWindow {
+        let mut window = SampleWindow::default();
+        for (i, &value) in values.iter().enumerate() {
+            window.insert(at(i as u64), value);
+        }
+        window
+    }
+
+    #[test]
+    fn running_stats_supports_removal() {
+        let mut stats = RunningStats::default();
+        for value in [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0] {
+            stats.push(value);
+        }
+        assert_eq!(stats.mean(), 5.0);
+        assert!((stats.std_dev() - 2.138_09).abs() < 1e-5);
+
+        stats.remove(2.0);
+        stats.remove(9.0);
+        assert!((stats.mean() - 29.0 / 6.0).abs() < 1e-6);
+        assert!((stats.std_dev() - 1.169_05).abs() < 1e-5);
+
+        stats.remove(4.0);
+        stats.remove(4.0);
+        stats.remove(4.0);
+        stats.remove(5.0);
+        stats.remove(5.0);
+        assert_eq!(stats.std_dev(), 0.0);
+        stats.remove(7.0);
+        assert_eq!((stats.count, stats.mean()), (0, 0.0));
+    }
+
+    #[test]
+    fn quantile_interpolates_between_samples() {
+        let sorted = [1.0, 2.0, 3.0, 4.0];
+        assert_eq!(quantile(&sorted, 0.0), 1.0);
+        assert_eq!(quantile(&sorted, 0.5), 2.5);
+        assert_eq!(quantile(&sorted, 0.25), 1.75);
+        assert_eq!(quantile(&sorted, 1.0), 4.0);
+        assert_eq!(quantile(&[], 0.5), 0.0);
+    }
+
+    #[test]
+    fn window_baselines() {
+        let values = window(&[1.0, 2.0, 3.0, 4.0, 100.0]);
+        let zscore = ZScoreDetector.baseline(&values).unwrap();
+        assert_eq!(zscore.center, 22.0);
+        // 中位数和 MAD 不受离群点影响
+        assert_eq!(MadDetector.baseline(&values), Some(Baseline { center: 3.0, spread: 1.4826 }));
+        assert_eq!(IqrDetector.baseline(&values), Some(Baseline { center: 3.0, spread: 2.0 }));
+
+        let few = window(&[1.0, 2.0]);
+        assert!(ZScoreDetector.baseline(&few).is_some());
+        assert!(MadDetector.baseline(&few).is_none());
+        assert!(IqrDetector.baseline(&window(&[1.0, 2.0, 3.0])).is_none());
+    }
+
+    #[test]
+    fn scores_and_threshold_modes() {
+        let baseline = Baseline { center: 10.0, spread: 2.0 };
+        assert_eq!(baseline.score(16.0), 3.0);
+        assert_eq!(baseline.expected_range(3.0), (4.0, 16.0));
+        // 没有离散程度时按下限评分，分数保持有限
+        let flat = Baseline { center: 10.0, spread: 0.0 };
+        assert_eq!(flat.score(10.0), 0.0);
+        assert!((flat.score(9.0) + 100.0).abs() < 1e-3);
+        assert!((Baseline { center: 0.0, spread: 0.0 }.score(0.01) - 10.0).abs() < 1e-3);
+
+        assert_eq!(ThresholdMode::Both.check(3.5, 3.0), Some(Direction::Above));
+        assert_eq!(ThresholdMode::Both.check(-3.5, 3.0), Some(Direction::Below));
+        assert_eq!(ThresholdMode::Both.check(3.0, 3.0), None);
+        assert_eq!(ThresholdMode::Upper.check(-3.5, 3.0), None);
+        assert_eq!(ThresholdMode::Lower.check(3.5, 3.0), None);
+    }
+
+    #[tokio::test]
+    async fn ewma_waits_for_warmup() {
+        let mut detector = AnomalyDetector::new(config(DetectionAlgorithm::Ewma { alpha: 0.25 }));
+        // 热身期内不做判断
+        for (i, value) in [10.0, 11.0, 1000.0].into_iter().enumerate() {
+            assert!(detector.add_data_point(value, at(i as u64)).await.is_ok());
+        }
+        for i in 3..40 {
+            let value = if i % 2 == 0 { 10.0 } else { 11.0 };
+            detector.add_data_point(value, at(i)).await.unwrap();
+        }
+        let report = detector.add_data_point(50.0, at(40)).await.unwrap_err();
+        assert_eq!(report.direction, Direction::Above);
+        assert!(report.expected_range.1 < 50.0);
+    }
+
+    #[tokio::test]
+    async fn reports_spikes_and_dips_with_window_stats() {
+        let mut detector = AnomalyDetector::new(config(DetectionAlgorithm::MedianAbsoluteDeviation));
+        for (i, value) in [10.0, 10.5, 9.8, 10.2, 10.1, 9.9, 10.3, 10.0].into_iter().enumerate() {
+            detector.add_data_point(value, at(i as u64)).await.unwrap();
+        }
+        let spike = detector.add_data_point(42.0, at(8)).await.unwrap_err();
+        assert_eq!((spike.value, spike.direction, spike.timestamp), (42.0, Direction::Above, at(8)));
+        assert_eq!(spike.window.count, 8);
+        assert!((spike.window.mean - 10.1).abs() < 1e-5);
+        assert!(spike.expected_range.0 < 10.1 && 10.1 < spike.expected_range.1);
+
+        let dip = detector.add_data_point(1.5, at(9)).await.unwrap_err();
+        assert_eq!(dip.direction, Direction::Below);
+        assert!(dip.score < -3.0);
+        // 异常值不进入窗口
+        assert_eq!(detector.current_window.len(), 8);
+
+        let mut upper_only = AnomalyDetector::new(AnomalyDetectorConfig {
+            mode: ThresholdMode::Upper,
+            ..config(DetectionAlgorithm::MedianAbsoluteDeviation)
+        });
+        for (i, value) in [10.0, 10.5, 9.8, 10.2, 10.1, 9.9, 10.3, 10.0, 1.5].into_iter().enumerate() {
+            upper_only.add_data_point(value, at(i as u64)).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn constant_series_tolerates_rounding_noise() {
+        for algorithm in [
+            DetectionAlgorithm::ZScore,
+            DetectionAlgorithm::MedianAbsoluteDeviation,
+            DetectionAlgorithm::InterquartileRange,
+        ] {
+            let mut detector = AnomalyDetector::new(config(algorithm));
+            for i in 0..20 {
+                detector.add_data_point(1000.0, at(i)).await.unwrap();
+            }
+            // 只差一个最小精度单位的值不算异常
+            detector.add_data_point(1000.0001, at(20)).await.unwrap();
+            let report = detector.add_data_point(1010.0, at(21)).await.unwrap_err();
+            assert_eq!(report.direction, Direction::Above);
+            assert!(report.score.is_finite());
+            assert!(report.expected_range.0 < 1000.0 && 1000.0 < report.expected_range.1);
+        }
+    }
+
+    #[tokio::test]
+    async fn window_handles_late_and_expired_samples() {
+        let mut detector = AnomalyDetector::new(AnomalyDetectorConfig {
+            window_size: 5,
+            window_duration: Some(Duration::from_secs(60)),
+            ..config(DetectionAlgorithm::ZScore)
+        });
+        for (secs, value) in [(100, 1.0), (110, 2.0), (105, 3.0)] {
+            assert_eq!(detector.add_data_point(value, at(secs)).await, Ok(SampleStatus::Accepted));
+        }
+        // 乱序样本按时间戳排列
+        assert_eq!(detector.current_window.values().collect::<Vec<_>>(), vec![1.0, 3.0, 2.0]);
+        // 早于最新样本超过 max_lateness 的样本被丢弃
+        assert_eq!(detector.add_data_point(2.0, at(99)).await, Ok(SampleStatus::Late));
+        assert_eq!(detector.late_samples(), 1);
+
+        // 超过 window_duration 的样本过期
+        detector.add_data_point(2.5, at(166)).await.unwrap();
+        assert_eq!(detector.current_window.values().collect::<Vec<_>>(), vec![2.0, 2.5]);
+        assert_eq!(detector.window_stats().count, 2);
+        assert_eq!(detector.window_stats().mean, 2.25);
+
+        // 异常值不进入窗口
+        for secs in 167..171 {
+            detector.add_data_point(2.0 + (secs % 2) as f32 * 0.5, at(secs)).await.unwrap();
+        }
+        assert!(detector.add_data_point(50.0, at(171)).await.is_err());
+        assert_eq!(detector.current_window.len(), 5);
+        assert!(detector.current_window.values().all(|value| value < 3.0));
+    }
+
+    #[tokio::test]
+    async fn registry_ingests_series_concurrently() {
+        let mut templates = HashMap::new();
+        templates.insert("latency".to_string(), AnomalyDetectorConfig { window_size: 10, ..config(DetectionAlgorithm::ZScore) });
+        let registry = DetectorRegistry::new(config(DetectionAlgorithm::MedianAbsoluteDeviation), templates);
+
+        let mut tasks = Vec::new();
+        for host in 0..8 {
+            let registry = registry.clone();
+            tasks.push(tokio::spawn(async move {
+                let own = SeriesKey::new("cpu").with_label("host", &host.to_string());
+                let shared = SeriesKey::new("latency");
+                for i in 0..50 {
+                    registry.ingest(&own, 10.0 + (i % 3) as f32, SystemTime::now()).await.unwrap();
+                    registry.ingest(&shared, 10.0 + (i % 3) as f32, SystemTime::now()).await.unwrap();
+                }
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(registry.len().await, 9);
+
+        // 每个序列按自己的模板创建，共享序列收到了全部样本
+        let shared = registry.entry(&SeriesKey::new("latency")).await;
+        let shared = shared.lock().await;
+        assert_eq!(shared.detector.config.algorithm, DetectionAlgorithm::ZScore);
+        assert_eq!(shared.detector.current_window.len(), 10);
+        let own = registry.entry(&SeriesKey::new("cpu").with_label("host", "3")).await;
+        let own = own.lock().await;
+        assert_eq!(own.detector.config.algorithm, DetectionAlgorithm::MedianAbsoluteDeviation);
+        assert_eq!(own.detector.current_window.len(), 50);
+    }
+
+    #[tokio::test]
+    async fn registry_evicts_only_idle_series() {
+        let registry = DetectorRegistry::new(config(DetectionAlgorithm::ZScore), HashMap::new());
+        let idle = SeriesKey::new("cpu").with_label("host", "a");
+        let active = SeriesKey::new("cpu").with_label("host", "b");
+        registry.ingest(&idle, 1.0, SystemTime::now()).await.unwrap();
+        registry.ingest(&active, 1.0, SystemTime::now()).await.unwrap();
+        sleep(Duration::from_millis(50)).await;
+        registry.ingest(&active, 1.0, SystemTime::now()).await.unwrap();
+
+        assert_eq!(registry.evict_idle(Duration::from_millis(30)).await, 1);
+        assert_eq!(registry.len().await, 1);
+        assert!(registry.series.lock().await.contains_key(&active));
+
+        // 正在被使用的序列不会被清理
+        let entry = registry.entry(&active).await;
+        let _guard = entry.lock().await;
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(registry.evict_idle(Duration::from_millis(30)).await, 0);
+        assert_eq!(registry.len().await, 1);
+    }
+
+    #[test]
+    fn series_key_display_sorts_labels() {
+        let key = SeriesKey::new("http_latency_ms").with_label("zone", "eu").with_label("host", "web-1");
+        assert_eq!(key.to_string(), "http_latency_ms{host=\"web-1\",zone=\"eu\"}");
+        assert_eq!(SeriesKey::new("cpu").to_string(), "cpu");
+    }
+
+    #[tokio::test]
+    async fn holt_winters_keeps_season_phase_after_anomaly() {
+        let algorithm = DetectionAlgorithm::HoltWinters { alpha: 0.2, beta: 0.05, gamma: 0.3, season_length: 4 };
+        let mut detector = AnomalyDetector::new(config(algorithm));
+        for i in 0..40 {
+            assert!(detector.add_data_point(seasonal_value(i), at(i)).await.is_ok(), "sample {} flagged", i);
+        }
+        assert!(detector.add_data_point(100.0, at(40)).await.is_err());
+        // 异常样本之后的正常样本仍应落在正确的季节
+        for i in 41..80 {
+            assert!(detector.add_data_point(seasonal_value(i), at(i)).await.is_ok(), "sample {} flagged", i);
+        }
+    }
+
+    #[test]
+    fn restore_applies_current_config() {
+        let mut detector = AnomalyDetector::new(config(DetectionAlgorithm::Ewma { alpha: 0.5 }));
+        for i in 0..10 {
+            detector.current_window.insert(at(i), i as f32);
+            detector.detector.observe(i as f32);
+        }
+        detector.late_samples = 3;
+        let snapshot = detector.snapshot();
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let restored = AnomalyDetector::restore(serde_json::from_str(&json).unwrap(), detector.config).unwrap();
+        assert_eq!(restored.detector.save_state(), detector.detector.save_state());
+        assert_eq!(restored.current_window.samples, detector.current_window.samples);
+        assert_eq!(restored.late_samples(), 3);
+
+        // 窗口缩小后只保留最近的样本，统计量随之重新计算
+        let smaller = AnomalyDetectorConfig { window_size: 4, ..detector.config };
+        let restored = AnomalyDetector::restore(snapshot.clone(), smaller).unwrap();
+        assert_eq!(restored.current_window.values().collect::<Vec<_>>(), vec![6.0, 7.0, 8.0, 9.0]);
+        assert_eq!(restored.rolling_mean, 7.5);
+
+        // 算法改变后模型重新学习
+        let restored = AnomalyDetector::restore(snapshot.clone(), config(DetectionAlgorithm::Ewma { alpha: 0.1 })).unwrap();
+        assert_eq!(restored.detector.baseline(&restored.current_window), None);
+
+        let mut future = snapshot;
+        future.version = SNAPSHOT_VERSION + 1;
+        assert!(AnomalyDetector::restore(future, detector.config).is_err());
+    }
+
+    #[test]
+    fn holt_winters_state_requires_matching_season_length() {
+        let mut detector = HoltWintersDetector::new(0.2, 0.05, 0.3, 4);
+        for value in [1.0, 2.0, 3.0, 4.0, 1.5] {
+            detector.observe(value);
+        }
+        let state = detector.save_state();
+        let mut other = HoltWintersDetector::new(0.2, 0.05, 0.3, 4);
+        other.load_state(state.clone()).unwrap();
+        assert_eq!((other.count, other.level), (detector.count, detector.level));
+        assert!(HoltWintersDetector::new(0.2, 0.05, 0.3, 6).load_state(state).is_err());
+    }
+
+    #[tokio::test]
+    async fn registry_snapshot_round_trip() {
+        let path = std::env::temp_dir().join(format!("anomaly_registry_{}.json", std::process::id()));
+        let registry = DetectorRegistry::new(config(DetectionAlgorithm::Ewma { alpha: 0.5 }), HashMap::new());
+        let key = SeriesKey::new("latency").with_label("host", "a");
+        for i in 0..5 {
+            registry.ingest(&key, 10.0 + i as f32, at(i)).await.unwrap();
+        }
+        registry.save_to_file(&path).await.unwrap();
+
+        let restored = DetectorRegistry::new(config(DetectionAlgorithm::Ewma { alpha: 0.5 }), HashMap::new());
+        assert_eq!(restored.restore_from_file(&path).await.unwrap(), 1);
+        let _ = std::fs::remove_file(&path);
+        let original = registry.snapshot().await;
+        let reloaded = restored.snapshot().await;
+        assert_eq!(original.series[0].0, reloaded.series[0].0);
+        assert_eq!(original.series[0].1.current_window, reloaded.series[0].1.current_window);
+        assert_eq!(original.series[0].1.model_state, reloaded.series[0].1.model_state);
+    }
+
+    // 记录收到的事件，便于断言
+    struct RecordingSink(Arc<std::sync::Mutex<Vec<AnomalyEvent>>>);
+
+    #[async_trait]
+    impl AlertSink for RecordingSink {
+        async fn emit(&mut self, event: &AnomalyEvent) -> Result<(), String> {
+            self.0.lock().unwrap().push(event.clone());
+            Ok(())
+        }
+    }
+
+    fn report(value: f32, secs: u64, direction: Direction) -> AnomalyReport {
+        AnomalyReport {
+            value,
+            expected_range: (0.0, 1.0),
+            score: 5.0,
+            direction,
+            window: WindowStats { mean: 0.5, std_dev: 0.1, count: 10 },
+            timestamp: at(secs),
+        }
+    }
+
+    #[tokio::test]
+    async fn dispatcher_suppresses_repeats_within_cooldown() {
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sinks: Vec<Box<dyn AlertSink>> = vec![Box::new(RecordingSink(events.clone()))];
+        let mut dispatcher = AlertDispatcher::new(sinks, Duration::from_secs(10));
+        assert!(dispatcher.dispatch("cpu", report(2.0, 0, Direction::Above)).await);
+        assert!(!dispatcher.dispatch("cpu", report(3.0, 1, Direction::Above)).await);
+        assert!(!dispatcher.dispatch("cpu", report(4.0, 2, Direction::Above)).await);
+        // 冷却期结束后再次告警，并带上被抑制的数量
+        assert!(dispatcher.dispatch("cpu", report(5.0, 15, Direction::Above)).await);
+        // 方向改变时立即告警
+        assert!(dispatcher.dispatch("cpu", report(-5.0, 16, Direction::Below)).await);
+
+        let events = events.lock().unwrap();
+        let summary: Vec<(f32, u32)> = events.iter().map(|e| (e.report.value, e.suppressed)).collect();
+        assert_eq!(summary, vec![(2.0, 0), (5.0, 2), (-5.0, 0)]);
+        assert!(events.iter().all(|e| e.series == "cpu"));
+    }
+
+    #[tokio::test]
+    async fn pipeline_summarizes_channel_input() {
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sinks: Vec<Box<dyn AlertSink>> = vec![Box::new(RecordingSink(events.clone()))];
+        let mut dispatcher = AlertDispatcher::new(sinks, Duration::from_secs(10));
+        let mut detector = AnomalyDetector::new(AnomalyDetectorConfig {
+            mode: ThresholdMode::Upper,
+            ..config(DetectionAlgorithm::ZScore)
+        });
+
+        let (tx, rx) = mpsc::channel(8);
+        let producer = tokio::spawn(async move {
+            for i in 0..40u64 {
+                let value = if (30..35).contains(&i) { 25.0 } else { 10.0 + (i % 3) as f32 * 0.2 };
+                tx.send(Sample { value, timestamp: at(i) }).await.unwrap();
+            }
+            tx.send(Sample { value: 10.0, timestamp: at(0) }).await.unwrap();
+        });
+        let summary = detector.run("demo", rx, &mut dispatcher).await;
+        producer.await.unwrap();
+
+        assert_eq!((summary.samples, summary.anomalies, summary.alerts), (41, 5, 1));
+        assert_eq!((summary.suppressed, summary.late), (4, 1));
+        // 关闭时补发冷却期内被抑制的最后一个告警
+        assert_eq!(events.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn json_lines_sink_appends_one_event_per_line() {
+        let path = std::env::temp_dir().join(format!("anomaly_events_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut sink = JsonLinesSink::open(&path).await.unwrap();
+        for secs in [0, 30] {
+            let event = AnomalyEvent { series: "cpu".to_string(), report: report(2.0, secs, Direction::Above), suppressed: 0 };
+            sink.emit(&event).await.unwrap();
+        }
+        sink.close().await.unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let lines: Vec<serde_json::Value> = contents.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["series"], "cpu");
+        assert_eq!(lines[1]["report"]["direction"], "Above");    }
+
+    #[tokio::test]
+    async fn dispatcher_flushes_suppressed_alerts_on_close() {
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sinks: Vec<Box<dyn AlertSink>> = vec![Box::new(RecordingSink(events.clone()))];
+        let mut dispatcher = AlertDispatcher::new(sinks, Duration::from_secs(10));
+        assert!(dispatcher.dispatch("cpu", report(2.0, 0, Direction::Above)).await);
+        assert!(!dispatcher.dispatch("cpu", report(3.0, 1, Direction::Above)).await);
+        assert!(!dispatcher.dispatch("cpu", report(4.0, 2, Direction::Above)).await);
+        dispatcher.close().await;
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!((events[0].report.value, events[0].suppressed), (2.0, 0));
+        assert_eq!((events[1].report.value, events[1].suppressed), (4.0, 1));
+    }
+
+    #[tokio::test]
+    async fn dispatcher_close_without_suppressed_alerts_emits_nothing() {
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sinks: Vec<Box<dyn AlertSink>> = vec![Box::new(RecordingSink(events.clone()))];
+        let mut dispatcher = AlertDispatcher::new(sinks, Duration::from_secs(10));
+        assert!(dispatcher.dispatch("cpu", report(2.0, 0, Direction::Above)).await);
+        assert!(dispatcher.dispatch("cpu", report(3.0, 20, Direction::Above)).await);
+        dispatcher.close().await;
+        assert_eq!(events.lock().unwrap().len(), 2);
+    }
+}
@@ -1,27 +1,139 @@
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, RwLock};
 use std::sync::Arc;
-use std::collections::HashMap;
-use serde::{Serialize, Deserialize};
-use serde_json::Result;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use serde::{Serialize, Deserialize, Deserializer};
 use async_trait::async_trait;
 use anyhow::Error;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Timelike, Utc};
+use warp::{Filter, Rejection, Reply};
+use warp::http::StatusCode;
+use uuid::Uuid;
+use sled::transaction::ConflictableTransactionError;
+use sled::Transactional;
 
 /// Represents a fraud detection service
 #[derive(Debug, Clone)]
 pub struct FraudDetectionService {
-    /// A map to store user data
-    user_data: Arc<Mutex<HashMap<String, UserData>>>,
+    /// Storage backend holding user data
+    user_data: Arc<dyn UserStore>,
+    /// Rules used to score users, adjusted by case outcomes
+    rules: Arc<RwLock<RuleSet>>,
+    /// Review queue for flagged users and transactions
+    cases: Arc<Mutex<CaseQueue>>,
+    /// Secondary indexes from emails, devices and cards to user ids
+    identities: Arc<RwLock<IdentityIndex>>,
+}
+
+/// Error returned when a user or case does not exist
+#[derive(Debug)]
+pub struct NotFound(String);
+
+impl fmt::Display for NotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} not found", self.0)
+    }
+}
+
+impl std::error::Error for NotFound {}
+
+fn not_found(what: &str) -> Error {
+    Error::new(NotFound(what.to_string()))
+}
+
+/// Error returned when a request cannot be applied, such as resolving a closed case
+#[derive(Debug)]
+pub struct InvalidRequest(String);
+
+impl fmt::Display for InvalidRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidRequest {}
+
+fn invalid_request(message: String) -> Error {
+    Error::new(InvalidRequest(message))
 }
 
 /// Represents user data
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct UserData {
+    /// Stable user ID
+    id: String,
     /// User's name
     name: String,
+    /// User's email address
+    #[serde(default)]
+    email: Option<String>,
+    /// Devices registered to the user
+    #[serde(default)]
+    devices: Vec<String>,
     /// User's age
     age: u32,
     /// User's history of transactions
     transactions: Vec<Transaction>,
+    /// Sliding-window aggregates over `transactions`, maintained by the store
+    #[serde(skip)]
+    aggregates: TransactionAggregates,
+}
+
+fn new_user_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+impl UserData {
+    /// Creates user data with a new ID and builds the aggregates for its transaction history
+    pub fn new(name: &str, age: u32, transactions: Vec<Transaction>) -> Self {
+        let aggregates = TransactionAggregates::from_transactions(&transactions);
+        UserData {
+            id: new_user_id(),
+            name: name.to_string(),
+            email: None,
+            devices: Vec::new(),
+            age,
+            transactions,
+            aggregates,
+        }
+    }
+
+    /// Sets the user's email address
+    pub fn with_email(mut self, email: &str) -> Self {
+        self.email = Some(email.to_string());
+        self
+    }
+
+    /// Registers a device to the user
+    pub fn with_device(mut self, device: &str) -> Self {
+        self.devices.push(device.to_string());
+        self
+    }
+
+    /// All identifiers linked to the user: email, registered devices and the
+    /// devices and cards used in transactions
+    fn identifiers(&self) -> BTreeSet<Identifier> {
+        let mut identifiers = BTreeSet::new();
+        if let Some(email) = &self.email {
+            identifiers.insert(Identifier::new(IdentifierKind::Email, email));
+        }
+        for device in &self.devices {
+            identifiers.insert(Identifier::new(IdentifierKind::Device, device));
+        }
+        for transaction in &self.transactions {
+            identifiers.extend(transaction.identifiers());
+        }
+        identifiers
+    }
+
+    /// Appends a transaction to the history and updates the aggregates
+    pub fn push_transaction(&mut self, transaction: Transaction) {
+        self.aggregates.record(&transaction);
+        self.transactions.push(transaction);
+    }
 }
 
 /// Represents a transaction
@@ -29,70 +141,2266 @@ pub struct UserData {
 pub struct Transaction {
     /// Transaction amount
     amount: f64,
-    /// Transaction date
-    date: String,
+    /// Transaction date, accepted as RFC 3339, `YYYY-MM-DD HH:MM:SS` (UTC) or `YYYY-MM-DD` (midnight UTC)
+    #[serde(deserialize_with = "deserialize_transaction_date")]
+    date: DateTime<Utc>,
+    /// Merchant the payment went to, if known
+    #[serde(default)]
+    merchant: Option<String>,
+    /// Device the payment was made from, if known
+    #[serde(default)]
+    device: Option<String>,
+    /// Fingerprint of the card used, if known
+    #[serde(default)]
+    card_fingerprint: Option<String>,
 }
 
-#[async_trait]
-pub trait FraudDetection {
-    async fn detect_fraud(&self, user_id: &str) -> Result<bool, Error>;
+impl Transaction {
+    /// Creates a transaction, parsing the date in any of the accepted formats
+    pub fn new(amount: f64, date: &str, merchant: Option<&str>) -> Result<Self, Error> {
+        Ok(Transaction {
+            amount,
+            date: parse_transaction_date(date)?,
+            merchant: merchant.map(str::to_string),
+            device: None,
+            card_fingerprint: None,
+        })
+    }
+
+    /// Sets the device the payment was made from
+    pub fn with_device(mut self, device: &str) -> Self {
+        self.device = Some(device.to_string());
+        self
+    }
+
+    /// Sets the fingerprint of the card used
+    pub fn with_card(mut self, card_fingerprint: &str) -> Self {
+        self.card_fingerprint = Some(card_fingerprint.to_string());
+        self
+    }
+
+    /// Device and card identifiers carried by the transaction
+    fn identifiers(&self) -> impl Iterator<Item = Identifier> + '_ {
+        let device = self.device.as_deref().map(|device| Identifier::new(IdentifierKind::Device, device));
+        let card = self.card_fingerprint.as_deref().map(|card| Identifier::new(IdentifierKind::Card, card));
+        device.into_iter().chain(card)
+    }
 }
 
-impl FraudDetectionService {
-    /// Creates a new instance of FraudDetectionService
-    pub fn new() -> Self {
-        FraudDetectionService {
-            user_data: Arc::new(Mutex::new(HashMap::new())),
+/// Kind of identifier used to link users
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum IdentifierKind {
+    Email,
+    Device,
+    Card,
+}
+
+/// An email, device or card fingerprint belonging to a user
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Identifier {
+    kind: IdentifierKind,
+    value: String,
+}
+
+impl Identifier {
+    fn new(kind: IdentifierKind, value: &str) -> Self {
+        // Emails are case-insensitive
+        let value = match kind {
+            IdentifierKind::Email => value.trim().to_lowercase(),
+            IdentifierKind::Device | IdentifierKind::Card => value.trim().to_string(),
+        };
+        Identifier { kind, value }
+    }
+}
+
+/// Another user sharing identifiers with the user being checked
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UserLink {
+    user_id: String,
+    shared: Vec<Identifier>,
+}
+
+/// Secondary indexes from identifiers to the ids of the users that use them
+#[derive(Debug, Default)]
+pub struct IdentityIndex {
+    users_by_identifier: HashMap<Identifier, HashSet<String>>,
+    identifiers_by_user: HashMap<String, BTreeSet<Identifier>>,
+}
+
+impl IdentityIndex {
+    /// Replaces the identifiers indexed for a user
+    fn index_user(&mut self, user_id: &str, identifiers: BTreeSet<Identifier>) {
+        self.remove_user(user_id);
+        self.add_identifiers(user_id, identifiers);
+    }
+
+    /// Adds identifiers to a user's index entries
+    fn add_identifiers(&mut self, user_id: &str, identifiers: impl IntoIterator<Item = Identifier>) {
+        let indexed = self.identifiers_by_user.entry(user_id.to_string()).or_default();
+        for identifier in identifiers {
+            self.users_by_identifier.entry(identifier.clone()).or_default().insert(user_id.to_string());
+            indexed.insert(identifier);
+        }
+    }
+
+    fn remove_user(&mut self, user_id: &str) {
+        for identifier in self.identifiers_by_user.remove(user_id).unwrap_or_default() {
+            if let Some(users) = self.users_by_identifier.get_mut(&identifier) {
+                users.remove(user_id);
+                if users.is_empty() {
+                    self.users_by_identifier.remove(&identifier);
+                }
+            }
+        }
+    }
+
+    /// Ids of the users using an identifier
+    fn find(&self, identifier: &Identifier) -> Vec<String> {
+        let mut users: Vec<String> = self.users_by_identifier.get(identifier).into_iter().flatten().cloned().collect();
+        users.sort();
+        users
+    }
+
+    /// Other users sharing any of the user's identifiers, plus `extra` identifiers
+    /// that are not indexed yet (such as those of an incoming transaction)
+    fn links(&self, user_id: &str, extra: impl IntoIterator<Item = Identifier>) -> Vec<UserLink> {
+        let mut identifiers = self.identifiers_by_user.get(user_id).cloned().unwrap_or_default();
+        identifiers.extend(extra);
+
+        let mut shared: BTreeMap<String, Vec<Identifier>> = BTreeMap::new();
+        for identifier in identifiers {
+            for other in self.users_by_identifier.get(&identifier).into_iter().flatten() {
+                if other != user_id {
+                    shared.entry(other.clone()).or_default().push(identifier.clone());
+                }
+            }
         }
+        shared.into_iter().map(|(user_id, shared)| UserLink { user_id, shared }).collect()
     }
+}
 
-    /// Adds a user to the service
-    pub async fn add_user(&self, user: UserData) {
-        let mut users = self.user_data.lock().await;
-        users.insert(user.name.clone(), user);
+/// Parses a transaction date in any of the accepted formats
+fn parse_transaction_date(value: &str) -> Result<DateTime<Utc>, Error> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(value) {
+        return Ok(date.with_timezone(&Utc));
+    }
+    for format in ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M"] {
+        if let Ok(date) = NaiveDateTime::parse_from_str(value, format) {
+            return Ok(date.and_utc());
+        }
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc());
     }
+    Err(Error::msg(format!("Invalid transaction date: {}", value)))
 }
 
-#[async_trait]
-impl FraudDetection for FraudDetectionService {
-    /// Detects fraud by analyzing user transactions
-    async fn detect_fraud(&self, user_id: &str) -> Result<bool, Error> {
-        let users = self.user_data.lock().await;
-        if let Some(user) = users.get(user_id) {
-            // Simple fraud detection logic: If a user has more than 10 transactions
-            // or transactions with an amount greater than $1000, it's considered fraudulent.
-            if user.transactions.len() > 10 || user.transactions.iter().any(|t| t.amount > 1000.0) {
-                Ok(true)
-            } else {
-                Ok(false)
+fn deserialize_transaction_date<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DateTime<Utc>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    parse_transaction_date(&value).map_err(serde::de::Error::custom)
+}
+
+/// Span of a sliding window over a user's transactions
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WindowSpan {
+    Minute,
+    Hour,
+    Day,
+}
+
+impl WindowSpan {
+    fn duration(&self) -> chrono::Duration {
+        match self {
+            WindowSpan::Minute => chrono::Duration::minutes(1),
+            WindowSpan::Hour => chrono::Duration::hours(1),
+            WindowSpan::Day => chrono::Duration::days(1),
+        }
+    }
+}
+
+impl fmt::Display for WindowSpan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WindowSpan::Minute => write!(f, "minute"),
+            WindowSpan::Hour => write!(f, "hour"),
+            WindowSpan::Day => write!(f, "day"),
+        }
+    }
+}
+
+/// Count, sum and merchants of the transactions inside one sliding window
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SlidingWindow {
+    /// Transactions in the window ordered by date
+    entries: VecDeque<(DateTime<Utc>, f64, Option<String>)>,
+    count: usize,
+    sum: f64,
+    /// Number of transactions per merchant in the window
+    merchants: HashMap<String, usize>,
+}
+
+impl SlidingWindow {
+    fn insert(&mut self, transaction: &Transaction) {
+        let position = self.entries.partition_point(|(date, _, _)| *date <= transaction.date);
+        self.entries.insert(position, (transaction.date, transaction.amount, transaction.merchant.clone()));
+        self.count += 1;
+        self.sum += transaction.amount;
+        if let Some(merchant) = &transaction.merchant {
+            *self.merchants.entry(merchant.clone()).or_insert(0) += 1;
+        }
+    }
+
+    /// Drops the transactions made before `cutoff`
+    fn expire_before(&mut self, cutoff: DateTime<Utc>) {
+        while matches!(self.entries.front(), Some((date, _, _)) if *date < cutoff) {
+            let Some((_, amount, merchant)) = self.entries.pop_front() else { break };
+            self.count -= 1;
+            self.sum -= amount;
+            if let Some(merchant) = merchant {
+                if let Some(count) = self.merchants.get_mut(&merchant) {
+                    *count -= 1;
+                    if *count == 0 {
+                        self.merchants.remove(&merchant);
+                    }
+                }
+            }
+        }
+        if self.entries.is_empty() {
+            // Reset the running sum so floating point error does not accumulate
+            self.sum = 0.0;
+        }
+    }
+}
+
+/// Per-user aggregates maintained incrementally so rules never scan the full history
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TransactionAggregates {
+    minute: SlidingWindow,
+    hour: SlidingWindow,
+    day: SlidingWindow,
+    /// Most recent transaction date seen
+    latest: Option<DateTime<Utc>>,
+    /// Running amount statistics over the whole history (Welford)
+    count: usize,
+    mean: f64,
+    m2: f64,
+    /// Largest amount in the whole history
+    #[serde(default)]
+    max_amount: f64,
+    /// Bit `h` is set when the history has a transaction during UTC hour `h`
+    #[serde(default)]
+    hours: u32,
+}
+
+impl TransactionAggregates {
+    /// Builds aggregates by replaying a transaction history
+    pub fn from_transactions(transactions: &[Transaction]) -> Self {
+        let mut aggregates = TransactionAggregates::default();
+        for transaction in transactions {
+            aggregates.record(transaction);
+        }
+        aggregates
+    }
+
+    /// Adds a transaction to the aggregates
+    pub fn record(&mut self, transaction: &Transaction) {
+        self.count += 1;
+        let delta = transaction.amount - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (transaction.amount - self.mean);
+        self.max_amount = if self.count == 1 { transaction.amount } else { self.max_amount.max(transaction.amount) };
+        self.hours |= 1 << transaction.date.hour();
+
+        let latest = self.latest.map_or(transaction.date, |latest| latest.max(transaction.date));
+        self.latest = Some(latest);
+        for span in [WindowSpan::Minute, WindowSpan::Hour, WindowSpan::Day] {
+            // Late transactions that already fall outside a window only count towards the totals
+            if transaction.date >= latest - span.duration() {
+                self.window_mut(span).insert(transaction);
             }
+        }
+        self.advance_to(latest);
+    }
+
+    /// Expires window entries that are too old relative to `now`
+    pub fn advance_to(&mut self, now: DateTime<Utc>) {
+        for span in [WindowSpan::Minute, WindowSpan::Hour, WindowSpan::Day] {
+            self.window_mut(span).expire_before(now - span.duration());
+        }
+    }
+
+    pub fn window(&self, span: WindowSpan) -> &SlidingWindow {
+        match span {
+            WindowSpan::Minute => &self.minute,
+            WindowSpan::Hour => &self.hour,
+            WindowSpan::Day => &self.day,
+        }
+    }
+
+    fn window_mut(&mut self, span: WindowSpan) -> &mut SlidingWindow {
+        match span {
+            WindowSpan::Minute => &mut self.minute,
+            WindowSpan::Hour => &mut self.hour,
+            WindowSpan::Day => &mut self.day,
+        }
+    }
+
+    /// Number of transactions in the whole history
+    pub fn total_count(&self) -> usize {
+        self.count
+    }
+
+    /// Number of distinct merchants in the window, counting `merchant` as well
+    pub fn distinct_merchants(&self, span: WindowSpan, merchant: Option<&str>) -> usize {
+        let merchants = &self.window(span).merchants;
+        merchants.len() + merchant.map_or(0, |merchant| usize::from(!merchants.contains_key(merchant)))
+    }
+
+    /// Largest amount in the whole history, if there is one
+    pub fn largest_amount(&self) -> Option<f64> {
+        (self.count > 0).then_some(self.max_amount)
+    }
+
+    /// Earliest hour from `start_hour` onwards, wrapping around midnight, that the history has
+    /// a transaction in and that lies between `start_hour` and `end_hour`
+    fn first_hour_in_range(&self, start_hour: u32, end_hour: u32) -> Option<u32> {
+        (start_hour..start_hour + 24)
+            .map(|hour| hour % 24)
+            .find(|&hour| self.hours & (1 << hour) != 0 && hour_in_range(hour, start_hour, end_hour))
+    }
+
+    /// How many standard deviations `amount` is from the user's mean amount
+    pub fn amount_zscore(&self, amount: f64) -> Option<f64> {
+        if self.count < 2 {
+            return None;
+        }
+        let std_dev = (self.m2 / (self.count - 1) as f64).sqrt();
+        if std_dev <= f64::EPSILON {
+            return None;
+        }
+        Some((amount - self.mean) / std_dev)
+    }
+}
+
+/// Condition checked by a rule
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RuleCondition {
+    /// Fires when the user has made more than `max_transactions` transactions,
+    /// counted over the whole history or only inside `window`
+    Velocity {
+        max_transactions: usize,
+        #[serde(default)]
+        window: Option<WindowSpan>,
+    },
+    /// Fires when the total amount spent inside `window` exceeds `max_total`
+    AmountVelocity { max_total: f64, window: WindowSpan },
+    /// Fires when the user pays more than `max_merchants` distinct merchants inside `window`
+    DistinctMerchants { max_merchants: usize, window: WindowSpan },
+    /// Fires when the amount is more than `max_z` standard deviations above the user's own mean,
+    /// once the user has at least `min_history` transactions
+    AmountZScore {
+        max_z: f64,
+        #[serde(default = "default_min_history")]
+        min_history: usize,
+    },
+    /// Fires when a transaction amount exceeds `amount`
+    AmountAbove { amount: f64 },
+    /// Fires when a user whose age is within the bounds spends more than `max_amount`
+    AgeLimit {
+        #[serde(default)]
+        min_age: Option<u32>,
+        #[serde(default)]
+        max_age: Option<u32>,
+        max_amount: f64,
+    },
+    /// Fires for transactions made between `start_hour` (inclusive) and `end_hour` (exclusive);
+    /// ranges may wrap around midnight
+    TimeOfDay { start_hour: u32, end_hour: u32 },
+    /// Fires when a user with fewer than `max_history` earlier transactions spends more than `max_amount`
+    NewAccount { max_history: usize, max_amount: f64 },
+    /// Fires when more than `max_linked_users` other users share an identifier of one of `kinds`
+    SharedIdentity {
+        max_linked_users: usize,
+        #[serde(default = "default_shared_kinds")]
+        kinds: Vec<IdentifierKind>,
+    },
+}
+
+/// A named, weighted rule
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Rule {
+    /// Rule name reported when the rule fires
+    name: String,
+    /// Contribution of the rule to the risk score
+    weight: f64,
+    /// Disabled rules are kept in the file but never evaluated
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+    #[serde(flatten)]
+    condition: RuleCondition,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_min_history() -> usize {
+    5
+}
+
+fn default_shared_kinds() -> Vec<IdentifierKind> {
+    vec![IdentifierKind::Device, IdentifierKind::Card]
+}
+
+/// A rule that fired during evaluation
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FiredRule {
+    /// Rule name
+    name: String,
+    /// Rule weight
+    weight: f64,
+    /// Human readable explanation
+    reason: String,
+}
+
+/// Result of scoring a user
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RiskAssessment {
+    /// Sum of the weights of the fired rules
+    score: f64,
+    /// Whether the score reached the rule set's fraud threshold
+    is_fraud: bool,
+    /// Rules that fired, in rule file order
+    fired_rules: Vec<FiredRule>,
+}
+
+/// Outcome of scoring an incoming transaction
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    Approve,
+    Review,
+    Decline,
+}
+
+/// Result of scoring a single transaction before it is committed
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TransactionDecision {
+    /// Whether the payment flow should approve, hold for review or decline the transaction
+    decision: Decision,
+    /// Sum of the weights of the fired rules
+    score: f64,
+    /// Rules that fired, used as the reasons for the decision
+    fired_rules: Vec<FiredRule>,
+}
+
+/// A set of rules with the threshold at which a user is considered fraudulent
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RuleSet {
+    /// Minimum risk score for a user to be flagged, and for a transaction to be declined
+    fraud_threshold: f64,
+    /// Minimum risk score for a transaction to be held for manual review
+    #[serde(default = "default_review_threshold")]
+    review_threshold: f64,
+    /// Relative weight change applied to the fired rules when a case is resolved
+    #[serde(default = "default_feedback_rate")]
+    feedback_rate: f64,
+    rules: Vec<Rule>,
+}
+
+fn default_review_threshold() -> f64 {
+    0.5
+}
+
+fn default_feedback_rate() -> f64 {
+    0.05
+}
+
+impl Default for RuleSet {
+    /// The original checks: more than 10 transactions or any amount over 1000
+    fn default() -> Self {
+        RuleSet {
+            fraud_threshold: 1.0,
+            review_threshold: default_review_threshold(),
+            feedback_rate: default_feedback_rate(),
+            rules: vec![
+                Rule {
+                    name: "velocity".to_string(),
+                    weight: 1.0,
+                    enabled: true,
+                    condition: RuleCondition::Velocity { max_transactions: 10, window: None },
+                },
+                Rule {
+                    name: "large_amount".to_string(),
+                    weight: 1.0,
+                    enabled: true,
+                    condition: RuleCondition::AmountAbove { amount: 1000.0 },
+                },
+            ],
+        }
+    }
+}
+
+impl RuleSet {
+    /// Loads rules from a JSON or TOML file, chosen by the file extension
+    pub async fn from_file(path: &Path) -> Result<Self, Error> {
+        let contents = tokio::fs::read_to_string(path).await?;
+        let rules = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&contents)?,
+            Some("json") => serde_json::from_str(&contents)?,
+            _ => return Err(Error::msg(format!("Unsupported rules file format: {}", path.display()))),
+        };
+        Ok(rules)
+    }
+
+    /// Scores a user from the stored aggregates, so the transaction history is never
+    /// replayed; `links` are the other users sharing the user's identifiers
+    pub fn evaluate(&self, user: &UserData, links: &[UserLink]) -> RiskAssessment {
+        let fired_rules: Vec<FiredRule> = self
+            .rules
+            .iter()
+            .filter(|rule| rule.enabled)
+            .filter_map(|rule| {
+                let reason = rule
+                    .condition
+                    .check_aggregates(user, &user.aggregates)
+                    .or_else(|| rule.condition.check_links(links))?;
+                Some(FiredRule { name: rule.name.clone(), weight: rule.weight, reason })
+            })
+            .collect();
+        let score = total_weight(&fired_rules);
+        RiskAssessment { score, is_fraud: score >= self.fraud_threshold, fired_rules }
+    }
+
+    /// Scores an incoming transaction against the user's full history and links
+    pub fn evaluate_transaction(&self, user: &UserData, transaction: &Transaction, links: &[UserLink]) -> TransactionDecision {
+        // Expire window entries on a copy so the stored aggregates are left untouched
+        let mut history = user.aggregates.clone();
+        history.advance_to(transaction.date);
+        let fired_rules: Vec<FiredRule> = self
+            .rules
+            .iter()
+            .filter(|rule| rule.enabled)
+            .filter_map(|rule| {
+                let reason = rule
+                    .condition
+                    .check(user, &history, transaction)
+                    .or_else(|| rule.condition.check_links(links))?;
+                Some(FiredRule { name: rule.name.clone(), weight: rule.weight, reason })
+            })
+            .collect();
+
+        let score = total_weight(&fired_rules);
+        let decision = if score >= self.fraud_threshold {
+            Decision::Decline
+        } else if score >= self.review_threshold {
+            Decision::Review
         } else {
-            Err(Error::msg("User not found"))
+            Decision::Approve
+        };
+        TransactionDecision { decision, score, fired_rules }
+    }
+}
+
+impl RuleSet {
+    /// Strengthens the fired rules after confirmed fraud and weakens them after a false positive
+    pub fn apply_feedback(&mut self, fired_rules: &[FiredRule], confirmed_fraud: bool) {
+        let factor = if confirmed_fraud { 1.0 + self.feedback_rate } else { 1.0 - self.feedback_rate };
+        for rule in self.rules.iter_mut() {
+            if fired_rules.iter().any(|fired| fired.name == rule.name) {
+                rule.weight = (rule.weight * factor).max(0.0);
+            }
+        }
+    }
+
+    /// Current weight of every rule, keyed by rule name
+    pub fn weights(&self) -> BTreeMap<String, f64> {
+        self.rules.iter().map(|rule| (rule.name.clone(), rule.weight)).collect()
+    }
+
+    /// Restores learned weights; rules missing from `weights` keep the weight from the rules file
+    pub fn apply_weights(&mut self, weights: &BTreeMap<String, f64>) {
+        for rule in self.rules.iter_mut() {
+            if let Some(weight) = weights.get(&rule.name) {
+                rule.weight = *weight;
+            }
         }
     }
 }
 
-#[tokio::main]
-async fn main() {
-    let service = FraudDetectionService::new();
+/// Sums the weights of the fired rules
+fn total_weight(fired_rules: &[FiredRule]) -> f64 {
+    fired_rules.iter().fold(0.0, |total, rule| total + rule.weight)
+}
 
-    // Example user data
-    let user = UserData {
-        name: "John Doe".to_string(),
-        age: 30,
-        transactions: vec![
-            Transaction { amount: 100.0, date: "2023-01-01".to_string() },
-            Transaction { amount: 200.0, date: "2023-01-02".to_string() },
-            // Add more transactions as needed
-        ],
-    };
+impl RuleCondition {
+    /// Checks the user's links to other users, returning why the rule fired
+    fn check_links(&self, links: &[UserLink]) -> Option<String> {
+        let RuleCondition::SharedIdentity { max_linked_users, kinds } = self else { return None };
+        let linked: Vec<&UserLink> = links
+            .iter()
+            .filter(|link| link.shared.iter().any(|identifier| kinds.contains(&identifier.kind)))
+            .collect();
+        (linked.len() > *max_linked_users).then(|| {
+            let users: Vec<&str> = linked.iter().map(|link| link.user_id.as_str()).collect();
+            format!("shares identifiers with {} other users: {}", linked.len(), users.join(", "))
+        })
+    }
 
-    // Add user to the service
-    service.add_user(user).await;
+    /// Checks a transaction against the aggregates of the user's earlier history, returning why the rule fired
+    fn check(&self, user: &UserData, history: &TransactionAggregates, transaction: &Transaction) -> Option<String> {
+        match *self {
+            RuleCondition::Velocity { max_transactions, window } => {
+                let count = window.map_or(history.total_count(), |span| history.window(span).count) + 1;
+                (count > max_transactions).then(|| match window {
+                    Some(span) => format!("{} transactions in the last {} exceed the limit of {}", count, span, max_transactions),
+                    None => format!("{} transactions exceed the limit of {}", count, max_transactions),
+                })
+            }
+            RuleCondition::AmountVelocity { max_total, window } => {
+                let total = history.window(window).sum + transaction.amount;
+                (total > max_total)
+                    .then(|| format!("{:.2} spent in the last {} exceeds {:.2}", total, window, max_total))
+            }
+            RuleCondition::DistinctMerchants { max_merchants, window } => {
+                let merchants = history.distinct_merchants(window, transaction.merchant.as_deref());
+                (merchants > max_merchants)
+                    .then(|| format!("{} distinct merchants in the last {} exceed {}", merchants, window, max_merchants))
+            }
+            RuleCondition::AmountZScore { max_z, min_history } => {
+                if history.total_count() < min_history {
+                    return None;
+                }
+                let z = history.amount_zscore(transaction.amount)?;
+                (z > max_z).then(|| format!("amount {:.2} is {:.1} standard deviations above the user's mean", transaction.amount, z))
+            }
+            RuleCondition::AmountAbove { amount } => (transaction.amount > amount)
+                .then(|| format!("amount {:.2} exceeds {:.2}", transaction.amount, amount)),
+            RuleCondition::AgeLimit { min_age, max_age, max_amount } => {
+                let in_range = min_age.is_none_or(|min| user.age >= min) && max_age.is_none_or(|max| user.age <= max);
+                (in_range && transaction.amount > max_amount).then(|| {
+                    format!("amount {:.2} exceeds {:.2} allowed at age {}", transaction.amount, max_amount, user.age)
+                })
+            }
+            RuleCondition::TimeOfDay { start_hour, end_hour } => {
+                let hour = transaction.date.hour();
+                hour_in_range(hour, start_hour, end_hour)
+                    .then(|| format!("transaction at {:02}:00 is between {:02}:00 and {:02}:00", hour, start_hour, end_hour))
+            }
+            RuleCondition::SharedIdentity { .. } => None,
+            RuleCondition::NewAccount { max_history, max_amount } => {
+                (history.total_count() < max_history && transaction.amount > max_amount).then(|| {
+                    format!(
+                        "amount {:.2} exceeds {:.2} with only {} earlier transactions",
+                        transaction.amount,
+                        max_amount,
+                        history.total_count()
+                    )
+                })
+            }
+        }
+    }
 
-    // Detect fraud for the user
-    match service.detect_fraud(&user.name).await {
-        Ok(is_fraud) => println!("Fraud detected: {}", is_fraud),
-        Err(e) => println!("Error detecting fraud: {}", e),
+    /// Checks the aggregates of the user's whole history, returning why the rule fired; rules
+    /// about single transactions look at the largest amount and the hours seen so far
+    fn check_aggregates(&self, user: &UserData, history: &TransactionAggregates) -> Option<String> {
+        match *self {
+            RuleCondition::Velocity { max_transactions, window } => {
+                let count = window.map_or(history.total_count(), |span| history.window(span).count);
+                (count > max_transactions).then(|| match window {
+                    Some(span) => format!("{} transactions in the last {} exceed the limit of {}", count, span, max_transactions),
+                    None => format!("{} transactions exceed the limit of {}", count, max_transactions),
+                })
+            }
+            RuleCondition::AmountVelocity { max_total, window } => {
+                let total = history.window(window).sum;
+                (total > max_total)
+                    .then(|| format!("{:.2} spent in the last {} exceeds {:.2}", total, window, max_total))
+            }
+            RuleCondition::DistinctMerchants { max_merchants, window } => {
+                let merchants = history.distinct_merchants(window, None);
+                (merchants > max_merchants)
+                    .then(|| format!("{} distinct merchants in the last {} exceed {}", merchants, window, max_merchants))
+            }
+            RuleCondition::AmountZScore { max_z, min_history } => {
+                if history.total_count() < min_history {
+                    return None;
+                }
+                let largest = history.largest_amount()?;
+                let z = history.amount_zscore(largest)?;
+                (z > max_z).then(|| format!("largest amount {:.2} is {:.1} standard deviations above the user's mean", largest, z))
+            }
+            RuleCondition::AmountAbove { amount } => {
+                let largest = history.largest_amount()?;
+                (largest > amount).then(|| format!("largest amount {:.2} exceeds {:.2}", largest, amount))
+            }
+            RuleCondition::AgeLimit { min_age, max_age, max_amount } => {
+                let largest = history.largest_amount()?;
+                let in_range = min_age.is_none_or(|min| user.age >= min) && max_age.is_none_or(|max| user.age <= max);
+                (in_range && largest > max_amount)
+                    .then(|| format!("largest amount {:.2} exceeds {:.2} allowed at age {}", largest, max_amount, user.age))
+            }
+            RuleCondition::TimeOfDay { start_hour, end_hour } => {
+                let hour = history.first_hour_in_range(start_hour, end_hour)?;
+                Some(format!("transaction at {:02}:00 is between {:02}:00 and {:02}:00", hour, start_hour, end_hour))
+            }
+            RuleCondition::SharedIdentity { .. } => None,
+            RuleCondition::NewAccount { max_history, max_amount } => {
+                // With at most `max_history` transactions, every one had fewer than `max_history` before it
+                let largest = history.largest_amount()?;
+                (history.total_count() <= max_history && largest > max_amount).then(|| {
+                    format!(
+                        "largest amount {:.2} exceeds {:.2} with only {} transactions",
+                        largest,
+                        max_amount,
+                        history.total_count()
+                    )
+                })
+            }
+        }
+    }
+}
+
+/// Whether `hour` lies between `start_hour` (inclusive) and `end_hour` (exclusive), wrapping around midnight
+fn hour_in_range(hour: u32, start_hour: u32, end_hour: u32) -> bool {
+    if start_hour <= end_hour {
+        hour >= start_hour && hour < end_hour
+    } else {
+        hour >= start_hour || hour < end_hour
+    }
+}
+
+/// Review status of a case
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CaseStatus {
+    Open,
+    ConfirmedFraud,
+    FalsePositive,
+}
+
+/// A note left by an analyst on a case
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CaseNote {
+    author: String,
+    text: String,
+    created_at: DateTime<Utc>,
+}
+
+/// An entry in a case's audit trail
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEntry {
+    /// Analyst name, or "system" for automatic actions
+    actor: String,
+    action: String,
+    at: DateTime<Utc>,
+}
+
+/// A flagged user awaiting or having received analyst review
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Case {
+    id: u64,
+    user_id: String,
+    status: CaseStatus,
+    /// Risk score that caused the case to be opened
+    score: f64,
+    /// Rules that fired, used to adjust rule weights when the case is resolved
+    fired_rules: Vec<FiredRule>,
+    notes: Vec<CaseNote>,
+    audit_trail: Vec<AuditEntry>,
+    created_at: DateTime<Utc>,
+}
+
+impl Case {
+    fn record(&mut self, actor: &str, action: String) {
+        self.audit_trail.push(AuditEntry { actor: actor.to_string(), action, at: Utc::now() });
+    }
+}
+
+/// Queue of cases for analyst review
+#[derive(Debug, Default)]
+pub struct CaseQueue {
+    next_id: u64,
+    cases: HashMap<u64, Case>,
+}
+
+impl CaseQueue {
+    /// Id of the user's open case, if there is one
+    fn open_case_for(&self, user_id: &str) -> Option<u64> {
+        self.cases
+            .values()
+            .find(|case| case.user_id == user_id && case.status == CaseStatus::Open)
+            .map(|case| case.id)
+    }
+
+    /// Builds a new open case with the next id
+    fn new_case(&self, user_id: &str, score: f64, fired_rules: Vec<FiredRule>, reason: &str) -> Case {
+        let mut case = Case {
+            id: self.next_id + 1,
+            user_id: user_id.to_string(),
+            status: CaseStatus::Open,
+            score,
+            fired_rules,
+            notes: Vec::new(),
+            audit_trail: Vec::new(),
+            created_at: Utc::now(),
+        };
+        case.record("system", format!("opened: {} (risk score {:.2})", reason, score));
+        case
     }
-}
\ No newline at end of file
+
+    /// Adds or replaces a case
+    fn insert(&mut self, case: Case) {
+        self.next_id = self.next_id.max(case.id);
+        self.cases.insert(case.id, case);
+    }
+
+    /// Drops a case that could not be stored; its id is not reused
+    fn remove(&mut self, case_id: u64) {
+        self.cases.remove(&case_id);
+    }
+
+    fn get(&self, case_id: u64) -> Result<&Case, Error> {
+        self.cases.get(&case_id).ok_or_else(|| not_found("Case"))
+    }
+}
+
+/// Storage backend for user data, review cases and rule weights learned from case outcomes
+#[async_trait]
+pub trait UserStore: fmt::Debug + Send + Sync {
+    /// Loads a user, with aggregates built
+    async fn get_user(&self, user_id: &str) -> Result<Option<Arc<UserData>>, Error>;
+
+    /// Loads a user's profile and aggregates for scoring; stores that keep the history
+    /// separately may leave `transactions` empty
+    async fn get_user_summary(&self, user_id: &str) -> Result<Option<Arc<UserData>>, Error> {
+        self.get_user(user_id).await
+    }
+
+    /// Inserts or replaces a user
+    async fn put_user(&self, user_id: &str, user: UserData) -> Result<(), Error>;
+
+    /// Appends a transaction to an existing user's history
+    async fn append_transaction(&self, user_id: &str, transaction: Transaction) -> Result<(), Error>;
+
+    /// Loads every user, used to rebuild secondary indexes
+    async fn all_users(&self) -> Result<Vec<Arc<UserData>>, Error>;
+
+    /// Loads every stored case; stores that do not persist cases have none
+    async fn all_cases(&self) -> Result<Vec<Case>, Error> {
+        Ok(Vec::new())
+    }
+
+    /// Inserts or replaces a case, together with the rule weights when its outcome changed them
+    async fn put_case(&self, _case: &Case, _rule_weights: Option<&BTreeMap<String, f64>>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Loads the learned rule weights, keyed by rule name
+    async fn rule_weights(&self) -> Result<BTreeMap<String, f64>, Error> {
+        Ok(BTreeMap::new())
+    }
+}
+
+/// Number of shards used when none is given
+const DEFAULT_SHARDS: usize = 16;
+
+/// Picks the shard for a user id
+fn shard_index(user_id: &str, shards: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    user_id.hash(&mut hasher);
+    (hasher.finish() % shards as u64) as usize
+}
+
+/// In-memory store split into independently locked shards, so lookups for
+/// users in different shards never contend
+#[derive(Debug)]
+pub struct MemoryUserStore {
+    shards: Vec<RwLock<HashMap<String, Arc<UserData>>>>,
+}
+
+impl MemoryUserStore {
+    pub fn new(shards: usize) -> Self {
+        MemoryUserStore { shards: (0..shards.max(1)).map(|_| RwLock::new(HashMap::new())).collect() }
+    }
+
+    fn shard(&self, user_id: &str) -> &RwLock<HashMap<String, Arc<UserData>>> {
+        &self.shards[shard_index(user_id, self.shards.len())]
+    }
+}
+
+#[async_trait]
+impl UserStore for MemoryUserStore {
+    async fn get_user(&self, user_id: &str) -> Result<Option<Arc<UserData>>, Error> {
+        Ok(self.shard(user_id).read().await.get(user_id).cloned())
+    }
+
+    async fn put_user(&self, user_id: &str, mut user: UserData) -> Result<(), Error> {
+        user.aggregates = TransactionAggregates::from_transactions(&user.transactions);
+        self.shard(user_id).write().await.insert(user_id.to_string(), Arc::new(user));
+        Ok(())
+    }
+
+    async fn append_transaction(&self, user_id: &str, transaction: Transaction) -> Result<(), Error> {
+        let mut shard = self.shard(user_id).write().await;
+        let user = shard.get_mut(user_id).ok_or_else(|| not_found("User"))?;
+        // Copies the user only while a concurrent reader still holds the previous version
+        Arc::make_mut(user).push_transaction(transaction);
+        Ok(())
+    }
+
+    async fn all_users(&self) -> Result<Vec<Arc<UserData>>, Error> {
+        let mut users = Vec::new();
+        for shard in &self.shards {
+            users.extend(shard.read().await.values().cloned());
+        }
+        Ok(users)
+    }
+}
+
+/// Embedded on-disk store backed by sled.
+///
+/// Each user's profile is stored as JSON next to its aggregates in the `users` tree, and every
+/// transaction under its own key in the `transactions` tree, so appending a transaction and
+/// loading a user for scoring never touch the rest of the history. Review cases are kept in
+/// the `cases` tree and learned rule weights in the `rule_weights` tree.
+///
+/// sled's reads, writes and transactions block the calling thread (page faults, IO, conflict
+/// retries), so every call runs on tokio's blocking pool; only the flush is awaited in place.
+#[derive(Debug)]
+pub struct SledUserStore {
+    trees: SledTrees,
+    /// Serializes read-modify-write updates per shard of users
+    write_locks: Vec<Mutex<()>>,
+}
+
+/// Handles to the database and its trees; cloning them is cheap, so a copy is moved into
+/// each blocking task
+#[derive(Debug, Clone)]
+struct SledTrees {
+    db: sled::Db,
+    users: sled::Tree,
+    transactions: sled::Tree,
+    cases: sled::Tree,
+    rule_weights: sled::Tree,
+}
+
+/// A user record in the `users` tree; the transaction history lives in the `transactions` tree
+#[derive(Serialize, Deserialize)]
+struct StoredUser {
+    user: UserData,
+    aggregates: TransactionAggregates,
+}
+
+impl SledUserStore {
+    /// Opens or creates the database at `path`
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let db = sled::open(path)?;
+        Ok(SledUserStore {
+            trees: SledTrees {
+                users: db.open_tree("users")?,
+                transactions: db.open_tree("transactions")?,
+                cases: db.open_tree("cases")?,
+                rule_weights: db.open_tree("rule_weights")?,
+                db,
+            },
+            write_locks: (0..DEFAULT_SHARDS).map(|_| Mutex::new(())).collect(),
+        })
+    }
+
+    fn write_lock(&self, user_id: &str) -> &Mutex<()> {
+        &self.write_locks[shard_index(user_id, self.write_locks.len())]
+    }
+
+    /// Runs synchronous sled work on the blocking thread pool
+    async fn blocking<T, F>(&self, work: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&SledTrees) -> Result<T, Error> + Send + 'static,
+    {
+        let trees = self.trees.clone();
+        tokio::task::spawn_blocking(move || work(&trees)).await?
+    }
+
+    /// Prefix of a user's transaction keys; 0xff never occurs in UTF-8, so one user's
+    /// prefix is never the prefix of another user's keys
+    fn transaction_prefix(user_id: &str) -> Vec<u8> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+        prefix
+    }
+
+    /// Key of a user's `seq`-th transaction, ordered by sequence number
+    fn transaction_key(user_id: &str, seq: usize) -> Vec<u8> {
+        let mut key = Self::transaction_prefix(user_id);
+        key.extend_from_slice(&(seq as u64).to_be_bytes());
+        key
+    }
+}
+
+impl SledTrees {
+    /// Loads a user's profile and aggregates, without the transaction history
+    fn load_record(&self, user_id: &str) -> Result<Option<StoredUser>, Error> {
+        match self.users.get(user_id.as_bytes())? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Turns a stored record into a user, loading the transaction history when `history` is set
+    fn to_user(&self, user_id: &str, record: StoredUser, history: bool) -> Result<UserData, Error> {
+        let mut user = record.user;
+        user.aggregates = record.aggregates;
+        if history {
+            for entry in self.transactions.scan_prefix(SledUserStore::transaction_prefix(user_id)) {
+                let (_, bytes) = entry?;
+                user.transactions.push(serde_json::from_slice(&bytes)?);
+            }
+        }
+        Ok(user)
+    }
+
+    /// Loads a user, with or without the transaction history
+    fn load_user(&self, user_id: &str, history: bool) -> Result<Option<Arc<UserData>>, Error> {
+        match self.load_record(user_id)? {
+            Some(record) => Ok(Some(Arc::new(self.to_user(user_id, record, history)?))),
+            None => Ok(None),
+        }
+    }
+}
+
+#[async_trait]
+impl UserStore for SledUserStore {
+    async fn get_user(&self, user_id: &str) -> Result<Option<Arc<UserData>>, Error> {
+        let user_id = user_id.to_string();
+        self.blocking(move |trees| trees.load_user(&user_id, true)).await
+    }
+
+    async fn get_user_summary(&self, user_id: &str) -> Result<Option<Arc<UserData>>, Error> {
+        let user_id = user_id.to_string();
+        self.blocking(move |trees| trees.load_user(&user_id, false)).await
+    }
+
+    async fn put_user(&self, user_id: &str, mut user: UserData) -> Result<(), Error> {
+        let _guard = self.write_lock(user_id).lock().await;
+        let transactions = std::mem::take(&mut user.transactions);
+        let aggregates = TransactionAggregates::from_transactions(&transactions);
+        let record = serde_json::to_vec(&StoredUser { user, aggregates })?;
+        let mut entries = Vec::with_capacity(transactions.len());
+        for (seq, transaction) in transactions.iter().enumerate() {
+            entries.push((Self::transaction_key(user_id, seq), serde_json::to_vec(transaction)?));
+        }
+        let user_id = user_id.to_string();
+
+        self.blocking(move |trees| {
+            let stale = trees
+                .transactions
+                .scan_prefix(Self::transaction_prefix(&user_id))
+                .keys()
+                .collect::<Result<Vec<_>, _>>()?;
+
+            // Replace the record and the whole history atomically
+            (&trees.users, &trees.transactions)
+                .transaction(|(users, stored)| {
+                    for key in &stale {
+                        stored.remove(key)?;
+                    }
+                    for (key, bytes) in &entries {
+                        stored.insert(key.as_slice(), bytes.as_slice())?;
+                    }
+                    users.insert(user_id.as_bytes(), record.as_slice())?;
+                    Ok::<_, ConflictableTransactionError>(())
+                })
+                .map_err(|e| Error::msg(format!("Failed to store user {}: {}", user_id, e)))
+        })
+        .await?;
+        self.trees.db.flush_async().await?;
+        Ok(())
+    }
+
+    async fn append_transaction(&self, user_id: &str, transaction: Transaction) -> Result<(), Error> {
+        let _guard = self.write_lock(user_id).lock().await;
+        let user_id = user_id.to_string();
+
+        self.blocking(move |trees| {
+            let mut record = trees.load_record(&user_id)?.ok_or_else(|| not_found("User"))?;
+            let key = Self::transaction_key(&user_id, record.aggregates.total_count());
+            let bytes = serde_json::to_vec(&transaction)?;
+            record.aggregates.record(&transaction);
+            let record = serde_json::to_vec(&record)?;
+
+            (&trees.users, &trees.transactions)
+                .transaction(|(users, stored)| {
+                    stored.insert(key.as_slice(), bytes.as_slice())?;
+                    users.insert(user_id.as_bytes(), record.as_slice())?;
+                    Ok::<_, ConflictableTransactionError>(())
+                })
+                .map_err(|e| Error::msg(format!("Failed to store transaction for user {}: {}", user_id, e)))
+        })
+        .await?;
+        self.trees.db.flush_async().await?;
+        Ok(())
+    }
+
+    async fn all_users(&self) -> Result<Vec<Arc<UserData>>, Error> {
+        self.blocking(|trees| {
+            let mut users = Vec::new();
+            for entry in trees.users.iter() {
+                let (key, bytes) = entry?;
+                let user_id = String::from_utf8(key.to_vec())?;
+                let record: StoredUser = serde_json::from_slice(&bytes)?;
+                users.push(Arc::new(trees.to_user(&user_id, record, true)?));
+            }
+            Ok(users)
+        })
+        .await
+    }
+
+    async fn all_cases(&self) -> Result<Vec<Case>, Error> {
+        self.blocking(|trees| {
+            let mut cases = Vec::new();
+            for entry in trees.cases.iter() {
+                let (_, bytes) = entry?;
+                cases.push(serde_json::from_slice(&bytes)?);
+            }
+            Ok(cases)
+        })
+        .await
+    }
+
+    async fn put_case(&self, case: &Case, rule_weights: Option<&BTreeMap<String, f64>>) -> Result<(), Error> {
+        let id = case.id;
+        let bytes = serde_json::to_vec(case)?;
+        let mut weights = Vec::new();
+        for (name, weight) in rule_weights.into_iter().flatten() {
+            weights.push((name.clone(), serde_json::to_vec(weight)?));
+        }
+
+        // The case outcome and the weights it produced are stored atomically
+        self.blocking(move |trees| {
+            (&trees.cases, &trees.rule_weights)
+                .transaction(|(cases, stored)| {
+                    cases.insert(&id.to_be_bytes(), bytes.as_slice())?;
+                    for (name, weight) in &weights {
+                        stored.insert(name.as_bytes(), weight.as_slice())?;
+                    }
+                    Ok::<_, ConflictableTransactionError>(())
+                })
+                .map_err(|e| Error::msg(format!("Failed to store case {}: {}", id, e)))
+        })
+        .await?;
+        self.trees.db.flush_async().await?;
+        Ok(())
+    }
+
+    async fn rule_weights(&self) -> Result<BTreeMap<String, f64>, Error> {
+        self.blocking(|trees| {
+            let mut weights = BTreeMap::new();
+            for entry in trees.rule_weights.iter() {
+                let (name, bytes) = entry?;
+                weights.insert(String::from_utf8(name.to_vec())?, serde_json::from_slice(&bytes)?);
+            }
+            Ok(weights)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+pub trait FraudDetection {
+    async fn detect_fraud(&self, user_id: &str) -> Result<RiskAssessment, Error>;
+
+    /// Scores a transaction against the user's history before it is committed
+    async fn score_transaction(&self, user_id: &str, transaction: &Transaction) -> Result<TransactionDecision, Error>;
+}
+
+impl Default for FraudDetectionService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FraudDetectionService {
+    /// Creates a new instance of FraudDetectionService
+    pub fn new() -> Self {
+        Self::with_rules(RuleSet::default())
+    }
+
+    /// Creates a new instance of FraudDetectionService that scores users with the given rules
+    pub fn with_rules(rules: RuleSet) -> Self {
+        Self::with_store(Arc::new(MemoryUserStore::new(DEFAULT_SHARDS)), rules)
+    }
+
+    /// Creates a new instance of FraudDetectionService on top of a storage backend
+    pub fn with_store(store: Arc<dyn UserStore>, rules: RuleSet) -> Self {
+        FraudDetectionService {
+            user_data: store,
+            rules: Arc::new(RwLock::new(rules)),
+            cases: Arc::new(Mutex::new(CaseQueue::default())),
+            identities: Arc::new(RwLock::new(IdentityIndex::default())),
+        }
+    }
+
+    /// Rebuilds the identity index from the store, returning the number of users indexed
+    pub async fn rebuild_identity_index(&self) -> Result<usize, Error> {
+        let users = self.user_data.all_users().await?;
+        let mut index = IdentityIndex::default();
+        for user in &users {
+            index.index_user(&user.id, user.identifiers());
+        }
+        *self.identities.write().await = index;
+        Ok(users.len())
+    }
+
+    /// Loads stored cases and learned rule weights, returning the number of cases restored
+    pub async fn restore_review_state(&self) -> Result<usize, Error> {
+        let weights = self.user_data.rule_weights().await?;
+        self.rules.write().await.apply_weights(&weights);
+        let stored = self.user_data.all_cases().await?;
+        let count = stored.len();
+        let mut cases = self.cases.lock().await;
+        for case in stored {
+            cases.insert(case);
+        }
+        Ok(count)
+    }
+
+    /// Ids of the users that use an email, device or card fingerprint
+    pub async fn find_users(&self, kind: IdentifierKind, value: &str) -> Vec<String> {
+        self.identities.read().await.find(&Identifier::new(kind, value))
+    }
+
+    /// Other users sharing an email, device or card with the user
+    pub async fn linked_users(&self, user_id: &str) -> Result<Vec<UserLink>, Error> {
+        self.user_summary(user_id).await?;
+        Ok(self.identities.read().await.links(user_id, None))
+    }
+
+    /// Current rules, including weight adjustments from resolved cases
+    pub async fn rules(&self) -> RuleSet {
+        self.rules.read().await.clone()
+    }
+
+    /// Lists cases, optionally only those with the given status, ordered by id
+    pub async fn list_cases(&self, status: Option<CaseStatus>) -> Vec<Case> {
+        let cases = self.cases.lock().await;
+        let mut cases: Vec<Case> = cases
+            .cases
+            .values()
+            .filter(|case| status.is_none_or(|status| case.status == status))
+            .cloned()
+            .collect();
+        cases.sort_by_key(|case| case.id);
+        cases
+    }
+
+    pub async fn get_case(&self, case_id: u64) -> Result<Case, Error> {
+        let cases = self.cases.lock().await;
+        Ok(cases.get(case_id)?.clone())
+    }
+
+    /// Opens a case for a user unless one is already open, returning the open case id.
+    /// The case is queued before it is stored, so concurrent flags share it, and is
+    /// withdrawn again if the write fails.
+    async fn open_case(&self, user_id: &str, score: f64, fired_rules: Vec<FiredRule>, reason: &str) -> Result<u64, Error> {
+        let case = {
+            let mut cases = self.cases.lock().await;
+            if let Some(case_id) = cases.open_case_for(user_id) {
+                return Ok(case_id);
+            }
+            let case = cases.new_case(user_id, score, fired_rules, reason);
+            cases.insert(case.clone());
+            case
+        };
+        if let Err(e) = self.user_data.put_case(&case, None).await {
+            self.cases.lock().await.remove(case.id);
+            return Err(e);
+        }
+        Ok(case.id)
+    }
+
+    /// Scores the user and opens a case when the user is flagged
+    pub async fn review_user(&self, user_id: &str) -> Result<RiskAssessment, Error> {
+        let assessment = self.detect_fraud(user_id).await?;
+        if assessment.is_fraud {
+            self.open_case(user_id, assessment.score, assessment.fired_rules.clone(), "user flagged").await?;
+        }
+        Ok(assessment)
+    }
+
+    /// Scores an incoming transaction and opens a case unless it is approved
+    pub async fn review_transaction(&self, user_id: &str, transaction: &Transaction) -> Result<TransactionDecision, Error> {
+        let result = self.score_transaction(user_id, transaction).await?;
+        if result.decision != Decision::Approve {
+            let outcome = if result.decision == Decision::Decline { "declined" } else { "held for review" };
+            let reason = format!("transaction of {:.2} {}", transaction.amount, outcome);
+            self.open_case(user_id, result.score, result.fired_rules.clone(), &reason).await?;
+        }
+        Ok(result)
+    }
+
+    /// Adds an analyst note to a case
+    pub async fn add_case_note(&self, case_id: u64, author: &str, text: &str) -> Result<Case, Error> {
+        let mut cases = self.cases.lock().await;
+        let mut case = cases.get(case_id)?.clone();
+        case.notes.push(CaseNote { author: author.to_string(), text: text.to_string(), created_at: Utc::now() });
+        case.record(author, "added a note".to_string());
+        self.user_data.put_case(&case, None).await?;
+        cases.insert(case.clone());
+        Ok(case)
+    }
+
+    /// Resolves an open case and feeds the outcome back into the rule weights
+    pub async fn resolve_case(&self, case_id: u64, analyst: &str, status: CaseStatus) -> Result<Case, Error> {
+        let mut cases = self.cases.lock().await;
+        let mut case = cases.get(case_id)?.clone();
+        if case.status != CaseStatus::Open {
+            return Err(invalid_request(format!("Case {} is already resolved", case_id)));
+        }
+        let (confirmed_fraud, outcome) = match status {
+            CaseStatus::ConfirmedFraud => (true, "confirmed fraud"),
+            CaseStatus::FalsePositive => (false, "false positive"),
+            CaseStatus::Open => {
+                return Err(invalid_request("A case can only be resolved as confirmed_fraud or false_positive".to_string()))
+            }
+        };
+
+        case.status = status;
+        case.record(analyst, format!("resolved as {}", outcome));
+        // Store the outcome and the adjusted weights before either takes effect
+        let mut rules = self.rules.write().await;
+        let mut adjusted = rules.clone();
+        adjusted.apply_feedback(&case.fired_rules, confirmed_fraud);
+        self.user_data.put_case(&case, Some(&adjusted.weights())).await?;
+        *rules = adjusted;
+        cases.insert(case.clone());
+        Ok(case)
+    }
+
+    /// Adds or replaces a user, keyed by the user's ID, and returns the ID
+    pub async fn add_user(&self, user: UserData) -> Result<String, Error> {
+        let user_id = user.id.clone();
+        let identifiers = user.identifiers();
+        self.user_data.put_user(&user_id, user).await?;
+        // Index only once the user is stored, so a failed write leaves no stale entries
+        self.identities.write().await.index_user(&user_id, identifiers);
+        Ok(user_id)
+    }
+
+    /// Commits a transaction to a user's history
+    pub async fn append_transaction(&self, user_id: &str, transaction: Transaction) -> Result<(), Error> {
+        let identifiers: Vec<Identifier> = transaction.identifiers().collect();
+        self.user_data.append_transaction(user_id, transaction).await?;
+        self.identities.write().await.add_identifiers(user_id, identifiers);
+        Ok(())
+    }
+
+    /// Loads the user's profile and aggregates without the transaction history
+    async fn user_summary(&self, user_id: &str) -> Result<Arc<UserData>, Error> {
+        self.user_data.get_user_summary(user_id).await?.ok_or_else(|| not_found("User"))
+    }
+}
+
+#[async_trait]
+impl FraudDetection for FraudDetectionService {
+    /// Detects fraud by evaluating the user's aggregates against the rule set
+    async fn detect_fraud(&self, user_id: &str) -> Result<RiskAssessment, Error> {
+        let user = self.user_summary(user_id).await?;
+        let links = self.identities.read().await.links(user_id, None);
+        Ok(self.rules.read().await.evaluate(&user, &links))
+    }
+
+    /// Scores an incoming transaction without adding it to the user's history
+    async fn score_transaction(&self, user_id: &str, transaction: &Transaction) -> Result<TransactionDecision, Error> {
+        let user = self.user_summary(user_id).await?;
+        let links = self.identities.read().await.links(user_id, transaction.identifiers());
+        Ok(self.rules.read().await.evaluate_transaction(&user, transaction, &links))
+    }
+}
+
+/// Body of a request to add a user; the ID is generated when not supplied
+#[derive(Deserialize)]
+struct NewUser {
+    #[serde(default = "new_user_id")]
+    id: String,
+    name: String,
+    #[serde(default)]
+    email: Option<String>,
+    #[serde(default)]
+    devices: Vec<String>,
+    age: u32,
+    transactions: Vec<Transaction>,
+}
+
+impl NewUser {
+    fn into_user(self) -> UserData {
+        let mut user = UserData::new(&self.name, self.age, self.transactions);
+        user.id = self.id;
+        user.email = self.email;
+        user.devices = self.devices;
+        user
+    }
+}
+
+/// Body of a case note request
+#[derive(Deserialize)]
+struct NoteRequest {
+    author: String,
+    text: String,
+}
+
+/// Body of a case resolution request
+#[derive(Deserialize)]
+struct ResolveRequest {
+    analyst: String,
+    status: CaseStatus,
+}
+
+/// Query parameters for listing cases
+#[derive(Deserialize)]
+struct CaseQuery {
+    status: Option<CaseStatus>,
+}
+
+/// Converts a service result into a JSON reply, mapping missing users and cases to 404,
+/// invalid requests to 400 and storage or other server failures to 500
+fn json_reply<T: Serialize>(result: Result<T, Error>) -> Result<warp::reply::Response, Rejection> {
+    let response = match result {
+        Ok(value) => warp::reply::json(&value).into_response(),
+        Err(e) => {
+            let status = if e.downcast_ref::<NotFound>().is_some() {
+                StatusCode::NOT_FOUND
+            } else if e.downcast_ref::<InvalidRequest>().is_some() {
+                StatusCode::BAD_REQUEST
+            } else {
+                StatusCode::INTERNAL_SERVER_ERROR
+            };
+            let body = warp::reply::json(&serde_json::json!({ "error": e.to_string() }));
+            warp::reply::with_status(body, status).into_response()
+        }
+    };
+    Ok(response)
+}
+
+/// Builds the HTTP API routes
+fn routes(service: FraudDetectionService) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let with_service = warp::any().map(move || service.clone());
+
+    // POST /users: add or replace a user, returning its ID
+    let put_user = warp::path!("users")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_service.clone())
+        .and_then(|user: NewUser, service: FraudDetectionService| async move {
+            json_reply(service.add_user(user.into_user()).await.map(|id| serde_json::json!({ "id": id })))
+        });
+
+    // GET /users/:id/links: other users sharing identifiers with the user
+    let user_links = warp::path!("users" / String / "links")
+        .and(warp::get())
+        .and(with_service.clone())
+        .and_then(|user_id: String, service: FraudDetectionService| async move {
+            json_reply(service.linked_users(&user_id).await)
+        });
+
+    // GET /identities/:kind/:value: users with an email, device or card
+    let find_users = warp::path!("identities" / String / String)
+        .and(warp::get())
+        .and(with_service.clone())
+        .and_then(|kind: String, value: String, service: FraudDetectionService| async move {
+            let kind = serde_json::from_value::<IdentifierKind>(serde_json::Value::String(kind));
+            match kind {
+                Ok(kind) => json_reply(Ok(service.find_users(kind, &value).await)),
+                Err(e) => json_reply::<()>(Err(invalid_request(format!("Unknown identifier kind: {}", e)))),
+            }
+        });
+
+    // POST /users/:id/transactions: commit a transaction
+    let append_transaction = warp::path!("users" / String / "transactions")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_service.clone())
+        .and_then(|user_id: String, transaction: Transaction, service: FraudDetectionService| async move {
+            json_reply(service.append_transaction(&user_id, transaction).await)
+        });
+
+    // GET /users/:id/score: score the user without side effects
+    let score_user = warp::path!("users" / String / "score")
+        .and(warp::get())
+        .and(with_service.clone())
+        .and_then(|user_id: String, service: FraudDetectionService| async move {
+            json_reply(service.detect_fraud(&user_id).await)
+        });
+
+    // POST /users/:id/review: score the user and open a case if flagged
+    let review_user = warp::path!("users" / String / "review")
+        .and(warp::post())
+        .and(with_service.clone())
+        .and_then(|user_id: String, service: FraudDetectionService| async move {
+            json_reply(service.review_user(&user_id).await)
+        });
+
+    // POST /users/:id/transactions/score: score a transaction before it is committed,
+    // opening a case unless it is approved
+    let score_transaction = warp::path!("users" / String / "transactions" / "score")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_service.clone())
+        .and_then(|user_id: String, transaction: Transaction, service: FraudDetectionService| async move {
+            json_reply(service.review_transaction(&user_id, &transaction).await)
+        });
+
+    // GET /cases?status=open
+    let list_cases = warp::path!("cases")
+        .and(warp::get())
+        .and(warp::query::<CaseQuery>())
+        .and(with_service.clone())
+        .and_then(|query: CaseQuery, service: FraudDetectionService| async move {
+            json_reply(Ok(service.list_cases(query.status).await))
+        });
+
+    // GET /cases/:id
+    let get_case = warp::path!("cases" / u64)
+        .and(warp::get())
+        .and(with_service.clone())
+        .and_then(|case_id: u64, service: FraudDetectionService| async move {
+            json_reply(service.get_case(case_id).await)
+        });
+
+    // POST /cases/:id/notes
+    let add_note = warp::path!("cases" / u64 / "notes")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_service.clone())
+        .and_then(|case_id: u64, note: NoteRequest, service: FraudDetectionService| async move {
+            json_reply(service.add_case_note(case_id, &note.author, &note.text).await)
+        });
+
+    // POST /cases/:id/resolve
+    let resolve_case = warp::path!("cases" / u64 / "resolve")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_service.clone())
+        .and_then(|case_id: u64, request: ResolveRequest, service: FraudDetectionService| async move {
+            json_reply(service.resolve_case(case_id, &request.analyst, request.status).await)
+        });
+
+    // GET /rules: current rules and weights
+    let get_rules = warp::path!("rules")
+        .and(warp::get())
+        .and(with_service)
+        .and_then(|service: FraudDetectionService| async move { json_reply(Ok(service.rules().await)) });
+
+    put_user
+        .or(user_links)
+        .or(find_users)
+        .or(append_transaction)
+        .or(score_user)
+        .or(review_user)
+        .or(score_transaction)
+        .or(list_cases)
+        .or(get_case)
+        .or(add_note)
+        .or(resolve_case)
+        .or(get_rules)
+}
+
+#[tokio::main]
+async fn main() {
+    // Load rules from the rules file if present, otherwise fall back to the built-in rules
+    let rules = match RuleSet::from_file(Path::new("fraud_rules.toml")).await {
+        Ok(rules) => rules,
+        Err(e) => {
+            println!("Using default rules: {}", e);
+            RuleSet::default()
+        }
+    };
+    // Keep user data on disk when the database can be opened, otherwise in memory
+    let store: Arc<dyn UserStore> = match SledUserStore::open(Path::new("fraud_users.db")) {
+        Ok(store) => Arc::new(store),
+        Err(e) => {
+            println!("Using in-memory storage: {}", e);
+            Arc::new(MemoryUserStore::new(DEFAULT_SHARDS))
+        }
+    };
+    let service = FraudDetectionService::with_store(store, rules);
+    match service.rebuild_identity_index().await {
+        Ok(count) => println!("Indexed identifiers of {} stored users", count),
+        Err(e) => println!("Error indexing stored users: {}", e),
+    }
+    match service.restore_review_state().await {
+        Ok(count) => println!("Restored {} review cases and learned rule weights", count),
+        Err(e) => println!("Error restoring review cases: {}", e),
+    }
+
+    // Example user data
+    let transactions = vec![
+        Transaction::new(100.0, "2023-01-01T10:00:00Z", Some("grocer")).unwrap().with_card("card-1234"),
+        Transaction::new(200.0, "2023-01-02T18:30:00Z", Some("bookshop")).unwrap().with_device("device-a"),
+        // Add more transactions as needed
+    ];
+    let user = UserData::new("John Doe", 30, transactions).with_email("john@example.com");
+
+    // Add user to the service
+    let user_id = match service.add_user(user).await {
+        Ok(user_id) => user_id,
+        Err(e) => {
+            println!("Error adding user: {}", e);
+            return;
+        }
+    };
+
+    // A second customer with the same name is stored separately, but shares a card
+    let namesake = UserData::new("John Doe", 45, vec![
+        Transaction::new(80.0, "2023-01-02T09:00:00Z", Some("cafe")).unwrap().with_card("card-1234"),
+    ]);
+    if let Err(e) = service.add_user(namesake).await {
+        println!("Error adding user: {}", e);
+    }
+    match service.linked_users(&user_id).await {
+        Ok(links) => {
+            for link in links {
+                println!("User {} shares {:?}", link.user_id, link.shared);
+            }
+        }
+        Err(e) => println!("Error finding linked users: {}", e),
+    }
+
+    // Score the user, opening a case if flagged
+    match service.review_user(&user_id).await {
+        Ok(assessment) => {
+            println!("Fraud detected: {} (risk score {:.2})", assessment.is_fraud, assessment.score);
+            for rule in &assessment.fired_rules {
+                println!("  {} (+{:.2}): {}", rule.name, rule.weight, rule.reason);
+            }
+        }
+        Err(e) => println!("Error detecting fraud: {}", e),
+    }
+
+    // Score an incoming transaction before it is committed
+    let incoming = Transaction::new(750.0, "2023-01-03T02:15:00Z", Some("electronics")).unwrap();
+    match service.review_transaction(&user_id, &incoming).await {
+        Ok(result) => {
+            println!("Transaction decision: {:?} (risk score {:.2})", result.decision, result.score);
+            for rule in &result.fired_rules {
+                println!("  {} (+{:.2}): {}", rule.name, rule.weight, rule.reason);
+            }
+        }
+        Err(e) => println!("Error scoring transaction: {}", e),
+    }
+
+    // Commit the transaction so later checks see it in the user's aggregates
+    if let Err(e) = service.append_transaction(&user_id, incoming).await {
+        println!("Error appending transaction: {}", e);
+    }
+
+    // Serve the HTTP API
+    println!("Fraud service listening on http://127.0.0.1:3030");
+    warp::serve(routes(service)).run(([127, 0, 0, 1], 3030)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn transaction(amount: f64, date: &str) -> Transaction {
+        Transaction::new(amount, date, None).unwrap()
+    }
+
+    fn merchant_transaction(amount: f64, date: &str, merchant: &str) -> Transaction {
+        Transaction::new(amount, date, Some(merchant)).unwrap()
+    }
+
+    fn user(age: u32, transactions: Vec<Transaction>) -> UserData {
+        UserData::new("Test User", age, transactions)
+    }
+
+    #[test]
+    fn default_rules_match_the_original_checks() {
+        let rules = RuleSet::default();
+        let quiet = user(30, vec![transaction(100.0, "2023-01-01"), transaction(200.0, "2023-01-02")]);
+        assert_eq!(rules.evaluate(&quiet, &[]), RiskAssessment { score: 0.0, is_fraud: false, fired_rules: vec![] });
+
+        let busy = user(30, (0..11).map(|_| transaction(10.0, "2023-01-01")).collect());
+        let assessment = rules.evaluate(&busy, &[]);
+        assert!(assessment.is_fraud);
+        assert_eq!(assessment.fired_rules[0].name, "velocity");
+        assert_eq!(assessment.fired_rules[0].reason, "11 transactions exceed the limit of 10");
+
+        let big = user(30, vec![transaction(1000.5, "2023-01-01")]);
+        assert_eq!(rules.evaluate(&big, &[]).fired_rules[0].name, "large_amount");
+    }
+
+    #[test]
+    fn age_limit_respects_open_bounds() {
+        let rules: RuleSet = toml::from_str(
+            r#"
+            fraud_threshold = 1.0
+
+            [[rules]]
+            name = "minor_spending"
+            weight = 0.6
+            type = "age_limit"
+            max_age = 17
+            max_amount = 200.0
+
+            [[rules]]
+            name = "large_amount"
+            weight = 0.5
+            type = "amount_above"
+            amount = 1000.0
+            "#,
+        )
+        .unwrap();
+
+        let minor = user(16, vec![transaction(300.0, "2023-01-01")]);
+        let assessment = rules.evaluate(&minor, &[]);
+        assert_eq!(assessment.fired_rules.len(), 1);
+        assert_eq!(assessment.fired_rules[0].name, "minor_spending");
+        assert!(!assessment.is_fraud);
+
+        let adult = user(30, vec![transaction(300.0, "2023-01-01")]);
+        assert!(rules.evaluate(&adult, &[]).fired_rules.is_empty());
+
+        let big_spender = user(16, vec![transaction(1500.0, "2023-01-01")]);
+        let assessment = rules.evaluate(&big_spender, &[]);
+        assert_eq!(assessment.score, 1.1);
+        assert!(assessment.is_fraud);
+    }
+
+    #[test]
+    fn time_of_day_and_new_account_rules() {
+        let rules: RuleSet = serde_json::from_str(
+            r#"{
+                "fraud_threshold": 2.0,
+                "rules": [
+                    { "name": "night", "weight": 0.5, "type": "time_of_day", "start_hour": 22, "end_hour": 6 },
+                    { "name": "new_account", "weight": 0.8, "type": "new_account", "max_history": 2, "max_amount": 500.0 },
+                    { "name": "disabled", "weight": 5.0, "enabled": false, "type": "amount_above", "amount": 0.0 }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        // The night window wraps around midnight
+        let late = user(30, vec![transaction(10.0, "2023-01-01T23:15:00"), transaction(10.0, "2023-01-02")]);
+        assert_eq!(rules.evaluate(&late, &[]).fired_rules[0].reason, "transaction at 23:00 is between 22:00 and 06:00");
+        let early = user(30, vec![transaction(10.0, "2023-01-02 05:30:00"), transaction(10.0, "2023-01-02 00:10:00")]);
+        assert_eq!(rules.evaluate(&early, &[]).fired_rules[0].reason, "transaction at 00:00 is between 22:00 and 06:00");
+        let daytime = user(30, vec![transaction(10.0, "2023-01-01 12:00:00")]);
+        assert!(rules.evaluate(&daytime, &[]).fired_rules.is_empty());
+
+        // Accounts with more than two transactions are no longer new
+        let established = user(30, vec![
+            transaction(10.0, "2023-01-01 12:00:00"),
+            transaction(10.0, "2023-01-02 12:00:00"),
+            transaction(900.0, "2023-01-03 12:00:00"),
+        ]);
+        assert!(rules.evaluate(&established, &[]).fired_rules.is_empty());
+        let fresh = user(30, vec![transaction(10.0, "2023-01-01 12:00:00"), transaction(900.0, "2023-01-02 12:00:00")]);
+        let assessment = rules.evaluate(&fresh, &[]);
+        assert_eq!(assessment.fired_rules.len(), 1);
+        assert_eq!(assessment.fired_rules[0].reason, "largest amount 900.00 exceeds 500.00 with only 2 transactions");
+        assert!(!assessment.is_fraud);
+    }
+
+    #[tokio::test]
+    async fn rules_load_from_toml_and_json_files() {
+        let dir = std::env::temp_dir();
+        let toml_path = dir.join(format!("fraud_rules_{}.toml", std::process::id()));
+        let json_path = dir.join(format!("fraud_rules_{}.json", std::process::id()));
+        let yaml_path = dir.join(format!("fraud_rules_{}.yaml", std::process::id()));
+        std::fs::write(&toml_path, toml::to_string(&RuleSet::default()).unwrap()).unwrap();
+        std::fs::write(&json_path, serde_json::to_string(&RuleSet::default()).unwrap()).unwrap();
+        std::fs::write(&yaml_path, "fraud_threshold: 1.0").unwrap();
+
+        assert_eq!(RuleSet::from_file(&toml_path).await.unwrap(), RuleSet::default());
+        assert_eq!(RuleSet::from_file(&json_path).await.unwrap(), RuleSet::default());
+        assert!(RuleSet::from_file(&yaml_path).await.is_err());
+        for path in [toml_path, json_path, yaml_path] {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    #[tokio::test]
+    async fn transactions_are_scored_without_being_committed() {
+        let rules: RuleSet = toml::from_str(
+            r#"
+            fraud_threshold = 1.0
+            review_threshold = 0.5
+
+            [[rules]]
+            name = "night"
+            weight = 0.5
+            type = "time_of_day"
+            start_hour = 0
+            end_hour = 6
+
+            [[rules]]
+            name = "large_amount"
+            weight = 0.6
+            type = "amount_above"
+            amount = 500.0
+            "#,
+        )
+        .unwrap();
+        let service = FraudDetectionService::with_rules(rules);
+        let alice = service.add_user(UserData::new("alice", 30, vec![transaction(100.0, "2023-01-01")])).await.unwrap();
+
+        let approve = service.score_transaction(&alice, &transaction(50.0, "2023-01-02T12:00:00")).await.unwrap();
+        assert_eq!((approve.decision, approve.score), (Decision::Approve, 0.0));
+        let review = service.score_transaction(&alice, &transaction(50.0, "2023-01-02T02:00:00")).await.unwrap();
+        assert_eq!(review.decision, Decision::Review);
+        let decline = service.score_transaction(&alice, &transaction(750.0, "2023-01-03T02:15:00")).await.unwrap();
+        assert_eq!(decline.decision, Decision::Decline);
+        assert_eq!(decline.fired_rules.iter().map(|rule| rule.name.as_str()).collect::<Vec<_>>(), vec!["night", "large_amount"]);
+
+        // Scoring leaves the history untouched
+        assert_eq!(service.user_data.get_user(&alice).await.unwrap().unwrap().transactions.len(), 1);
+        assert!(service.score_transaction("bob", &transaction(1.0, "2023-01-01")).await.is_err());
+    }
+
+    #[test]
+    fn transaction_dates_accept_common_formats() {
+        let expected = Utc.with_ymd_and_hms(2023, 5, 1, 12, 30, 0).unwrap();
+        for date in ["2023-05-01T12:30:00Z", "2023-05-01T14:30:00+02:00", "2023-05-01 12:30:00", "2023-05-01T12:30"] {
+            assert_eq!(parse_transaction_date(date).unwrap(), expected, "{}", date);
+        }
+        assert_eq!(parse_transaction_date("2023-05-01").unwrap(), Utc.with_ymd_and_hms(2023, 5, 1, 0, 0, 0).unwrap());
+        assert!(parse_transaction_date("01/05/2023").is_err());
+
+        let parsed: Transaction = serde_json::from_str(r#"{"amount": 5.0, "date": "2023-05-01 12:30:00"}"#).unwrap();
+        assert_eq!((parsed.date, parsed.merchant), (expected, None));
+        assert!(serde_json::from_str::<Transaction>(r#"{"amount": 5.0, "date": "yesterday"}"#).is_err());
+    }
+
+    #[test]
+    fn aggregates_expire_sliding_windows() {
+        let mut aggregates = TransactionAggregates::from_transactions(&[
+            merchant_transaction(10.0, "2023-05-01 10:00:00", "a"),
+            merchant_transaction(20.0, "2023-05-01 10:00:30", "b"),
+            merchant_transaction(30.0, "2023-05-01 10:30:00", "a"),
+        ]);
+        assert_eq!(aggregates.window(WindowSpan::Minute).count, 1);
+        assert_eq!(aggregates.window(WindowSpan::Hour).count, 3);
+        assert_eq!(aggregates.window(WindowSpan::Hour).sum, 60.0);
+        assert_eq!(aggregates.distinct_merchants(WindowSpan::Hour, Some("a")), 2);
+        assert_eq!(aggregates.distinct_merchants(WindowSpan::Hour, Some("c")), 3);
+
+        // A late transaction outside the minute window still counts for the hour and the totals
+        aggregates.record(&merchant_transaction(5.0, "2023-05-01 10:20:00", "c"));
+        assert_eq!(aggregates.window(WindowSpan::Minute).count, 1);
+        assert_eq!(aggregates.window(WindowSpan::Hour).count, 4);
+        assert_eq!(aggregates.total_count(), 4);
+
+        aggregates.advance_to(parse_transaction_date("2023-05-01 11:15:00").unwrap());
+        let hour = aggregates.window(WindowSpan::Hour);
+        assert_eq!((hour.count, hour.sum), (2, 35.0));
+        assert_eq!(aggregates.distinct_merchants(WindowSpan::Hour, None), 2);
+
+        aggregates.advance_to(parse_transaction_date("2023-05-03").unwrap());
+        let day = aggregates.window(WindowSpan::Day);
+        assert_eq!((day.count, day.sum), (0, 0.0));
+        assert_eq!(aggregates.total_count(), 4);
+    }
+
+    #[test]
+    fn amount_zscore_needs_spread() {
+        let flat = TransactionAggregates::from_transactions(&[transaction(10.0, "2023-01-01"), transaction(10.0, "2023-01-02")]);
+        assert_eq!(flat.amount_zscore(100.0), None);
+        let aggregates = TransactionAggregates::from_transactions(&[
+            transaction(10.0, "2023-01-01"),
+            transaction(20.0, "2023-01-02"),
+            transaction(30.0, "2023-01-03"),
+        ]);
+        assert_eq!(aggregates.amount_zscore(40.0), Some(2.0));
+        assert_eq!(TransactionAggregates::default().amount_zscore(40.0), None);
+    }
+
+    #[test]
+    fn transactions_are_scored_against_history() {
+        let rules: RuleSet = toml::from_str(
+            r#"
+            fraud_threshold = 1.0
+            review_threshold = 0.5
+
+            [[rules]]
+            name = "burst"
+            weight = 0.5
+            type = "velocity"
+            max_transactions = 3
+            window = "minute"
+
+            [[rules]]
+            name = "hour_spend"
+            weight = 0.5
+            type = "amount_velocity"
+            max_total = 100.0
+            window = "hour"
+
+            [[rules]]
+            name = "merchants"
+            weight = 0.2
+            type = "distinct_merchants"
+            max_merchants = 2
+            window = "day"
+
+            [[rules]]
+            name = "unusual_amount"
+            weight = 0.2
+            type = "amount_z_score"
+            max_z = 3.0
+            min_history = 3
+            "#,
+        )
+        .unwrap();
+        let user = UserData::new(
+            "Ann",
+            30,
+            vec![
+                merchant_transaction(20.0, "2023-05-01 12:00:00", "a"),
+                merchant_transaction(21.0, "2023-05-01 12:00:10", "b"),
+                merchant_transaction(22.0, "2023-05-01 12:00:20", "a"),
+            ],
+        );
+        let score = |transaction: Transaction| rules.evaluate_transaction(&user, &transaction, &[]);
+
+        let decision = score(merchant_transaction(10.0, "2023-05-01 12:05:00", "a"));
+        assert_eq!((decision.decision, decision.score), (Decision::Approve, 0.0));
+
+        let decision = score(transaction(10.0, "2023-05-01 12:00:30"));
+        assert_eq!(decision.decision, Decision::Review);
+        assert_eq!(decision.fired_rules[0].name, "burst");
+
+        let decision = score(merchant_transaction(50.0, "2023-05-01 12:00:40", "c"));
+        assert_eq!(decision.decision, Decision::Decline);
+        let fired: Vec<&str> = decision.fired_rules.iter().map(|rule| rule.name.as_str()).collect();
+        assert_eq!(fired, vec!["burst", "hour_spend", "merchants", "unusual_amount"]);
+
+        // All windows have expired by the next afternoon
+        let decision = score(merchant_transaction(21.0, "2023-05-02 13:00:00", "c"));
+        assert!(decision.fired_rules.is_empty());
+
+        // Scoring the user reads the aggregates as of the latest transaction
+        assert!(rules.evaluate(&user, &[]).fired_rules.is_empty());
+        let mut busy = user.clone();
+        busy.push_transaction(merchant_transaction(50.0, "2023-05-01 12:00:40", "c"));
+        let assessment = rules.evaluate(&busy, &[]);
+        let fired: Vec<&str> = assessment.fired_rules.iter().map(|rule| rule.name.as_str()).collect();
+        assert_eq!(fired, vec!["burst", "hour_spend", "merchants"]);
+        assert!(assessment.is_fraud);
+        // The transaction history itself is never read
+        busy.transactions.clear();
+        assert_eq!(rules.evaluate(&busy, &[]), assessment);
+    }
+
+    /// A fresh database directory under the system temp dir
+    fn temp_db(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("fraud_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        path
+    }
+
+    /// Opens the store, waiting for sled's background flusher of a dropped handle to release the lock
+    async fn open_store(path: &Path) -> SledUserStore {
+        for _ in 0..50 {
+            if let Ok(store) = SledUserStore::open(path) {
+                return store;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        SledUserStore::open(path).unwrap()
+    }
+
+    #[tokio::test]
+    async fn memory_store_handles_concurrent_appends() {
+        let store = Arc::new(MemoryUserStore::new(4));
+        for i in 0..8 {
+            let name = format!("user-{}", i);
+            store.put_user(&name, UserData::new(&name, 30, vec![transaction(1.0, "2023-01-01")])).await.unwrap();
+        }
+
+        // Readers keep the version they loaded while writers append
+        let before = store.get_user("user-0").await.unwrap().unwrap();
+        let mut tasks = Vec::new();
+        for i in 0..8 {
+            let store = store.clone();
+            tasks.push(tokio::spawn(async move {
+                let name = format!("user-{}", i % 4);
+                for _ in 0..25 {
+                    store.append_transaction(&name, transaction(2.0, "2023-01-02")).await.unwrap();
+                }
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(before.transactions.len(), 1);
+        for i in 0..4 {
+            let user = store.get_user(&format!("user-{}", i)).await.unwrap().unwrap();
+            assert_eq!((user.transactions.len(), user.aggregates.total_count()), (51, 51));
+        }
+        assert_eq!(store.get_user("user-4").await.unwrap().unwrap().transactions.len(), 1);
+        assert!(store.get_user("nobody").await.unwrap().is_none());
+        assert!(store.append_transaction("nobody", transaction(1.0, "2023-01-01")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn sled_store_persists_users_across_reopen() {
+        let path = temp_db("reopen");
+        {
+            let store = open_store(&path).await;
+            store.put_user("Jane", UserData::new("Jane", 40, vec![transaction(10.0, "2023-01-01")])).await.unwrap();
+            store.append_transaction("Jane", transaction(20.0, "2023-01-02")).await.unwrap();
+            assert!(store.append_transaction("John", transaction(1.0, "2023-01-01")).await.is_err());
+        }
+
+        let store = open_store(&path).await;
+        let user = store.get_user("Jane").await.unwrap().unwrap();
+        let amounts: Vec<f64> = user.transactions.iter().map(|t| t.amount).collect();
+        assert_eq!(amounts, vec![10.0, 20.0]);
+        assert_eq!((user.aggregates.total_count(), user.aggregates.window(WindowSpan::Day).sum), (2, 30.0));
+        assert!(store.get_user("John").await.unwrap().is_none());
+        drop(store);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn feedback_adjusts_fired_rule_weights() {
+        let mut rules = RuleSet::default();
+        let fired = vec![FiredRule { name: "velocity".to_string(), weight: 1.0, reason: String::new() }];
+        rules.apply_feedback(&fired, true);
+        let weights: Vec<f64> = rules.rules.iter().map(|rule| rule.weight).collect();
+        assert_eq!(weights, vec![1.05, 1.0]);
+        rules.apply_feedback(&fired, false);
+        assert!((rules.rules[0].weight - 0.9975).abs() < 1e-12);
+    }
+
+    #[tokio::test]
+    async fn flagged_transactions_open_one_case_per_user() {
+        let service = FraudDetectionService::new();
+        let jane = service.add_user(UserData::new("Jane", 40, vec![transaction(10.0, "2023-01-01")])).await.unwrap();
+        assert_eq!(service.review_transaction(&jane, &transaction(20.0, "2023-01-02")).await.unwrap().decision, Decision::Approve);
+        assert!(service.list_cases(None).await.is_empty());
+
+        let declined = service.review_transaction(&jane, &transaction(1500.0, "2023-01-02")).await.unwrap();
+        assert_eq!(declined.decision, Decision::Decline);
+        service.review_transaction(&jane, &transaction(2500.0, "2023-01-03")).await.unwrap();
+        let cases = service.list_cases(Some(CaseStatus::Open)).await;
+        assert_eq!(cases.len(), 1);
+        assert_eq!((cases[0].id, cases[0].user_id.as_str(), cases[0].score), (1, jane.as_str(), 1.0));
+
+        let case = service.add_case_note(1, "analyst", "called the customer").await.unwrap();
+        assert_eq!(case.notes[0].text, "called the customer");
+        assert!(service.resolve_case(1, "analyst", CaseStatus::Open).await.is_err());
+        let case = service.resolve_case(1, "analyst", CaseStatus::FalsePositive).await.unwrap();
+        assert_eq!(case.audit_trail.len(), 3);
+        assert!(service.resolve_case(1, "analyst", CaseStatus::ConfirmedFraud).await.is_err());
+        assert_eq!(service.rules().await.rules[1].weight, 0.95);
+        assert!(service.get_case(2).await.unwrap_err().downcast_ref::<NotFound>().is_some());
+    }
+
+    #[tokio::test]
+    async fn scoring_alone_opens_no_cases() {
+        let service = FraudDetectionService::new();
+        let jane = service.add_user(UserData::new("Jane", 40, vec![transaction(1500.0, "2023-01-01")])).await.unwrap();
+        assert!(service.detect_fraud(&jane).await.unwrap().is_fraud);
+        let declined = service.score_transaction(&jane, &transaction(2500.0, "2023-01-02")).await.unwrap();
+        assert_eq!(declined.decision, Decision::Decline);
+        assert!(service.list_cases(None).await.is_empty());
+
+        assert!(service.review_user(&jane).await.unwrap().is_fraud);
+        assert_eq!(service.list_cases(Some(CaseStatus::Open)).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn http_api_reports_missing_resources_as_not_found() {
+        let api = routes(FraudDetectionService::new());
+        let response = warp::test::request()
+            .method("POST")
+            .path("/users")
+            .json(&serde_json::json!({ "name": "Jane", "age": 40, "transactions": [{ "amount": 10.0, "date": "2023-01-01" }] }))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let created: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+
+        let path = format!("/users/{}/score", created["id"].as_str().unwrap());
+        let response = warp::test::request().path(&path).reply(&api).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let assessment: RiskAssessment = serde_json::from_slice(response.body()).unwrap();
+        assert!(!assessment.is_fraud);
+
+        let response = warp::test::request().path("/users/John/score").reply(&api).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = warp::test::request().path("/cases/7").reply(&api).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = warp::test::request().path("/cases?status=open").reply(&api).await;
+        assert_eq!(response.body().as_ref(), b"[]");
+    }
+
+    #[test]
+    fn identity_index_links_users_sharing_identifiers() {
+        let mut index = IdentityIndex::default();
+        let device = Identifier::new(IdentifierKind::Device, "phone-1");
+        let email = Identifier::new(IdentifierKind::Email, " Ann@Example.com ");
+        assert_eq!(email, Identifier::new(IdentifierKind::Email, "ann@example.com"));
+
+        index.index_user("ann", [device.clone(), email.clone()].into());
+        index.index_user("bob", [device.clone()].into());
+        index.index_user("cat", BTreeSet::new());
+        assert_eq!(index.find(&device), vec!["ann", "bob"]);
+        assert_eq!(index.links("ann", []), vec![UserLink { user_id: "bob".to_string(), shared: vec![device.clone()] }]);
+        // Identifiers of an incoming transaction link users before they are indexed
+        assert_eq!(index.links("cat", [email.clone()])[0].user_id, "ann");
+
+        let rule = RuleCondition::SharedIdentity { max_linked_users: 0, kinds: default_shared_kinds() };
+        assert!(rule.check_links(&index.links("bob", [])).is_some());
+        assert!(rule.check_links(&index.links("cat", [email])).is_none());
+
+        index.index_user("bob", BTreeSet::new());
+        assert_eq!(index.find(&device), vec!["ann"]);
+        assert!(index.links("ann", []).is_empty());
+    }
+
+    #[tokio::test]
+    async fn namesakes_are_stored_separately_and_linked_by_card() {
+        let service = FraudDetectionService::new();
+        let card = |amount| transaction(amount, "2023-01-01").with_card("card-1234");
+        let first = service.add_user(UserData::new("John Doe", 30, vec![card(10.0)])).await.unwrap();
+        let second = service.add_user(UserData::new("John Doe", 45, Vec::new())).await.unwrap();
+        assert_ne!(first, second);
+        assert!(service.linked_users(&first).await.unwrap().is_empty());
+
+        service.append_transaction(&second, card(20.0)).await.unwrap();
+        let links = service.linked_users(&first).await.unwrap();
+        assert_eq!(links, vec![UserLink { user_id: second.clone(), shared: vec![Identifier::new(IdentifierKind::Card, "card-1234")] }]);
+        assert_eq!(service.find_users(IdentifierKind::Card, "card-1234").await.len(), 2);
+        assert!(service.linked_users("missing").await.is_err());
+
+        // The index can be rebuilt from the store after a restart
+        *service.identities.write().await = IdentityIndex::default();
+        assert_eq!(service.rebuild_identity_index().await.unwrap(), 2);
+        assert_eq!(service.linked_users(&second).await.unwrap()[0].user_id, first);
+    }
+
+    #[tokio::test]
+    async fn sled_store_appends_without_rewriting_history() {
+        let path = temp_db("append");
+        {
+            let store = open_store(&path).await;
+            let user = UserData::new("Jane", 40, vec![transaction(10.0, "2023-01-01"), transaction(20.0, "2023-01-02")]);
+            let user_id = user.id.clone();
+            store.put_user(&user_id, user).await.unwrap();
+            store.append_transaction(&user_id, transaction(30.0, "2023-01-02T12:00:00Z")).await.unwrap();
+
+            let summary = store.get_user_summary(&user_id).await.unwrap().unwrap();
+            assert!(summary.transactions.is_empty());
+            assert_eq!(summary.aggregates.total_count(), 3);
+            assert_eq!(summary.aggregates.window(WindowSpan::Day).sum, 50.0);
+        }
+
+        // Reopening the database sees the same history and aggregates
+        let store = open_store(&path).await;
+        let users = store.all_users().await.unwrap();
+        assert_eq!(users.len(), 1);
+        let amounts: Vec<f64> = users[0].transactions.iter().map(|t| t.amount).collect();
+        assert_eq!(amounts, vec![10.0, 20.0, 30.0]);
+        assert_eq!(users[0].aggregates.total_count(), 3);
+
+        // Replacing the user drops the old history
+        let mut replacement = UserData::new("Jane", 41, vec![transaction(5.0, "2023-02-01")]);
+        replacement.id = users[0].id.clone();
+        store.put_user(&replacement.id.clone(), replacement).await.unwrap();
+        let user = store.get_user(&users[0].id).await.unwrap().unwrap();
+        assert_eq!(user.transactions.len(), 1);
+        assert_eq!(user.aggregates.total_count(), 1);
+        drop(store);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[tokio::test]
+    async fn cases_and_learned_weights_survive_restart() {
+        let path = temp_db("cases");
+        let user = UserData::new("Jane", 40, vec![transaction(1500.0, "2023-01-01")]);
+        let user_id = user.id.clone();
+        {
+            let service = FraudDetectionService::with_store(Arc::new(open_store(&path).await), RuleSet::default());
+            service.add_user(user).await.unwrap();
+            assert!(service.review_user(&user_id).await.unwrap().is_fraud);
+            let case = service.resolve_case(1, "analyst", CaseStatus::ConfirmedFraud).await.unwrap();
+            assert_eq!(case.status, CaseStatus::ConfirmedFraud);
+        }
+
+        let service = FraudDetectionService::with_store(Arc::new(open_store(&path).await), RuleSet::default());
+        assert_eq!(service.restore_review_state().await.unwrap(), 1);
+        let cases = service.list_cases(Some(CaseStatus::ConfirmedFraud)).await;
+        assert_eq!(cases.len(), 1);
+        assert_eq!(cases[0].user_id, user_id);
+        assert_eq!(service.rules().await.weights()["large_amount"], 1.05);
+        assert!(service.resolve_case(1, "analyst", CaseStatus::FalsePositive).await.is_err());
+
+        // New cases continue after the restored ids
+        service.review_user(&user_id).await.unwrap();
+        assert_eq!(service.list_cases(Some(CaseStatus::Open)).await[0].id, 2);
+        drop(service);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn json_reply_maps_errors_to_status_codes() {
+        let status = |result: Result<(), Error>| json_reply(result).unwrap().status();
+        assert_eq!(status(Ok(())), StatusCode::OK);
+        assert_eq!(status(Err(not_found("User"))), StatusCode::NOT_FOUND);
+        assert_eq!(status(Err(invalid_request("bad".to_string()))), StatusCode::BAD_REQUEST);
+        let io_error = std::io::Error::other("disk full");
+        assert_eq!(status(Err(Error::new(io_error))), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    /// A store whose writes always fail
+    #[derive(Debug)]
+    struct FailingStore;
+
+    #[async_trait]
+    impl UserStore for FailingStore {
+        async fn get_user(&self, _user_id: &str) -> Result<Option<Arc<UserData>>, Error> {
+            Ok(None)
+        }
+
+        async fn put_user(&self, _user_id: &str, _user: UserData) -> Result<(), Error> {
+            Err(Error::new(std::io::Error::other("disk full")))
+        }
+
+        async fn append_transaction(&self, _user_id: &str, _transaction: Transaction) -> Result<(), Error> {
+            Err(Error::new(std::io::Error::other("disk full")))
+        }
+
+        async fn all_users(&self) -> Result<Vec<Arc<UserData>>, Error> {
+            Ok(Vec::new())
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_write_leaves_no_index_entries() {
+        let service = FraudDetectionService::with_store(Arc::new(FailingStore), RuleSet::default());
+        let user = UserData::new("Jane", 40, Vec::new()).with_email("jane@example.com");
+        assert!(service.add_user(user).await.is_err());
+        assert!(service.find_users(IdentifierKind::Email, "jane@example.com").await.is_empty());
+    }
+
+    #[test]
+    fn stored_users_require_an_id() {
+        let new_user: NewUser = serde_json::from_str(r#"{"name": "Jane", "age": 40, "transactions": []}"#).unwrap();
+        assert!(!new_user.into_user().id.is_empty());
+
+        let record = StoredUser { user: UserData::new("Jane", 40, Vec::new()), aggregates: TransactionAggregates::default() };
+        let mut stored = serde_json::to_value(&record).unwrap();
+        assert!(serde_json::from_value::<StoredUser>(stored.clone()).is_ok());
+        stored["user"].as_object_mut().unwrap().remove("id");
+        assert!(serde_json::from_value::<StoredUser>(stored).is_err());
+    }
+}
@@ -1,82 +1,2485 @@
 use tokio::sync::mpsc;
-use tokio::time::{self, Duration};
-# 扩展功能模块
-use std::collections::VecDeque;
+use tokio::time::{self, Duration, Instant};
+use tokio::fs::File;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use async_trait::async_trait;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
 use std::sync::Arc;
 use tokio::sync::Mutex;
-use std::time::Instant;
-# 优化算法效率
+
+/// Rational time base in which a stream expresses its timestamps, e.g. 1/90000 for MPEG-TS
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeBase {
+    pub num: u32,
+    pub den: u32,
+}
+
+impl TimeBase {
+    /// The 90 kHz clock used by MPEG-TS
+    pub const MPEG_TS: TimeBase = TimeBase { num: 1, den: 90_000 };
+
+    /// Returns `None` for a zero denominator, which cannot express any timestamp
+    pub fn new(num: u32, den: u32) -> Option<Self> {
+        let time_base = TimeBase { num, den };
+        time_base.is_valid().then_some(time_base)
+    }
+
+    /// Whether timestamps in this time base can be converted; the fields are public,
+    /// so packets built by hand are checked again when they are queued
+    pub fn is_valid(&self) -> bool {
+        self.den != 0
+    }
+
+    /// Converts a timestamp in this time base to seconds
+    pub fn to_seconds(&self, timestamp: i64) -> f64 {
+        timestamp as f64 * self.num as f64 / self.den as f64
+    }
+
+    /// Converts a timestamp in this time base to 90 kHz ticks
+    pub fn to_90khz(&self, timestamp: i64) -> i64 {
+        (i128::from(timestamp) * 90_000 * i128::from(self.num) / i128::from(self.den)) as i64
+    }
+}
+
+/// Kind of elementary stream a packet belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamKind {
+    Audio,
+    Video,
+    Subtitle,
+    /// Timed metadata such as ID3 tags
+    Metadata,
+}
+
+/// Codec of an elementary stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Aac,
+    Mp3,
+    Ac3,
+    H264,
+    Hevc,
+    Mpeg2Video,
+    DvbSubtitle,
+    /// ID3 tags carried in PES packets
+    Id3,
+    /// Any other MPEG-TS stream type
+    Unknown(u8),
+}
+
+impl Codec {
+    /// Identifies an elementary stream from its PMT stream type and descriptors,
+    /// returning its codec, kind and language
+    fn classify(stream_type: u8, descriptors: &[u8]) -> Option<(Codec, StreamKind, Option<String>)> {
+        let mut language = None;
+        let mut subtitling = false;
+        let mut ac3 = false;
+        let mut position = 0;
+        while position + 2 <= descriptors.len() {
+            let tag = descriptors[position];
+            let length = usize::from(descriptors[position + 1]);
+            let Some(data) = descriptors.get(position + 2..position + 2 + length) else { break };
+            match tag {
+                // ISO 639 language and DVB subtitling descriptors both start with a language code
+                0x0A | 0x59 if data.len() >= 3 => {
+                    language = Some(String::from_utf8_lossy(&data[..3]).trim().to_string());
+                    subtitling |= tag == 0x59;
+                }
+                0x6A => ac3 = true,
+                _ => {}
+            }
+            position += 2 + length;
+        }
+
+        let (codec, kind) = match stream_type {
+            0x0F | 0x11 => (Codec::Aac, StreamKind::Audio),
+            0x03 | 0x04 => (Codec::Mp3, StreamKind::Audio),
+            0x81 => (Codec::Ac3, StreamKind::Audio),
+            0x1B => (Codec::H264, StreamKind::Video),
+            0x24 => (Codec::Hevc, StreamKind::Video),
+            0x01 | 0x02 => (Codec::Mpeg2Video, StreamKind::Video),
+            0x15 => (Codec::Id3, StreamKind::Metadata),
+            // Private data streams are identified by their descriptors
+            0x06 if subtitling => (Codec::DvbSubtitle, StreamKind::Subtitle),
+            0x06 if ac3 => (Codec::Ac3, StreamKind::Audio),
+            _ => return None,
+        };
+        Some((codec, kind, language))
+    }
+
+    /// MPEG-TS PMT stream type used when muxing this codec
+    fn stream_type(&self) -> u8 {
+        match self {
+            Codec::Aac => 0x0F,
+            Codec::Mp3 => 0x03,
+            Codec::Ac3 => 0x81,
+            Codec::H264 => 0x1B,
+            Codec::Hevc => 0x24,
+            Codec::Mpeg2Video => 0x02,
+            Codec::DvbSubtitle => 0x06,
+            Codec::Id3 => 0x15,
+            Codec::Unknown(stream_type) => *stream_type,
+        }
+    }
+}
+
+/// A media packet carrying presentation and decoding timestamps
+#[derive(Debug, Clone)]
+pub struct MediaPacket {
+    /// Identifier of the elementary stream, e.g. the MPEG-TS PID
+    pub stream_id: u32,
+    pub kind: StreamKind,
+    pub codec: Codec,
+    /// Presentation timestamp in `time_base` units
+    pub pts: i64,
+    /// Decoding timestamp in `time_base` units, when it differs from the PTS
+    pub dts: Option<i64>,
+    /// Presentation duration in `time_base` units, when known
+    pub duration: Option<i64>,
+    /// Whether the packet can be decoded without earlier packets
+    pub keyframe: bool,
+    pub time_base: TimeBase,
+    pub payload: Vec<u8>,
+}
+
+impl MediaPacket {
+    /// Presentation time in seconds
+    pub fn presentation_time(&self) -> f64 {
+        self.time_base.to_seconds(self.pts)
+    }
+
+    /// Decoding time in seconds, falling back to the presentation time
+    pub fn decode_time(&self) -> f64 {
+        self.time_base.to_seconds(self.dts.unwrap_or(self.pts))
+    }
+
+    /// Presentation time in seconds at which the packet ends, or starts when its duration is unknown
+    pub fn end_time(&self) -> f64 {
+        self.time_base.to_seconds(self.pts + self.duration.unwrap_or(0))
+    }
+}
+
+/// Description of an elementary stream
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamInfo {
+    pub stream_id: u32,
+    pub kind: StreamKind,
+    pub codec: Codec,
+    /// ISO 639-2 language code of audio and subtitle tracks, e.g. `eng`
+    pub language: Option<String>,
+}
+
+impl StreamInfo {
+    pub fn new(stream_id: u32, kind: StreamKind, codec: Codec) -> Self {
+        StreamInfo { stream_id, kind, codec, language: None }
+    }
+
+    pub fn with_language(mut self, language: &str) -> Self {
+        self.language = Some(language.to_string());
+        self
+    }
+}
+
+impl fmt::Display for StreamInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "stream {} ({:?}, {:?}", self.stream_id, self.kind, self.codec)?;
+        if let Some(language) = &self.language {
+            write!(f, ", {}", language)?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Error for operations on a stream that is not registered or has the wrong kind
+#[derive(Debug)]
+pub struct UnknownStream(pub u32);
+
+impl fmt::Display for UnknownStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no such stream: {}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownStream {}
+
+/// Size of an MPEG-TS packet
+const TS_PACKET_SIZE: usize = 188;
+const TS_SYNC_BYTE: u8 = 0x47;
+const PAT_PID: u16 = 0;
+
+/// A PES packet being assembled from TS packets
+#[derive(Debug, Default)]
+struct PesBuffer {
+    data: Vec<u8>,
+    keyframe: bool,
+}
+
+impl PesBuffer {
+    /// Whether the packet has reached its declared length; video PES packets may leave it unset
+    fn is_complete(&self) -> bool {
+        let Some(length) = self.data.get(4..6) else { return false };
+        let length = usize::from(u16::from_be_bytes([length[0], length[1]]));
+        length != 0 && self.data.len() >= 6 + length
+    }
+}
+
+/// Demuxes audio, video, subtitle and timed metadata packets from an MPEG-TS file.
+///
+/// PAT and PMT sections are expected to fit in a single TS packet, which holds
+/// for ordinary recordings. Audio and video packet durations are derived from the
+/// next packet of the same stream, so each is returned once its successor is seen.
+pub struct TsDemuxer {
+    reader: BufReader<File>,
+    pmt_pids: HashSet<u16>,
+    /// Elementary streams by PID
+    streams: HashMap<u16, StreamInfo>,
+    /// Streams found or changed since the last call to `take_new_streams`
+    announced: Vec<StreamInfo>,
+    pes: HashMap<u16, PesBuffer>,
+    /// Last packet of each stream, waiting for the next one to get its duration
+    pending: HashMap<u16, MediaPacket>,
+    ready: VecDeque<MediaPacket>,
+    /// Last timestamp seen, for unwrapping 33-bit timestamps onto a continuous timeline
+    timestamp_reference: Option<i64>,
+    finished: bool,
+}
+
+impl TsDemuxer {
+    /// Opens an MPEG-TS file
+    pub async fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path).await?;
+        Ok(TsDemuxer {
+            reader: BufReader::new(file),
+            pmt_pids: HashSet::new(),
+            streams: HashMap::new(),
+            announced: Vec::new(),
+            pes: HashMap::new(),
+            pending: HashMap::new(),
+            ready: VecDeque::new(),
+            timestamp_reference: None,
+            finished: false,
+        })
+    }
+
+    /// Streams declared in the PMT since the last call, including ones whose details changed
+    pub fn take_new_streams(&mut self) -> Vec<StreamInfo> {
+        std::mem::take(&mut self.announced)
+    }
+
+    /// Returns the next demuxed packet, or `None` at the end of the file
+    pub async fn next_packet(&mut self) -> io::Result<Option<MediaPacket>> {
+        loop {
+            if let Some(packet) = self.ready.pop_front() {
+                return Ok(Some(packet));
+            }
+            if self.finished {
+                return Ok(None);
+            }
+
+            let mut buffer = [0u8; TS_PACKET_SIZE];
+            match self.reader.read_exact(&mut buffer).await {
+                Ok(_) => self.handle_ts_packet(&buffer)?,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => self.finish(),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Flushes the partially assembled and pending packets at the end of the file
+    fn finish(&mut self) {
+        self.finished = true;
+        let pids: Vec<u16> = self.pes.keys().copied().collect();
+        for pid in pids {
+            self.flush_pes(pid);
+        }
+        let mut pending: Vec<MediaPacket> = self.pending.drain().map(|(_, packet)| packet).collect();
+        pending.sort_by_key(|packet| packet.dts.unwrap_or(packet.pts));
+        self.ready.extend(pending);
+    }
+
+    fn handle_ts_packet(&mut self, packet: &[u8]) -> io::Result<()> {
+        if packet[0] != TS_SYNC_BYTE {
+            return Err(invalid_data("lost MPEG-TS sync"));
+        }
+        let payload_unit_start = packet[1] & 0x40 != 0;
+        let pid = (u16::from(packet[1] & 0x1F) << 8) | u16::from(packet[2]);
+        let adaptation_field_control = (packet[3] >> 4) & 0x03;
+
+        let mut offset = 4;
+        let mut random_access = false;
+        if adaptation_field_control & 0x02 != 0 {
+            let length = packet[4] as usize;
+            if length > 0 {
+                random_access = packet[5] & 0x40 != 0;
+            }
+            offset += 1 + length;
+        }
+        if adaptation_field_control & 0x01 == 0 || offset >= TS_PACKET_SIZE {
+            return Ok(());
+        }
+        let payload = &packet[offset..];
+
+        if pid == PAT_PID || self.pmt_pids.contains(&pid) {
+            if payload_unit_start {
+                let pointer = payload[0] as usize;
+                if let Some(section) = payload.get(1 + pointer..) {
+                    self.handle_section(pid, section);
+                }
+            }
+            return Ok(());
+        }
+
+        if !self.streams.contains_key(&pid) {
+            return Ok(());
+        }
+        if payload_unit_start {
+            self.flush_pes(pid);
+            self.pes.insert(pid, PesBuffer { data: payload.to_vec(), keyframe: random_access });
+        } else if let Some(pes) = self.pes.get_mut(&pid) {
+            pes.data.extend_from_slice(payload);
+        }
+        // A PES packet with a declared length is complete without waiting for the next one
+        if self.pes.get(&pid).is_some_and(|pes| pes.is_complete()) {
+            self.flush_pes(pid);
+        }
+        Ok(())
+    }
+
+    /// Parses a PAT or PMT section
+    fn handle_section(&mut self, pid: u16, section: &[u8]) {
+        if section.len() < 3 {
+            return;
+        }
+        let table_id = section[0];
+        let section_length = ((usize::from(section[1]) & 0x0F) << 8) | usize::from(section[2]);
+        // The section ends with a 4-byte CRC
+        let end = (3 + section_length).saturating_sub(4).min(section.len());
+
+        if pid == PAT_PID && table_id == 0x00 {
+            let mut position = 8;
+            while position + 4 <= end {
+                let program_number = u16::from_be_bytes([section[position], section[position + 1]]);
+                let program_pid = u16::from_be_bytes([section[position + 2], section[position + 3]]) & 0x1FFF;
+                if program_number != 0 {
+                    self.pmt_pids.insert(program_pid);
+                }
+                position += 4;
+            }
+        } else if table_id == 0x02 && section.len() >= 12 {
+            let program_info_length = (usize::from(section[10]) & 0x0F) << 8 | usize::from(section[11]);
+            let mut position = 12 + program_info_length;
+            while position + 5 <= end {
+                let stream_type = section[position];
+                let elementary_pid = u16::from_be_bytes([section[position + 1], section[position + 2]]) & 0x1FFF;
+                let info_length = (usize::from(section[position + 3]) & 0x0F) << 8 | usize::from(section[position + 4]);
+                let descriptors = section.get(position + 5..(position + 5 + info_length).min(end)).unwrap_or_default();
+                if let Some((codec, kind, language)) = Codec::classify(stream_type, descriptors) {
+                    let info = StreamInfo { stream_id: u32::from(elementary_pid), kind, codec, language };
+                    if self.streams.get(&elementary_pid) != Some(&info) {
+                        self.announced.push(info.clone());
+                        self.streams.insert(elementary_pid, info);
+                    }
+                }
+                position += 5 + info_length;
+            }
+        }
+    }
+
+    /// Picks the value of a 33-bit timestamp closest to the previous one, so timestamps stay
+    /// ordered across the wraparound every 26.5 hours and when muxed from negative values
+    fn unwrap_timestamp(&mut self, timestamp: i64) -> i64 {
+        const WRAP: i64 = 1 << 33;
+        let unwrapped = match self.timestamp_reference {
+            Some(reference) => {
+                let base = reference - reference.rem_euclid(WRAP) + timestamp;
+                [base - WRAP, base, base + WRAP]
+                    .into_iter()
+                    .min_by_key(|candidate| (candidate - reference).abs())
+                    .unwrap_or(base)
+            }
+            None => timestamp,
+        };
+        self.timestamp_reference = Some(unwrapped);
+        unwrapped
+    }
+
+    /// Turns the assembled PES packet of a stream into a media packet
+    fn flush_pes(&mut self, pid: u16) {
+        let Some(pes) = self.pes.remove(&pid) else { return };
+        let Some(&StreamInfo { codec, kind, .. }) = self.streams.get(&pid) else { return };
+        let Some((pts, dts, payload)) = parse_pes(&pes.data) else { return };
+        let pts = self.unwrap_timestamp(pts);
+        let dts = dts.map(|dts| self.unwrap_timestamp(dts));
+
+        let packet = MediaPacket {
+            stream_id: u32::from(pid),
+            kind,
+            codec,
+            pts,
+            dts,
+            duration: None,
+            keyframe: pes.keyframe || kind == StreamKind::Audio,
+            time_base: TimeBase::MPEG_TS,
+            payload: payload.to_vec(),
+        };
+        if matches!(kind, StreamKind::Subtitle | StreamKind::Metadata) {
+            // Sparse streams would be held for too long waiting for a successor
+            self.ready.push_back(packet);
+            return;
+        }
+        if let Some(mut previous) = self.pending.insert(pid, packet) {
+            let next_pts = self.pending[&pid].pts;
+            if next_pts > previous.pts {
+                previous.duration = Some(next_pts - previous.pts);
+            }
+            self.ready.push_back(previous);
+        }
+    }
+}
+
+/// Parses a PES header, returning the PTS, optional DTS and the elementary stream payload
+fn parse_pes(data: &[u8]) -> Option<(i64, Option<i64>, &[u8])> {
+    if data.len() < 9 || data[0..3] != [0x00, 0x00, 0x01] {
+        return None;
+    }
+    let pts_dts_flags = data[7] >> 6;
+    let header_length = data[8] as usize;
+    let payload = data.get(9 + header_length..)?;
+    let pts = match pts_dts_flags {
+        0b10 | 0b11 => parse_timestamp(data.get(9..14)?),
+        _ => return None,
+    };
+    let dts = match pts_dts_flags {
+        0b11 => Some(parse_timestamp(data.get(14..19)?)),
+        _ => None,
+    };
+    Some((pts, dts, payload))
+}
+
+/// Decodes a 33-bit PES timestamp
+fn parse_timestamp(bytes: &[u8]) -> i64 {
+    (i64::from(bytes[0] >> 1) & 0x07) << 30
+        | i64::from(bytes[1]) << 22
+        | (i64::from(bytes[2]) >> 1) << 15
+        | i64::from(bytes[3]) << 7
+        | i64::from(bytes[4]) >> 1
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+const PMT_PID: u16 = 0x1000;
+const FIRST_ELEMENTARY_PID: u16 = 0x0100;
+/// Tables are repeated after this many PES packets so receivers can join mid-stream
+const PSI_INTERVAL: u32 = 40;
+/// TS packets per RTP datagram, keeping datagrams below a typical MTU
+const TS_PACKETS_PER_DATAGRAM: usize = 7;
+/// RTP payload type for MPEG-2 transport streams (RFC 3551)
+const RTP_PAYLOAD_TYPE_MP2T: u8 = 33;
+
+/// Elementary stream registered with a [`TsMuxer`]
+#[derive(Debug)]
+struct MuxStream {
+    stream_id: u32,
+    kind: StreamKind,
+    language: Option<String>,
+    pid: u16,
+    stream_type: u8,
+    pes_stream_id: u8,
+    continuity: u8,
+}
+
+/// Re-muxes media packets into a single-program MPEG-TS.
+///
+/// Streams are assigned PIDs in order of appearance. The PAT and PMT are
+/// written up front, whenever a new stream appears and every [`PSI_INTERVAL`]
+/// packets. The PCR is carried on the first video stream, or the first stream
+/// when there is no video.
+#[derive(Debug, Default)]
+struct TsMuxer {
+    streams: Vec<MuxStream>,
+    /// Languages announced for streams, written to the PMT
+    languages: HashMap<u32, String>,
+    pcr_pid: Option<u16>,
+    pat_continuity: u8,
+    pmt_continuity: u8,
+    version: u8,
+    since_tables: u32,
+}
+
+impl TsMuxer {
+    /// Records stream details for the PMT, rewriting the tables if the stream is already muxed
+    fn describe(&mut self, info: &StreamInfo) {
+        match &info.language {
+            Some(language) => self.languages.insert(info.stream_id, language.clone()),
+            None => self.languages.remove(&info.stream_id),
+        };
+        if let Some(stream) = self.streams.iter_mut().find(|stream| stream.stream_id == info.stream_id) {
+            if stream.language != info.language {
+                stream.language = info.language.clone();
+                self.version = (self.version + 1) % 32;
+                self.since_tables = PSI_INTERVAL;
+            }
+        }
+    }
+
+    /// Appends the TS packets for one media packet to `out`
+    fn mux(&mut self, packet: &MediaPacket, out: &mut Vec<u8>) {
+        let index = match self.streams.iter().position(|stream| stream.stream_id == packet.stream_id) {
+            Some(index) => index,
+            None => {
+                self.add_stream(packet);
+                self.since_tables = PSI_INTERVAL;
+                self.streams.len() - 1
+            }
+        };
+        if self.since_tables >= PSI_INTERVAL {
+            self.write_tables(out);
+            self.since_tables = 0;
+        }
+        self.since_tables += 1;
+
+        let pts = packet.time_base.to_90khz(packet.pts);
+        let dts = packet.dts.map(|dts| packet.time_base.to_90khz(dts));
+        let pid = self.streams[index].pid;
+        let pcr = (self.pcr_pid == Some(pid)).then(|| dts.unwrap_or(pts).max(0) as u64);
+        let pes = pes_packet(self.streams[index].pes_stream_id, pts, dts, &packet.payload);
+
+        let stream = &mut self.streams[index];
+        packetize(pid, &mut stream.continuity, packet.keyframe, pcr, &pes, out);
+    }
+
+    fn add_stream(&mut self, packet: &MediaPacket) {
+        let pid = FIRST_ELEMENTARY_PID + self.streams.len() as u16;
+        let same_kind = self.streams.iter().filter(|stream| stream.kind == packet.kind);
+        let pes_stream_id = match (packet.kind, packet.codec) {
+            // AC-3 is carried in private stream 1
+            (_, Codec::Ac3) => 0xBD,
+            (StreamKind::Audio, _) => 0xC0 + (same_kind.count() as u8 & 0x1F),
+            (StreamKind::Video, _) => 0xE0 + (same_kind.count() as u8 & 0x0F),
+            // Subtitles and timed metadata are carried in private stream 1 as well
+            (StreamKind::Subtitle | StreamKind::Metadata, _) => 0xBD,
+        };
+        self.streams.push(MuxStream {
+            stream_id: packet.stream_id,
+            kind: packet.kind,
+            language: self.languages.get(&packet.stream_id).cloned(),
+            pid,
+            stream_type: packet.codec.stream_type(),
+            pes_stream_id,
+            continuity: 0,
+        });
+        // Move the PCR to the first video stream once one appears
+        let pcr_on_video = self.streams.iter().any(|stream| Some(stream.pid) == self.pcr_pid && stream.kind == StreamKind::Video);
+        if self.pcr_pid.is_none() || (packet.kind == StreamKind::Video && !pcr_on_video) {
+            self.pcr_pid = Some(pid);
+        }
+        self.version = (self.version + 1) % 32;
+    }
+
+    fn write_tables(&mut self, out: &mut Vec<u8>) {
+        let mut pat = Vec::new();
+        pat.extend_from_slice(&1u16.to_be_bytes());
+        pat.extend_from_slice(&(0xE000 | PMT_PID).to_be_bytes());
+        let section = psi_section(0x00, 1, self.version, &pat);
+        packetize(PAT_PID, &mut self.pat_continuity, false, None, &section, out);
+
+        let mut pmt = Vec::new();
+        pmt.extend_from_slice(&(0xE000 | self.pcr_pid.unwrap_or(0x1FFF)).to_be_bytes());
+        pmt.extend_from_slice(&0xF000u16.to_be_bytes());
+        for stream in &self.streams {
+            let mut descriptors = Vec::new();
+            match (stream.kind, &stream.language) {
+                (StreamKind::Subtitle, language) => {
+                    // DVB subtitling descriptor: language, normal subtitles, composition and ancillary pages
+                    descriptors.extend_from_slice(&[0x59, 8]);
+                    descriptors.extend_from_slice(&language_code(language.as_deref().unwrap_or("und")));
+                    descriptors.extend_from_slice(&[0x10, 0x00, 0x01, 0x00, 0x01]);
+                }
+                (_, Some(language)) => {
+                    descriptors.extend_from_slice(&[0x0A, 4]);
+                    descriptors.extend_from_slice(&language_code(language));
+                    descriptors.push(0x00);
+                }
+                (_, None) => {}
+            }
+            pmt.push(stream.stream_type);
+            pmt.extend_from_slice(&(0xE000 | stream.pid).to_be_bytes());
+            pmt.extend_from_slice(&(0xF000 | descriptors.len() as u16).to_be_bytes());
+            pmt.extend_from_slice(&descriptors);
+        }
+        let section = psi_section(0x02, 1, self.version, &pmt);
+        packetize(PMT_PID, &mut self.pmt_continuity, false, None, &section, out);
+    }
+}
+
+/// Three-byte ISO 639 code for a PMT descriptor, padded with spaces
+fn language_code(language: &str) -> [u8; 3] {
+    let mut code = [b' '; 3];
+    for (slot, byte) in code.iter_mut().zip(language.bytes()) {
+        *slot = byte;
+    }
+    code
+}
+
+/// Builds a PSI section with its pointer field and CRC
+fn psi_section(table_id: u8, id: u16, version: u8, body: &[u8]) -> Vec<u8> {
+    let section_length = 5 + body.len() + 4;
+    let mut section = vec![
+        table_id,
+        0xB0 | (section_length >> 8) as u8,
+        section_length as u8,
+    ];
+    section.extend_from_slice(&id.to_be_bytes());
+    section.extend_from_slice(&[0xC1 | (version << 1), 0x00, 0x00]);
+    section.extend_from_slice(body);
+    let crc = crc32_mpeg2(&section);
+    section.extend_from_slice(&crc.to_be_bytes());
+    // Pointer field: the section starts right after it
+    section.insert(0, 0x00);
+    // Pad the rest of the TS packet payload with stuffing bytes
+    let padded = section.len().div_ceil(TS_PACKET_SIZE - 4) * (TS_PACKET_SIZE - 4);
+    section.resize(padded, 0xFF);
+    section
+}
+
+/// CRC-32/MPEG-2 as used by PSI sections
+fn crc32_mpeg2(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= u32::from(byte) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04C1_1DB7 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Builds a PES packet with PTS and, when it differs, DTS
+fn pes_packet(stream_id: u8, pts: i64, dts: Option<i64>, payload: &[u8]) -> Vec<u8> {
+    let dts = dts.filter(|&dts| dts != pts);
+    let header_length = if dts.is_some() { 10 } else { 5 };
+    let length = 3 + header_length + payload.len();
+    // Video PES packets may be unbounded when they do not fit the 16-bit length field
+    let length = if length > usize::from(u16::MAX) { 0 } else { length as u16 };
+
+    let mut pes = vec![0x00, 0x00, 0x01, stream_id];
+    pes.extend_from_slice(&length.to_be_bytes());
+    pes.push(0x84);
+    match dts {
+        Some(dts) => {
+            pes.extend_from_slice(&[0xC0, header_length as u8]);
+            pes.extend_from_slice(&encode_timestamp(0b0011, pts));
+            pes.extend_from_slice(&encode_timestamp(0b0001, dts));
+        }
+        None => {
+            pes.extend_from_slice(&[0x80, header_length as u8]);
+            pes.extend_from_slice(&encode_timestamp(0b0010, pts));
+        }
+    }
+    pes.extend_from_slice(payload);
+    pes
+}
+
+/// Encodes a 33-bit PES timestamp with its 4-bit prefix
+fn encode_timestamp(prefix: u8, timestamp: i64) -> [u8; 5] {
+    let t = timestamp as u64 & 0x1_FFFF_FFFF;
+    [
+        (prefix << 4) | ((t >> 29) as u8 & 0x0E) | 0x01,
+        (t >> 22) as u8,
+        ((t >> 14) as u8 & 0xFE) | 0x01,
+        (t >> 7) as u8,
+        ((t << 1) as u8 & 0xFE) | 0x01,
+    ]
+}
+
+/// Splits a PES packet or PSI section into TS packets. The first packet carries
+/// the random access flag and PCR; the last is padded with adaptation field stuffing.
+fn packetize(pid: u16, continuity: &mut u8, random_access: bool, pcr: Option<u64>, mut data: &[u8], out: &mut Vec<u8>) {
+    let mut first = true;
+    while !data.is_empty() || first {
+        let mut adaptation = Vec::new();
+        if first && (random_access || pcr.is_some()) {
+            let mut flags = 0u8;
+            if random_access {
+                flags |= 0x40;
+            }
+            if pcr.is_some() {
+                flags |= 0x10;
+            }
+            adaptation.push(flags);
+            if let Some(base) = pcr {
+                let base = base & 0x1_FFFF_FFFF;
+                adaptation.extend_from_slice(&[
+                    (base >> 25) as u8,
+                    (base >> 17) as u8,
+                    (base >> 9) as u8,
+                    (base >> 1) as u8,
+                    ((base as u8 & 0x01) << 7) | 0x7E,
+                    0x00,
+                ]);
+            }
+        }
+
+        let header_room = if adaptation.is_empty() { 0 } else { 1 + adaptation.len() };
+        let payload_length = data.len().min(TS_PACKET_SIZE - 4 - header_room);
+        let adaptation_size = TS_PACKET_SIZE - 4 - payload_length;
+        if adaptation_size > 0 && adaptation.is_empty() && adaptation_size > 1 {
+            adaptation.push(0x00);
+        }
+        if adaptation_size > 1 {
+            adaptation.resize(adaptation_size - 1, 0xFF);
+        }
+
+        let adaptation_control = if adaptation_size > 0 { 0x30 } else { 0x10 };
+        out.extend_from_slice(&[
+            TS_SYNC_BYTE,
+            if first { 0x40 } else { 0x00 } | (pid >> 8) as u8 & 0x1F,
+            pid as u8,
+            adaptation_control | *continuity,
+        ]);
+        if adaptation_size > 0 {
+            out.push(adaptation.len() as u8);
+            out.extend_from_slice(&adaptation);
+        }
+        out.extend_from_slice(&data[..payload_length]);
+
+        *continuity = (*continuity + 1) & 0x0F;
+        data = &data[payload_length..];
+        first = false;
+    }
+}
+
+/// Advice to the output on how to absorb clock drift and buffer underruns
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlaybackHint {
+    /// Play audio at `ratio` times its nominal sample rate from now on, e.g. by resampling
+    ResampleAudio { stream_id: u32, ratio: f64 },
+    /// The next video frame has not arrived in time; keep showing the frame with this PTS
+    RepeatFrame { stream_id: u32, pts: i64 },
+}
+
+/// Destination for synchronized packets, e.g. a player, recorder or network receiver
+#[async_trait]
+pub trait OutputSink: Send {
+    /// Writes a packet at its scheduled presentation time
+    async fn write_packet(&mut self, packet: &MediaPacket) -> io::Result<()>;
+
+    /// Announces a stream before its first packet, or updated details of a known stream
+    async fn add_stream(&mut self, _info: &StreamInfo) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Applies a playback hint; outputs that cannot resample or repeat frames ignore it
+    async fn apply_hint(&mut self, _hint: &PlaybackHint) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Flushes buffered output once the synchronizer has drained
+    async fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Sink that logs every packet to stdout
+pub struct StdoutSink;
+
+#[async_trait]
+impl OutputSink for StdoutSink {
+    async fn write_packet(&mut self, packet: &MediaPacket) -> io::Result<()> {
+        println!(
+            "Synchronized {:?} packet (stream {}, {:?}{}) at {:.3}s: {} bytes",
+            packet.kind,
+            packet.stream_id,
+            packet.codec,
+            if packet.keyframe { ", keyframe" } else { "" },
+            packet.presentation_time(),
+            packet.payload.len()
+        );
+        Ok(())
+    }
+
+    async fn add_stream(&mut self, info: &StreamInfo) -> io::Result<()> {
+        println!("New {}", info);
+        Ok(())
+    }
+
+    async fn apply_hint(&mut self, hint: &PlaybackHint) -> io::Result<()> {
+        println!("Playback hint: {:?}", hint);
+        Ok(())
+    }
+}
+
+/// Sink that re-muxes packets into an MPEG-TS file
+pub struct TsFileSink {
+    writer: BufWriter<File>,
+    muxer: TsMuxer,
+    buffer: Vec<u8>,
+}
+
+impl TsFileSink {
+    /// Creates or truncates the output file
+    pub async fn create(path: &Path) -> io::Result<Self> {
+        let file = File::create(path).await?;
+        Ok(TsFileSink {
+            writer: BufWriter::new(file),
+            muxer: TsMuxer::default(),
+            buffer: Vec::new(),
+        })
+    }
+}
+
+#[async_trait]
+impl OutputSink for TsFileSink {
+    async fn write_packet(&mut self, packet: &MediaPacket) -> io::Result<()> {
+        self.buffer.clear();
+        self.muxer.mux(packet, &mut self.buffer);
+        self.writer.write_all(&self.buffer).await
+    }
+
+    async fn add_stream(&mut self, info: &StreamInfo) -> io::Result<()> {
+        self.muxer.describe(info);
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> io::Result<()> {
+        self.writer.flush().await
+    }
+}
+
+/// Sink that forwards packets as MPEG-TS over RTP (RFC 2250) to a UDP receiver,
+/// e.g. `ffplay rtp://127.0.0.1:5004`
+pub struct RtpSink {
+    socket: UdpSocket,
+    muxer: TsMuxer,
+    buffer: Vec<u8>,
+    sequence: u16,
+    ssrc: u32,
+}
+
+impl RtpSink {
+    /// Binds an ephemeral local port and sends to `receiver`
+    pub async fn connect(receiver: SocketAddr) -> io::Result<Self> {
+        let local: SocketAddr = if receiver.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(receiver).await?;
+        Ok(RtpSink {
+            socket,
+            muxer: TsMuxer::default(),
+            buffer: Vec::new(),
+            sequence: 0,
+            ssrc: std::process::id(),
+        })
+    }
+}
+
+#[async_trait]
+impl OutputSink for RtpSink {
+    async fn write_packet(&mut self, packet: &MediaPacket) -> io::Result<()> {
+        self.buffer.clear();
+        self.muxer.mux(packet, &mut self.buffer);
+        // RTP timestamps for MP2T use the 90 kHz clock
+        let timestamp = packet.time_base.to_90khz(packet.dts.unwrap_or(packet.pts)) as u32;
+
+        for chunk in self.buffer.chunks(TS_PACKETS_PER_DATAGRAM * TS_PACKET_SIZE) {
+            let mut datagram = Vec::with_capacity(12 + chunk.len());
+            datagram.extend_from_slice(&[0x80, RTP_PAYLOAD_TYPE_MP2T]);
+            datagram.extend_from_slice(&self.sequence.to_be_bytes());
+            datagram.extend_from_slice(&timestamp.to_be_bytes());
+            datagram.extend_from_slice(&self.ssrc.to_be_bytes());
+            datagram.extend_from_slice(chunk);
+            self.socket.send(&datagram).await?;
+            self.sequence = self.sequence.wrapping_add(1);
+        }
+        Ok(())
+    }
+
+    async fn add_stream(&mut self, info: &StreamInfo) -> io::Result<()> {
+        self.muxer.describe(info);
+        Ok(())
+    }
+}
+
+/// A packet as seen by a [`RecordingSink`]
+#[derive(Debug, Clone)]
+pub struct EmittedPacket {
+    pub stream_id: u32,
+    pub kind: StreamKind,
+    /// Presentation time in seconds
+    pub presentation_time: f64,
+    /// When the synchronizer handed the packet to the sink
+    pub emitted_at: Instant,
+}
+
+/// Sink that records emission order and timing, for checking the scheduler.
+/// Clones share the same record, so keep one to inspect after handing a clone to the synchronizer.
+#[derive(Debug, Clone, Default)]
+pub struct RecordingSink {
+    emitted: Arc<std::sync::Mutex<Vec<EmittedPacket>>>,
+    hints: Arc<std::sync::Mutex<Vec<PlaybackHint>>>,
+}
+
+impl RecordingSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Packets emitted so far, in emission order
+    pub fn emitted(&self) -> Vec<EmittedPacket> {
+        self.emitted.lock().unwrap().clone()
+    }
+
+    /// Playback hints received so far
+    pub fn hints(&self) -> Vec<PlaybackHint> {
+        self.hints.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl OutputSink for RecordingSink {
+    async fn write_packet(&mut self, packet: &MediaPacket) -> io::Result<()> {
+        self.emitted.lock().unwrap().push(EmittedPacket {
+            stream_id: packet.stream_id,
+            kind: packet.kind,
+            presentation_time: packet.presentation_time(),
+            emitted_at: Instant::now(),
+        });
+        Ok(())
+    }
+
+    async fn apply_hint(&mut self, hint: &PlaybackHint) -> io::Result<()> {
+        self.hints.lock().unwrap().push(hint.clone());
+        Ok(())
+    }
+}
+
+/// Clock that drives output scheduling. While the configured master stream has not received
+/// any packets, the clock follows the primary video stream, or the wall clock without one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MasterClock {
+    /// Follow the active audio track; the other streams are scheduled against it (default)
+    Audio,
+    /// Follow the lowest-numbered video stream; the other streams are scheduled against it
+    Video,
+    /// Follow the wall clock from the first packet
+    External,
+}
+
+/// Jitter buffer parameters
+#[derive(Debug, Clone, Copy)]
+pub struct JitterConfig {
+    /// Playout delay used while the measured jitter does not call for more
+    pub target_latency: Duration,
+    /// Upper bound for the adaptive playout delay
+    pub max_latency: Duration,
+    /// The adaptive delay covers this many multiples of the measured interarrival jitter
+    pub jitter_multiplier: f64,
+    /// Largest relative clock-rate change used to slew the playout delay, e.g. 0.005 for 0.5 %.
+    /// Clamped to [0, `MAX_RATE_ADJUST`] so the media clock always runs forward.
+    pub max_rate_adjust: f64,
+}
+
+impl Default for JitterConfig {
+    fn default() -> Self {
+        JitterConfig {
+            target_latency: Duration::from_millis(60),
+            max_latency: Duration::from_millis(500),
+            jitter_multiplier: 4.0,
+            max_rate_adjust: 0.005,
+        }
+    }
+}
+
+/// Scheduling parameters for the synchronizer
+#[derive(Debug, Clone, Copy)]
+pub struct SyncConfig {
+    pub master: MasterClock,
+    /// Packets due no further ahead than this are emitted immediately; earlier ones are held
+    pub early_tolerance: Duration,
+    /// Non-master packets later than this are dropped instead of emitted; a master packet this late triggers a rebuffer
+    pub late_threshold: Duration,
+    /// A/V drift beyond which output is counted as out of lip-sync tolerance
+    pub max_drift: Duration,
+    pub jitter: JitterConfig,
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        SyncConfig {
+            master: MasterClock::Audio,
+            early_tolerance: Duration::from_millis(5),
+            late_threshold: Duration::from_millis(100),
+            max_drift: Duration::from_millis(45),
+            jitter: JitterConfig::default(),
+        }
+    }
+}
+
+/// Jitter buffer metrics of one stream
+#[derive(Debug, Clone, Default)]
+pub struct StreamMetrics {
+    /// Media time currently buffered, in seconds
+    pub buffer_depth: f64,
+    /// Smoothed interarrival jitter in seconds
+    pub jitter: f64,
+    /// Playout delay this stream's jitter calls for, in seconds
+    pub target_latency: f64,
+    pub late_drops: u64,
+    /// Times the stream ran dry before its next packet arrived
+    pub underruns: u64,
+    pub emitted: u64,
+}
+
+/// Synchronization statistics
+#[derive(Debug, Clone, Default)]
+pub struct SyncStats {
+    pub dropped_late: u64,
+    /// Most recent A/V drift in seconds; positive when the slave stream is ahead of the master
+    pub current_drift: Option<f64>,
+    /// Largest absolute drift observed, in seconds
+    pub max_drift: f64,
+    /// Slave packets emitted with a drift beyond the configured tolerance
+    pub out_of_tolerance: u64,
+    /// Packets the output sink failed to write
+    pub sink_errors: u64,
+    /// Packets rejected because their time base has a zero denominator
+    pub invalid_time_base: u64,
+    /// Metrics per stream id
+    pub streams: BTreeMap<u32, StreamMetrics>,
+    pub active_audio: Option<u32>,
+    /// Playout delay currently aimed for, in seconds
+    pub playout_delay: f64,
+    /// Rate of the media clock relative to the local clock
+    pub clock_rate: f64,
+    /// Estimated rate difference between the sender's clocks of the primary video stream and
+    /// the active audio track in parts per million; positive when the video clock runs fast
+    pub clock_drift_ppm: Option<f64>,
+}
+
+/// Samples of arrival history kept per stream
+const TRANSIT_HISTORY: usize = 512;
+/// Media time over which the lowest transit is taken as the network base delay, in seconds
+const BASE_TRANSIT_WINDOW: f64 = 2.0;
+/// Shortest arrival history, in seconds of media time, from which clock skew is estimated
+const MIN_SKEW_SPAN: f64 = 1.0;
+/// Buckets whose minimum transits form the lower envelope used for skew estimation
+const SKEW_BUCKETS: usize = 8;
+/// Time over which a playout delay error is slewed out, in seconds
+const SLEW_TIME: f64 = 1.0;
+/// Change in resampling ratio that warrants a new hint
+const RATE_HINT_STEP: f64 = 20e-6;
+/// Upper bound for `JitterConfig::max_rate_adjust`; at 1 or more the clock could stop or run backwards
+const MAX_RATE_ADJUST: f64 = 0.5;
+/// How often the worker re-evaluates the clock when no packet is due sooner
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Adaptive jitter buffer of one stream.
+///
+/// Arrival times are compared with media times: their difference, the transit,
+/// varies with network jitter (estimated as in RFC 3550) and drifts with the
+/// sender's clock skew, which is estimated from the lower envelope of transits.
+#[derive(Debug, Default)]
+struct JitterBuffer {
+    packets: VecDeque<MediaPacket>,
+    /// Smoothed interarrival jitter in seconds
+    jitter: f64,
+    last_transit: Option<f64>,
+    /// Decode time and transit of recent arrivals
+    history: VecDeque<(f64, f64)>,
+    /// Arrivals before this decode time are ignored for the base transit, e.g. after a stall
+    base_since: f64,
+    /// Stream id, PTS and media end time of the last emitted packet
+    last_emitted: Option<(u32, i64, f64)>,
+    /// Whether the current gap has already been counted as an underrun
+    starved: bool,
+    late_drops: u64,
+    underruns: u64,
+    emitted: u64,
+}
+
+impl JitterBuffer {
+    /// Queues a packet in presentation order and updates the jitter estimate.
+    /// `arrival` is in seconds on the synchronizer's local timeline.
+    fn push(&mut self, packet: MediaPacket, arrival: f64) {
+        let transit = arrival - packet.decode_time();
+        if let Some(last) = self.last_transit {
+            self.jitter += ((transit - last).abs() - self.jitter) / 16.0;
+        }
+        self.last_transit = Some(transit);
+        self.history.push_back((packet.decode_time(), transit));
+        if self.history.len() > TRANSIT_HISTORY {
+            self.history.pop_front();
+        }
+
+        let time = packet.presentation_time();
+        let position = self.packets.partition_point(|queued| queued.presentation_time() <= time);
+        self.packets.insert(position, packet);
+    }
+
+    /// Lowest recent transit: the network delay without queuing jitter
+    fn base_transit(&self) -> Option<f64> {
+        let newest = self.history.back()?.0;
+        self.history
+            .iter()
+            .filter(|(time, _)| *time >= self.base_since && newest - time <= BASE_TRANSIT_WINDOW)
+            .map(|(_, transit)| *transit)
+            .reduce(f64::min)
+    }
+
+    /// Rate at which transit changes with media time, i.e. how much slower the sender's
+    /// clock for this stream runs than the local clock
+    fn skew(&self) -> Option<f64> {
+        let first = self.history.front()?.0;
+        let span = self.history.back()?.0 - first;
+        if span < MIN_SKEW_SPAN {
+            return None;
+        }
+        let mut buckets = [(0.0, f64::INFINITY); SKEW_BUCKETS];
+        for &(time, transit) in &self.history {
+            let index = (((time - first) / span * SKEW_BUCKETS as f64) as usize).min(SKEW_BUCKETS - 1);
+            if transit < buckets[index].1 {
+                buckets[index] = (time, transit);
+            }
+        }
+        let envelope: Vec<(f64, f64)> = buckets.into_iter().filter(|(_, transit)| transit.is_finite()).collect();
+        least_squares_slope(&envelope)
+    }
+
+    /// Playout delay this stream's jitter calls for, in seconds
+    fn target_latency(&self, config: &JitterConfig) -> f64 {
+        (self.jitter * config.jitter_multiplier)
+            .max(config.target_latency.as_secs_f64())
+            .min(config.max_latency.as_secs_f64())
+    }
+
+    /// Media time currently buffered, in seconds
+    fn depth(&self) -> f64 {
+        match (self.packets.front(), self.packets.back()) {
+            (Some(front), Some(back)) => (back.end_time() - front.presentation_time()).max(0.0),
+            _ => 0.0,
+        }
+    }
+
+    fn mark_emitted(&mut self, packet: &MediaPacket) {
+        let time = packet.presentation_time();
+        // Without a known duration, assume the packet lasts as long as the gap to its predecessor
+        let end = match (packet.duration, self.last_emitted) {
+            (Some(_), _) => packet.end_time(),
+            (None, Some((_, _, previous_end))) if previous_end < time => time + (time - previous_end),
+            (None, _) => time,
+        };
+        self.last_emitted = Some((packet.stream_id, packet.pts, end));
+        self.starved = false;
+        self.emitted += 1;
+    }
+
+    fn metrics(&self, config: &JitterConfig) -> StreamMetrics {
+        StreamMetrics {
+            buffer_depth: self.depth(),
+            jitter: self.jitter,
+            target_latency: self.target_latency(config),
+            late_drops: self.late_drops,
+            underruns: self.underruns,
+            emitted: self.emitted,
+        }
+    }
+}
+
+/// Slope of the least-squares line through `points`
+fn least_squares_slope(points: &[(f64, f64)]) -> Option<f64> {
+    if points.len() < 2 {
+        return None;
+    }
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let covariance: f64 = points.iter().map(|(x, y)| (x - mean_x) * (y - mean_y)).sum();
+    let variance: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+    (variance > 0.0).then(|| covariance / variance)
+}
+
+/// Maps media time to wall-clock time
+#[derive(Debug, Clone, Copy)]
+struct MediaClock {
+    /// Media time in seconds presented at the anchor instant
+    anchor: Option<(f64, Instant)>,
+    /// Media seconds that pass per local second
+    rate: f64,
+}
+
+impl Default for MediaClock {
+    fn default() -> Self {
+        MediaClock { anchor: None, rate: 1.0 }
+    }
+}
+
+impl MediaClock {
+    /// Current media time, once the clock has started
+    fn now(&self, at: Instant) -> Option<f64> {
+        self.anchor.map(|(media, instant)| {
+            if at >= instant {
+                media + (at - instant).as_secs_f64() * self.rate
+            } else {
+                media - (instant - at).as_secs_f64() * self.rate
+            }
+        })
+    }
+
+    fn set(&mut self, media: f64, at: Instant) {
+        self.anchor = Some((media, at));
+    }
+
+    /// Changes the rate without moving the current position
+    fn set_rate(&mut self, rate: f64, at: Instant) {
+        if let Some(media) = self.now(at) {
+            self.anchor = Some((media, at));
+        }
+        self.rate = rate;
+    }
+}
+
+/// Output of one scheduling pass
+#[derive(Debug, Default)]
+struct PollResult {
+    /// Streams to announce to the sink before the packets
+    streams: Vec<StreamInfo>,
+    packets: Vec<MediaPacket>,
+    hints: Vec<PlaybackHint>,
+    /// When the next held packet is due
+    next_due: Option<Instant>,
+}
+
+/// A registered stream with its jitter buffer
+#[derive(Debug)]
+struct StreamState {
+    info: StreamInfo,
+    buffer: JitterBuffer,
+    /// Resampling ratio last announced for this audio track
+    hinted_ratio: f64,
+}
+
+impl StreamState {
+    fn new(info: StreamInfo) -> Self {
+        StreamState { info, buffer: JitterBuffer::default(), hinted_ratio: 1.0 }
+    }
+
+    /// Whether the stream's timing feeds the playout delay and lip-sync measurements.
+    /// Subtitles and metadata are sparse and often muxed well ahead of time.
+    fn is_continuous(&self) -> bool {
+        matches!(self.info.kind, StreamKind::Audio | StreamKind::Video)
+    }
+}
+
+/// Streams, clock and statistics shared between the synchronizer handle and its worker task
+#[derive(Debug)]
+struct SyncState {
+    config: SyncConfig,
+    streams: BTreeMap<u32, StreamState>,
+    /// The audio track that is output; the others are consumed in step so switching is seamless
+    active_audio: Option<u32>,
+    clock: MediaClock,
+    /// Origin of the local timeline arrival times are measured on
+    epoch: Instant,
+    /// Streams registered or changed since the worker last informed the sink
+    announced: Vec<StreamInfo>,
+    stats: SyncStats,
+}
+
+impl SyncState {
+    fn new(mut config: SyncConfig) -> Self {
+        let max_adjust = config.jitter.max_rate_adjust;
+        config.jitter.max_rate_adjust = if max_adjust.is_nan() { 0.0 } else { max_adjust.clamp(0.0, MAX_RATE_ADJUST) };
+        SyncState {
+            config,
+            streams: BTreeMap::new(),
+            active_audio: None,
+            clock: MediaClock::default(),
+            epoch: Instant::now(),
+            announced: Vec::new(),
+            stats: SyncStats { clock_rate: 1.0, ..SyncStats::default() },
+        }
+    }
+
+    /// Adds a stream or updates its details; the first audio track becomes active
+    fn register(&mut self, info: StreamInfo) {
+        if info.kind == StreamKind::Audio && self.active_audio.is_none() {
+            self.active_audio = Some(info.stream_id);
+        }
+        match self.streams.get_mut(&info.stream_id) {
+            Some(stream) if stream.info == info => return,
+            Some(stream) => stream.info = info.clone(),
+            None => {
+                self.streams.insert(info.stream_id, StreamState::new(info.clone()));
+            }
+        }
+        self.announced.push(info);
+    }
+
+    /// Removes a stream and its queued packets, activating another audio track if needed
+    fn remove(&mut self, stream_id: u32) -> Option<StreamInfo> {
+        let stream = self.streams.remove(&stream_id)?;
+        if self.active_audio == Some(stream_id) {
+            self.active_audio = self.streams.values().find(|stream| stream.info.kind == StreamKind::Audio).map(|stream| stream.info.stream_id);
+        }
+        Some(stream.info)
+    }
+
+    /// Switches the output to another audio track without touching the clock
+    fn set_active_audio(&mut self, stream_id: u32) -> Result<(), UnknownStream> {
+        match self.streams.get_mut(&stream_id) {
+            Some(stream) if stream.info.kind == StreamKind::Audio => {
+                stream.hinted_ratio = 1.0;
+                stream.buffer.last_emitted = None;
+            }
+            _ => return Err(UnknownStream(stream_id)),
+        }
+        if let Some(previous) = self.active_audio.replace(stream_id).and_then(|id| self.streams.get_mut(&id)) {
+            previous.buffer.last_emitted = None;
+            previous.buffer.starved = false;
+        }
+        Ok(())
+    }
+
+    /// Queues a packet that arrived at `arrival`, registering its stream if it is new.
+    /// Packets with an invalid time base are rejected before their stream is registered.
+    fn enqueue(&mut self, packet: MediaPacket, arrival: Instant) {
+        if !packet.time_base.is_valid() {
+            eprintln!("Rejecting packet of stream {}: time base {}/0", packet.stream_id, packet.time_base.num);
+            self.stats.invalid_time_base += 1;
+            return;
+        }
+        if !self.streams.contains_key(&packet.stream_id) {
+            self.register(StreamInfo::new(packet.stream_id, packet.kind, packet.codec));
+        }
+        let arrival = arrival.saturating_duration_since(self.epoch).as_secs_f64();
+        if let Some(stream) = self.streams.get_mut(&packet.stream_id) {
+            stream.buffer.push(packet, arrival);
+        }
+    }
+
+    /// The lowest-numbered video stream, which drives a video master clock
+    fn primary_video(&self) -> Option<u32> {
+        self.streams.values().find(|stream| stream.info.kind == StreamKind::Video).map(|stream| stream.info.stream_id)
+    }
+
+    /// Stream whose arrivals drive the clock, if the clock follows a stream. Falls back to
+    /// the primary video stream, then to the wall clock, while the configured one has no packets.
+    fn master(&self) -> Option<u32> {
+        let configured = match self.config.master {
+            MasterClock::Audio => self.active_audio,
+            MasterClock::Video => self.primary_video(),
+            MasterClock::External => return None,
+        };
+        let has_arrivals = |id: &u32| self.streams.get(id).is_some_and(|stream| !stream.buffer.history.is_empty());
+        configured.filter(has_arrivals).or_else(|| self.primary_video().filter(has_arrivals))
+    }
+
+    /// Whether the stream's packets reach the sink; inactive audio tracks are consumed silently
+    fn is_output(&self, stream: &StreamState) -> bool {
+        stream.info.kind != StreamKind::Audio || self.active_audio == Some(stream.info.stream_id)
+    }
+
+    /// Playout delay that covers the jitter of every audio and video stream that has received packets
+    fn playout_delay(&self) -> f64 {
+        self.streams
+            .values()
+            .filter(|stream| stream.is_continuous() && !stream.buffer.history.is_empty())
+            .map(|stream| stream.buffer.target_latency(&self.config.jitter))
+            .reduce(f64::max)
+            .unwrap_or(self.config.jitter.target_latency.as_secs_f64())
+    }
+
+    /// Starts the clock one playout delay behind the master stream's arrivals and
+    /// slews its rate so the delay follows the jitter and the sender's clock
+    fn update_clock(&mut self, now: Instant) {
+        let delay = self.playout_delay();
+        self.stats.playout_delay = delay;
+
+        let Some(master) = self.master().and_then(|id| self.streams.get(&id)) else {
+            // The wall clock runs at a fixed rate from the first packet
+            if self.clock.anchor.is_none() {
+                let start = self
+                    .streams
+                    .values()
+                    .filter_map(|stream| stream.buffer.packets.front())
+                    .map(MediaPacket::presentation_time)
+                    .reduce(f64::min);
+                if let Some(start) = start {
+                    self.clock.set(start - delay, now);
+                }
+            }
+            return;
+        };
+        let Some(transit) = master.buffer.base_transit() else { return };
+        let local = now.saturating_duration_since(self.epoch).as_secs_f64();
+        let target = local - transit - delay;
+
+        match self.clock.now(now) {
+            None if !master.buffer.packets.is_empty() => self.clock.set(target, now),
+            None => {}
+            Some(current) => {
+                // A positive error means the clock runs ahead and the buffer holds too little
+                let error = current - target;
+                let max_adjust = self.config.jitter.max_rate_adjust;
+                self.clock.set_rate(1.0 - (error / SLEW_TIME).clamp(-max_adjust, max_adjust), now);
+            }
+        }
+        self.stats.clock_rate = self.clock.rate;
+    }
+
+    /// Emits due packets, drops late ones and reports playback hints
+    fn poll(&mut self, now: Instant) -> PollResult {
+        self.update_clock(now);
+        let mut result = PollResult { streams: std::mem::take(&mut self.announced), ..PollResult::default() };
+        let config = self.config;
+        let master = self.master();
+
+        // The master stream goes first so the clock reflects what has been presented
+        let mut order: Vec<u32> = self.streams.keys().copied().filter(|&id| Some(id) != master).collect();
+        order.splice(0..0, master);
+        for stream_id in order {
+            let is_master = Some(stream_id) == master;
+            while let Some(clock_now) = self.clock.now(now) {
+                let Some(stream) = self.streams.get(&stream_id) else { break };
+                let is_output = self.is_output(stream);
+                let continuous = stream.is_continuous();
+                let Some(packet) = stream.buffer.packets.front() else { break };
+                let (time, decode_time) = (packet.presentation_time(), packet.decode_time());
+                let ahead = time - clock_now;
+
+                if ahead > config.early_tolerance.as_secs_f64() {
+                    // Hold the packet until it is due
+                    let due = now + Duration::from_secs_f64(ahead / self.clock.rate);
+                    result.next_due = Some(result.next_due.map_or(due, |next| next.min(due)));
+                    break;
+                }
+                let Some(stream) = self.streams.get_mut(&stream_id) else { break };
+                if -ahead > config.late_threshold.as_secs_f64() {
+                    if is_master {
+                        // The master stream stalled: rebuffer and measure transit afresh from here
+                        self.clock.set(time - self.stats.playout_delay, now);
+                        stream.buffer.base_since = decode_time;
+                        continue;
+                    }
+                    stream.buffer.packets.pop_front();
+                    if is_output {
+                        stream.buffer.late_drops += 1;
+                        self.stats.dropped_late += 1;
+                    }
+                    continue;
+                }
+
+                let Some(packet) = stream.buffer.packets.pop_front() else { break };
+                if !is_output {
+                    continue;
+                }
+                stream.buffer.mark_emitted(&packet);
+                if !is_master && continuous && master.is_some() {
+                    self.record_drift(ahead);
+                }
+                if packet.kind == StreamKind::Audio {
+                    result.hints.extend(self.resample_hint(stream_id));
+                }
+                result.packets.push(packet);
+            }
+            result.hints.extend(self.check_underrun(stream_id, now));
+        }
+        self.update_metrics();
+        result
+    }
+
+    /// Resampling ratio for an audio track: audio follows the clock rate and, when it is
+    /// not the master, compensates the skew of its sender clock against the master's
+    fn resample_hint(&mut self, stream_id: u32) -> Option<PlaybackHint> {
+        let master_skew = self.master().filter(|&id| id != stream_id).and_then(|id| self.streams.get(&id)?.buffer.skew());
+        let stream = self.streams.get_mut(&stream_id)?;
+        let relative_skew = match (stream.buffer.skew(), master_skew) {
+            (Some(skew), Some(master_skew)) => skew - master_skew,
+            _ => 0.0,
+        };
+        let ratio = self.clock.rate * (1.0 - relative_skew);
+        if (ratio - stream.hinted_ratio).abs() < RATE_HINT_STEP {
+            return None;
+        }
+        stream.hinted_ratio = ratio;
+        Some(PlaybackHint::ResampleAudio { stream_id, ratio })
+    }
+
+    /// Counts an underrun once the last packet of an audio or video stream has ended
+    /// with nothing queued behind it
+    fn check_underrun(&mut self, stream_id: u32, now: Instant) -> Option<PlaybackHint> {
+        let clock_now = self.clock.now(now)?;
+        let tolerance = self.config.early_tolerance.as_secs_f64();
+        let stream = self.streams.get_mut(&stream_id)?;
+        if !stream.is_continuous() {
+            return None;
+        }
+        let buffer = &mut stream.buffer;
+        let (_, pts, end) = buffer.last_emitted?;
+        if buffer.starved || !buffer.packets.is_empty() || clock_now <= end + tolerance {
+            return None;
+        }
+        buffer.starved = true;
+        buffer.underruns += 1;
+        // Video keeps showing the last frame; audio has nothing to repeat
+        (stream.info.kind == StreamKind::Video).then_some(PlaybackHint::RepeatFrame { stream_id, pts })
+    }
+
+    fn update_metrics(&mut self) {
+        self.stats.active_audio = self.active_audio;
+        self.stats.streams = self
+            .streams
+            .iter()
+            .map(|(&id, stream)| (id, stream.buffer.metrics(&self.config.jitter)))
+            .collect();
+        let skew = |id: Option<u32>| id.and_then(|id| self.streams.get(&id)?.buffer.skew());
+        self.stats.clock_drift_ppm = match (skew(self.active_audio), skew(self.primary_video())) {
+            (Some(audio), Some(video)) => Some((audio - video) * 1e6),
+            _ => None,
+        };
+    }
+
+    fn record_drift(&mut self, drift: f64) {
+        self.stats.current_drift = Some(drift);
+        self.stats.max_drift = self.stats.max_drift.max(drift.abs());
+        if drift.abs() > self.config.max_drift.as_secs_f64() {
+            self.stats.out_of_tolerance += 1;
+        }
+    }
+}
 
 /// AudioVideoSync struct that holds the state for audio and video synchronization
 pub struct AudioVideoSync {
-    audio_packets: Arc<Mutex<VecDeque<(Instant, Vec<u8>)>>>,
-    video_packets: Arc<Mutex<VecDeque<(Instant, Vec<u8>)>>>,
-# 增强安全性
-    sync_channel: mpsc::Sender<(Instant, Vec<u8>)>,
-# 扩展功能模块
+    state: Arc<Mutex<SyncState>>,
+    sync_channel: mpsc::Sender<MediaPacket>,
+    worker: JoinHandle<()>,
+}
+
+impl Default for AudioVideoSync {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl AudioVideoSync {
-    /// Creates a new AudioVideoSync instance
+    /// Creates a new AudioVideoSync instance using the audio stream as master clock
     pub fn new() -> Self {
-        let (tx, mut rx) = mpsc::channel(100);
-        let audio_packets = Arc::new(Mutex::new(VecDeque::new()));
-        let video_packets = Arc::new(Mutex::new(VecDeque::new()));
+        Self::with_config(SyncConfig::default())
+    }
 
-        tokio::spawn(async move {
-            while let Some((timestamp, packet)) = rx.recv().await {
-                Self::process_packet(timestamp, &packet, &audio_packets, &video_packets).await;
-            }
-        });
+    /// Creates a new AudioVideoSync instance with the given scheduling parameters, logging to stdout
+    pub fn with_config(config: SyncConfig) -> Self {
+        Self::with_sink(config, Box::new(StdoutSink))
+    }
+
+    /// Creates a new AudioVideoSync instance that writes synchronized packets to `sink`
+    pub fn with_sink(config: SyncConfig, sink: Box<dyn OutputSink>) -> Self {
+        let (tx, rx) = mpsc::channel(100);
+        let state = Arc::new(Mutex::new(SyncState::new(config)));
+        let worker = tokio::spawn(Self::run(rx, state.clone(), sink));
 
         AudioVideoSync {
-            audio_packets,
-            video_packets,
+            state,
             sync_channel: tx,
+            worker,
+        }
+    }
+
+    /// Submits a packet for synchronized output. Packets of unregistered streams register them.
+    pub async fn push(&self, packet: MediaPacket) -> Result<(), mpsc::error::SendError<MediaPacket>> {
+        self.sync_channel.send(packet).await
+    }
+
+    /// Adds a stream, or updates the details of a registered one. The first audio track
+    /// registered becomes the active one.
+    pub async fn register_stream(&self, info: StreamInfo) {
+        self.state.lock().await.register(info);
+    }
+
+    /// Removes a stream and drops its queued packets
+    pub async fn remove_stream(&self, stream_id: u32) -> Option<StreamInfo> {
+        self.state.lock().await.remove(stream_id)
+    }
+
+    /// Returns the registered streams, ordered by stream id
+    pub async fn streams(&self) -> Vec<StreamInfo> {
+        self.state.lock().await.streams.values().map(|stream| stream.info.clone()).collect()
+    }
+
+    /// Switches the audio track that is output. The clock and all buffers are kept, so
+    /// the new track continues in sync from its next due packet.
+    pub async fn set_active_audio(&self, stream_id: u32) -> Result<(), UnknownStream> {
+        self.state.lock().await.set_active_audio(stream_id)
+    }
+
+    /// Returns the audio track that is output
+    pub async fn active_audio(&self) -> Option<u32> {
+        self.state.lock().await.active_audio
+    }
+
+    /// Feeds every packet of an MPEG-TS file into the synchronizer, returning the packet count.
+    /// Packets are paced by decoding time like a live source, since the jitter buffer
+    /// measures arrival times.
+    pub async fn feed_ts_file(&self, path: &Path) -> io::Result<u64> {
+        let mut demuxer = TsDemuxer::open(path).await?;
+        let mut count = 0;
+        let mut start: Option<(f64, Instant)> = None;
+        while let Some(packet) = demuxer.next_packet().await? {
+            for info in demuxer.take_new_streams() {
+                self.register_stream(info).await;
+            }
+            let time = packet.decode_time();
+            let (first, started) = *start.get_or_insert((time, Instant::now()));
+            if time > first {
+                time::sleep_until(started + Duration::from_secs_f64(time - first)).await;
+            }
+            self.push(packet).await.map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "synchronizer stopped"))?;
+            count += 1;
         }
+        Ok(count)
     }
 
-    /// Processes a packet and synchronizes it with the other stream
-    async fn process_packet(timestamp: Instant, packet: &[u8], audio_packets: &Arc<Mutex<VecDeque<(Instant, Vec<u8>)>>>, video_packets: &Arc<Mutex<VecDeque<(Instant, Vec<u8>)>>>) {
-        let mut audio_stream = audio_packets.lock().await;
-        let mut video_stream = video_packets.lock().await;
+    /// Returns the current synchronization statistics
+    pub async fn stats(&self) -> SyncStats {
+        self.state.lock().await.stats.clone()
+    }
 
-        if packet[0] == 1 { // Assuming the first byte denotes whether it's audio or video packet
-            audio_stream.push_back((timestamp, packet.to_vec()));
-# FIXME: 处理边界情况
-        } else {
-            video_stream.push_back((timestamp, packet.to_vec()));
+    /// Stops accepting packets, waits until the queued ones are emitted and the sink is flushed
+    pub async fn close(self) -> SyncStats {
+        drop(self.sync_channel);
+        if let Err(e) = self.worker.await {
+            eprintln!("Synchronizer worker failed: {}", e);
         }
+        let stats = self.state.lock().await.stats.clone();
+        stats
+    }
 
-        while let Some((audio_timestamp, audio_packet)) = audio_stream.front() {
-            let delay = (*audio_timestamp).saturating_duration_since(Instant::now());
-            if delay > Duration::from_millis(100) {
-                audio_stream.pop_front();
-            } else {
-                break;
+    /// Worker loop: queues incoming packets and emits them when due. After the
+    /// channel closes, the remaining packets are still emitted on schedule.
+    async fn run(mut rx: mpsc::Receiver<MediaPacket>, state: Arc<Mutex<SyncState>>, mut sink: Box<dyn OutputSink>) {
+        let mut closed = false;
+        loop {
+            let PollResult { streams, packets, hints, next_due } = state.lock().await.poll(Instant::now());
+            for info in streams {
+                if let Err(e) = sink.add_stream(&info).await {
+                    eprintln!("Error announcing {}: {}", info, e);
+                    state.lock().await.stats.sink_errors += 1;
+                }
+            }
+            for hint in hints {
+                if let Err(e) = sink.apply_hint(&hint).await {
+                    eprintln!("Error applying {:?}: {}", hint, e);
+                    state.lock().await.stats.sink_errors += 1;
+                }
+            }
+            for packet in packets {
+                if let Err(e) = sink.write_packet(&packet).await {
+                    eprintln!("Error writing {:?} packet at {:.3}s: {}", packet.kind, packet.presentation_time(), e);
+                    state.lock().await.stats.sink_errors += 1;
+                }
+            }
+            if closed {
+                // Emit what is still held, then finish once nothing is due
+                match next_due {
+                    Some(due) => time::sleep_until(due).await,
+                    None => break,
+                }
+                continue;
+            }
+
+            // Wake up regularly to keep slewing the clock and to notice underruns
+            let tick = Instant::now() + POLL_INTERVAL;
+            let wake_at = next_due.map_or(tick, |due| due.min(tick));
+            tokio::select! {
+                received = rx.recv() => match received {
+                    Some(packet) => state.lock().await.enqueue(packet, Instant::now()),
+                    None => closed = true,
+                },
+                _ = time::sleep_until(wake_at) => {}
             }
         }
+        if let Err(e) = sink.finish().await {
+            eprintln!("Error flushing output: {}", e);
+            state.lock().await.stats.sink_errors += 1;
+        }
+    }
+}
 
-        while let Some((video_timestamp, video_packet)) = video_stream.front() {
-            let delay = (*video_timestamp).saturating_duration_since(Instant::now());
-            if delay > Duration::from_millis(100) {
-                video_stream.pop_front();
-            } else {
-                break;
+/// Opens the sink named on the command line: `rtp://host:port` or an output `.ts` path
+async fn open_sink(target: Option<&str>) -> io::Result<Box<dyn OutputSink>> {
+    match target {
+        None => Ok(Box::new(StdoutSink)),
+        Some(target) => match target.strip_prefix("rtp://") {
+            Some(address) => {
+                let receiver = address.parse().map_err(|_| invalid_data("invalid RTP receiver address"))?;
+                Ok(Box::new(RtpSink::connect(receiver).await?))
+            }
+            None => Ok(Box::new(TsFileSink::create(Path::new(target)).await?)),
+        },
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    // Synchronize a recording when one is given on the command line:
+    // `<input.ts> [output.ts | rtp://host:port]`
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(path) = args.get(1) {
+        let sink = match open_sink(args.get(2).map(String::as_str)).await {
+            Ok(sink) => sink,
+            Err(e) => {
+                println!("Error opening output: {}", e);
+                return;
             }
+        };
+        let sync = AudioVideoSync::with_sink(SyncConfig::default(), sink);
+        match sync.feed_ts_file(Path::new(path)).await {
+            Ok(count) => println!("Demuxed {} packets from {}", count, path),
+            Err(e) => println!("Error reading {}: {}", path, e),
         }
+        println!("{:?}", sync.close().await);
+        return;
+    }
 
-        // Synchronize and output the audio and video packets
-        if let (Some((audio_timestamp, audio_packet)), Some((video_timestamp, video_packet))) = (audio_stream.front(), video_stream.front()) {
-            if audio_timestamp >= video_timestamp {
-                Self::output_packet(&audio_packets, &video_packets, audio_timestamp, audio_packet).await;
-            } else {
-                Self::output_packet(&audio_packets, &video_packets, video_timestamp, video_packet).await;
+    let recorder = RecordingSink::new();
+    let sync = AudioVideoSync::with_sink(SyncConfig::default(), Box::new(recorder.clone()));
+    sync.register_stream(StreamInfo::new(1, StreamKind::Audio, Codec::Aac).with_language("eng")).await;
+    sync.register_stream(StreamInfo::new(2, StreamKind::Video, Codec::H264)).await;
+    sync.register_stream(StreamInfo::new(3, StreamKind::Audio, Codec::Aac).with_language("deu")).await;
+    sync.register_stream(StreamInfo::new(4, StreamKind::Subtitle, Codec::DvbSubtitle).with_language("eng")).await;
+
+    // Simulated live source: two audio languages in 1024-sample frames at 48 kHz, 30 fps video
+    // and a subtitle per second on the 90 kHz clock, arriving with up to 8 ms of network jitter
+    // from a video clock 0.1 % fast
+    let audio_frame = 1920;
+    let video_frame = 3000;
+    let mut seed = 0x2545_F491u32;
+    let mut network_delay = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        f64::from(seed % 8_000) / 1e6
+    };
+    let mut arrivals = Vec::new();
+    for stream_id in [1, 3] {
+        for i in 0..140i64 {
+            let audio = MediaPacket {
+                stream_id,
+                kind: StreamKind::Audio,
+                codec: Codec::Aac,
+                pts: i * audio_frame,
+                dts: None,
+                duration: Some(audio_frame),
+                keyframe: true,
+                time_base: TimeBase::MPEG_TS,
+                payload: vec![0; 256],
+            };
+            arrivals.push((audio.decode_time() + network_delay(), audio));
+        }
+    }
+    for frame in 0..90i64 {
+        let video = MediaPacket {
+            stream_id: 2,
+            kind: StreamKind::Video,
+            codec: Codec::H264,
+            pts: frame * video_frame,
+            dts: Some(frame * video_frame - video_frame),
+            duration: Some(video_frame),
+            keyframe: frame % 15 == 0,
+            time_base: TimeBase::MPEG_TS,
+            payload: vec![0; 4096],
+        };
+        arrivals.push((video.decode_time() / 1.001 + network_delay(), video));
+    }
+    for second in 0..3i64 {
+        let subtitle = MediaPacket {
+            stream_id: 4,
+            kind: StreamKind::Subtitle,
+            codec: Codec::DvbSubtitle,
+            pts: second * 90_000,
+            dts: None,
+            duration: Some(90_000),
+            keyframe: true,
+            time_base: TimeBase::MPEG_TS,
+            payload: format!("Subtitle {}", second + 1).into_bytes(),
+        };
+        // Subtitles are typically muxed ahead of time
+        arrivals.push(((subtitle.decode_time() - 0.5).max(0.0), subtitle));
+    }
+    arrivals.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let started = Instant::now();
+    let mut switched = false;
+    for (arrival, packet) in arrivals {
+        time::sleep_until(started + Duration::from_secs_f64(arrival.max(0.0))).await;
+        // Switch to the German track halfway through
+        if !switched && arrival >= 1.5 {
+            sync.set_active_audio(3).await.expect("German track is registered");
+            switched = true;
+        }
+        sync.push(packet).await.expect("synchronizer stopped");
+    }
+
+    let stats = sync.close().await;
+    let emitted = recorder.emitted();
+    if let Some(first) = emitted.first() {
+        // Compare each packet's wall-clock offset with its media-time offset
+        let max_error = emitted
+            .iter()
+            .map(|packet| {
+                let wall = (packet.emitted_at - first.emitted_at).as_secs_f64();
+                (wall - (packet.presentation_time - first.presentation_time)).abs()
+            })
+            .fold(0.0, f64::max);
+        println!("Emitted {} packets, max scheduling error {:.1} ms", emitted.len(), max_error * 1000.0);
+    }
+    // The audio output should hand over from one track to the other without a gap or overlap
+    let audio: Vec<&EmittedPacket> = emitted.iter().filter(|packet| packet.kind == StreamKind::Audio).collect();
+    for pair in audio.windows(2).filter(|pair| pair[0].stream_id != pair[1].stream_id) {
+        println!(
+            "Audio switched from stream {} at {:.3}s to stream {} at {:.3}s",
+            pair[0].stream_id, pair[0].presentation_time, pair[1].stream_id, pair[1].presentation_time
+        );
+    }
+    for (stream_id, metrics) in &stats.streams {
+        println!("Stream {}: {:?}", stream_id, metrics);
+    }
+    println!(
+        "Playout delay {:.0} ms, clock rate {:.5}, clock drift {:?} ppm, dropped {}, out of tolerance {}",
+        stats.playout_delay * 1000.0,
+        stats.clock_rate,
+        stats.clock_drift_ppm.map(|ppm| ppm.round()),
+        stats.dropped_late,
+        stats.out_of_tolerance
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A packet of AAC stream 1 or H.264 stream 2 on the 90 kHz clock
+    fn packet(kind: StreamKind, pts: i64) -> MediaPacket {
+        let (stream_id, codec) = if kind == StreamKind::Audio { (1, Codec::Aac) } else { (2, Codec::H264) };
+        MediaPacket {
+            stream_id,
+            kind,
+            codec,
+            pts,
+            dts: None,
+            duration: None,
+            keyframe: true,
+            time_base: TimeBase::MPEG_TS,
+            payload: vec![0; 16],
+        }
+    }
+
+    /// A PSI section with a placeholder CRC
+    fn section(table_id: u8, body: &[u8]) -> Vec<u8> {
+        let length = body.len() + 4;
+        let mut data = vec![0x00, table_id, 0xB0 | (length >> 8) as u8, length as u8];
+        data.extend_from_slice(body);
+        data.extend_from_slice(&[0; 4]);
+        data
+    }
+
+    /// Splits `data` into TS packets on `pid`, padding each through its adaptation field
+    fn ts_packets(pid: u16, data: &[u8], random_access: bool) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (index, chunk) in data.chunks(TS_PACKET_SIZE - 6).enumerate() {
+            let start = if index == 0 { 0x40 } else { 0x00 };
+            out.extend_from_slice(&[TS_SYNC_BYTE, start | (pid >> 8) as u8, pid as u8, 0x30 | (index & 0x0F) as u8]);
+            let stuffing = TS_PACKET_SIZE - 5 - chunk.len();
+            out.push(stuffing as u8);
+            out.push(if random_access && index == 0 { 0x40 } else { 0x00 });
+            out.resize(out.len() + stuffing - 1, 0xFF);
+            out.extend_from_slice(chunk);
+        }
+        out
+    }
+
+    /// A stream with an H.264 PID 0x100, an AAC PID 0x101 and a private data PID 0x102
+    fn program_tables() -> Vec<u8> {
+        let mut data = ts_packets(PAT_PID, &section(0x00, &[0x00, 0x01, 0xC1, 0x00, 0x00, 0x00, 0x01, 0xE0, 0x20]), false);
+        let mut pmt = vec![0x00, 0x01, 0xC1, 0x00, 0x00, 0xE1, 0x00, 0xF0, 0x00];
+        for (stream_type, pid) in [(0x1B, 0x100u16), (0x0F, 0x101), (0x06, 0x102)] {
+            pmt.extend_from_slice(&[stream_type, 0xE0 | (pid >> 8) as u8, pid as u8, 0xF0, 0x00]);
+        }
+        data.extend(ts_packets(0x20, &section(0x02, &pmt), false));
+        data
+    }
+
+    /// Demuxes `data` written to a temporary file, returning the packets and the announced streams
+    async fn demux_streams(name: &str, data: &[u8]) -> io::Result<(Vec<MediaPacket>, Vec<StreamInfo>)> {
+        let path = std::env::temp_dir().join(format!("av_sync_{}_{}.ts", std::process::id(), name));
+        tokio::fs::write(&path, data).await?;
+        let result = async {
+            let mut demuxer = TsDemuxer::open(&path).await?;
+            let mut packets = Vec::new();
+            while let Some(packet) = demuxer.next_packet().await? {
+                packets.push(packet);
+            }
+            Ok((packets, demuxer.take_new_streams()))
+        }
+        .await;
+        let _ = tokio::fs::remove_file(&path).await;
+        result
+    }
+
+    async fn demux(name: &str, data: &[u8]) -> io::Result<Vec<MediaPacket>> {
+        Ok(demux_streams(name, data).await?.0)
+    }
+
+    fn pts(packets: &[MediaPacket]) -> Vec<i64> {
+        packets.iter().map(|p| p.pts).collect()
+    }
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn packets_are_held_for_the_playout_delay() {
+        let mut state = SyncState::new(SyncConfig::default());
+        let start = state.epoch;
+        state.enqueue(packet(StreamKind::Audio, 0), start);
+
+        // The clock starts one 60 ms target latency behind the first arrival
+        let result = state.poll(start);
+        assert!(result.packets.is_empty());
+        let wait = result.next_due.unwrap() - start;
+        assert!(wait.abs_diff(ms(60)) < ms(1), "wait {:?}", wait);
+        assert!((state.stats.playout_delay - 0.06).abs() < 1e-9);
+
+        let result = state.poll(start + ms(60));
+        assert_eq!(pts(&result.packets), vec![0]);
+        assert_eq!(state.stats.streams[&1].emitted, 1);
+    }
+
+    #[test]
+    fn zero_denominator_time_bases_are_rejected() {
+        assert!(TimeBase::new(1, 0).is_none());
+        assert_eq!(TimeBase::new(1, 48_000), Some(TimeBase { num: 1, den: 48_000 }));
+
+        let mut state = SyncState::new(SyncConfig::default());
+        let start = state.epoch;
+        let mut broken = packet(StreamKind::Audio, 0);
+        broken.time_base = TimeBase { num: 1, den: 0 };
+        state.enqueue(broken, start);
+        assert!(state.streams.is_empty());
+        assert_eq!(state.stats.invalid_time_base, 1);
+        assert!(state.poll(start + ms(100)).packets.is_empty());
+    }
+
+    #[test]
+    fn late_slave_packets_are_dropped_and_drift_is_recorded() {
+        let mut state = SyncState::new(SyncConfig::default());
+        let start = state.epoch;
+        state.enqueue(packet(StreamKind::Audio, 0), start);
+        state.poll(start);
+        state.poll(start + ms(60));
+
+        // One second later the audio master reaches 1.0s; video trails by 150, 70 and 30 ms
+        state.enqueue(packet(StreamKind::Audio, 90_000), start + ms(1000));
+        for pts in [76_500, 83_700, 87_300] {
+            state.enqueue(packet(StreamKind::Video, pts), start + ms(1000));
+        }
+        let result = state.poll(start + ms(1060));
+        assert_eq!(pts(&result.packets), vec![90_000, 83_700, 87_300]);
+
+        let stats = &state.stats;
+        assert_eq!((stats.streams[&1].emitted, stats.streams[&2].emitted, stats.dropped_late), (2, 2, 1));
+        assert_eq!(stats.streams[&2].late_drops, 1);
+        assert!((stats.current_drift.unwrap() + 0.03).abs() < 1e-6);
+        assert!((stats.max_drift - 0.07).abs() < 1e-6);
+        // Only the 70 ms drift is beyond the 45 ms lip-sync tolerance
+        assert_eq!(stats.out_of_tolerance, 1);
+    }
+
+    #[test]
+    fn stalled_master_rebuffers_instead_of_dropping() {
+        let config = SyncConfig { master: MasterClock::Video, ..SyncConfig::default() };
+        let mut state = SyncState::new(config);
+        let start = state.epoch;
+        state.enqueue(packet(StreamKind::Video, 0), start);
+        state.poll(start);
+        state.poll(start + ms(60));
+
+        // The next frame arrives a second late and is held for a fresh playout delay,
+        // which has grown with the jitter the stall caused
+        state.enqueue(packet(StreamKind::Video, 3000), start + ms(1000));
+        let result = state.poll(start + ms(1000));
+        assert!(result.packets.is_empty());
+        let delay = state.stats.playout_delay;
+        assert!(delay > 0.06);
+        let wait = result.next_due.unwrap() - (start + ms(1000));
+        assert!((wait.as_secs_f64() - delay / state.stats.clock_rate).abs() < 1e-6, "wait {:?}", wait);
+
+        let result = state.poll(start + ms(1000) + wait);
+        assert_eq!(pts(&result.packets), vec![3000]);
+        assert_eq!((state.stats.streams[&2].emitted, state.stats.dropped_late), (2, 0));
+    }
+
+    #[test]
+    fn clock_rate_slews_within_the_configured_limit() {
+        let mut state = SyncState::new(SyncConfig::default());
+        let start = state.epoch;
+        state.enqueue(packet(StreamKind::Audio, 0), start);
+        state.poll(start);
+
+        // A packet arriving 20 ms early leaves the buffer deeper than needed, so the clock
+        // catches up, but by at most 0.5 %
+        state.enqueue(packet(StreamKind::Audio, 1800), start);
+        state.poll(start + ms(1));
+        assert!((state.stats.clock_rate - 1.005).abs() < 1e-9);
+        let result = state.poll(start + ms(60));
+        assert_eq!(
+            result.hints,
+            vec![PlaybackHint::ResampleAudio { stream_id: 1, ratio: state.stats.clock_rate }]
+        );
+    }
+
+    #[test]
+    fn rate_adjustment_is_clamped_so_the_clock_runs_forward() {
+        let limit = |max_rate_adjust: f64| {
+            let mut config = SyncConfig::default();
+            config.jitter.max_rate_adjust = max_rate_adjust;
+            SyncState::new(config).config.jitter.max_rate_adjust
+        };
+        assert_eq!(limit(0.01), 0.01);
+        assert_eq!(limit(1.5), MAX_RATE_ADJUST);
+        assert_eq!(limit(-0.1), 0.0);
+        assert_eq!(limit(f64::NAN), 0.0);
+
+        // A buffer far too shallow slows the clock by at most the clamped limit
+        let mut config = SyncConfig::default();
+        config.jitter.max_rate_adjust = 2.0;
+        let mut state = SyncState::new(config);
+        let start = state.epoch;
+        state.enqueue(packet(StreamKind::Audio, 0), start);
+        state.poll(start);
+        state.enqueue(packet(StreamKind::Audio, 90_000), start + ms(3000));
+        state.poll(start + ms(3000));
+        assert!(state.stats.clock_rate >= 1.0 - MAX_RATE_ADJUST);
+        assert!(state.poll(start + ms(3001)).next_due.is_some());
+    }
+
+    #[test]
+    fn video_underrun_repeats_the_last_frame_once() {
+        let config = SyncConfig { master: MasterClock::External, ..SyncConfig::default() };
+        let mut state = SyncState::new(config);
+        let start = state.epoch;
+        let mut frame = packet(StreamKind::Video, 0);
+        frame.duration = Some(3000);
+        state.enqueue(frame, start);
+        state.poll(start);
+        let result = state.poll(start + ms(60));
+        assert_eq!(pts(&result.packets), vec![0]);
+
+        // The frame ends at 33 ms media time and nothing follows it
+        assert!(state.poll(start + ms(95)).hints.is_empty());
+        let result = state.poll(start + ms(100));
+        assert_eq!(result.hints, vec![PlaybackHint::RepeatFrame { stream_id: 2, pts: 0 }]);
+        assert!(state.poll(start + ms(150)).hints.is_empty());
+        assert_eq!(state.stats.streams[&2].underruns, 1);
+    }
+
+    #[test]
+    fn timestamps_round_trip_through_pes_encoding() {
+        for timestamp in [0, 1, 90_000, (1 << 32) + 12_345, (1 << 33) - 1] {
+            assert_eq!(parse_timestamp(&encode_timestamp(0b0010, timestamp)), timestamp);
+        }
+        // Only the low 33 bits are carried
+        assert_eq!(parse_timestamp(&encode_timestamp(0b0010, (1 << 33) + 7)), 7);
+
+        let pes = pes_packet(0xE0, 3600, Some(0), b"frame");
+        assert_eq!(parse_pes(&pes), Some((3600, Some(0), &b"frame"[..])));
+        // A DTS equal to the PTS is left out
+        let pes = pes_packet(0xC0, 1920, Some(1920), b"audio");
+        assert_eq!(parse_pes(&pes), Some((1920, None, &b"audio"[..])));
+        // Packets without a PTS cannot be scheduled and are skipped
+        let mut pes = pes_packet(0xC0, 1920, None, b"audio");
+        pes[7] = 0x00;
+        assert_eq!(parse_pes(&pes), None);
+        assert_eq!(parse_pes(&[0x00, 0x00, 0x02, 0xE0]), None);
+    }
+
+    #[tokio::test]
+    async fn demuxer_returns_typed_packets_for_announced_streams() {
+        let mut data = program_tables();
+        let frame: Vec<u8> = (0..400).map(|b| b as u8).collect();
+        data.extend(ts_packets(0x100, &pes_packet(0xE0, 3600, Some(0), &frame), true));
+        data.extend(ts_packets(0x101, &pes_packet(0xC0, 0, None, &[1; 300]), false));
+        data.extend(ts_packets(0x102, &pes_packet(0xBD, 0, None, b"private"), false));
+        data.extend(ts_packets(0x100, &pes_packet(0xE0, 7200, Some(3600), &[2; 100]), false));
+        data.extend(ts_packets(0x101, &pes_packet(0xC0, 1920, None, &[3; 300]), false));
+
+        let packets = demux("typed", &data).await.unwrap();
+        assert_eq!(packets.len(), 4, "the private data stream is ignored");
+        let video: Vec<&MediaPacket> = packets.iter().filter(|p| p.stream_id == 0x100).collect();
+        let audio: Vec<&MediaPacket> = packets.iter().filter(|p| p.stream_id == 0x101).collect();
+
+        assert_eq!((video[0].kind, video[0].codec), (StreamKind::Video, Codec::H264));
+        assert_eq!((video[0].pts, video[0].dts, video[0].duration), (3600, Some(0), Some(3600)));
+        assert_eq!(video[0].payload, frame);
+        assert!(video[0].keyframe);
+        // The last packet of a stream has no successor to derive its duration from
+        assert_eq!((video[1].pts, video[1].duration, video[1].keyframe), (7200, None, false));
+
+        assert_eq!((audio[0].kind, audio[0].codec), (StreamKind::Audio, Codec::Aac));
+        assert_eq!((audio[0].pts, audio[0].duration), (0, Some(1920)));
+        // Audio frames can always be decoded on their own
+        assert!(audio.iter().all(|p| p.keyframe));
+    }
+
+    #[tokio::test]
+    async fn demuxer_rejects_data_without_sync_bytes() {
+        let mut data = program_tables();
+        data[TS_PACKET_SIZE] = 0x00;
+        let error = demux("nosync", &data).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn crc32_matches_mpeg2_check_value() {
+        assert_eq!(crc32_mpeg2(b"123456789"), 0x0376_E6E7);
+        // A section followed by its own CRC checks to zero
+        let section = psi_section(0x00, 1, 0, &[0x00, 0x01, 0xF0, 0x00]);
+        let length = 3 + (usize::from(section[2] & 0x0F) << 8 | usize::from(section[3]));
+        assert_eq!(crc32_mpeg2(&section[1..1 + length]), 0);
+    }
+
+    /// Interleaved H.264 and AAC packets whose first video frame spans hundreds of TS packets
+    fn sample_packets() -> Vec<MediaPacket> {
+        let mut packets = Vec::new();
+        for i in 0..10i64 {
+            let mut video = packet(StreamKind::Video, 3600 * i + 3600);
+            video.dts = Some(3600 * i);
+            video.duration = Some(3600);
+            video.keyframe = i == 0;
+            // Too large for the PES length field
+            video.payload = (0..if i == 0 { 70_000 } else { 2_000 }).map(|b| (b + i) as u8).collect();
+            packets.push(video);
+            let audio = MediaPacket {
+                duration: Some(1920),
+                payload: vec![i as u8; 300],
+                ..packet(StreamKind::Audio, 1920 * i)
+            };
+            packets.push(audio);
+        }
+        packets
+    }
+
+    /// Checks that `received` carries the same packets as `sent`, demuxed on the given `(stream_id, pid)` pairs
+    fn assert_same_packets(received: &[MediaPacket], sent: &[MediaPacket], pids: [(u32, u32); 2]) {
+        for (stream_id, pid) in pids {
+            let expected: Vec<&MediaPacket> = sent.iter().filter(|p| p.stream_id == stream_id).collect();
+            let actual: Vec<&MediaPacket> = received.iter().filter(|p| p.stream_id == pid).collect();
+            assert_eq!(actual.len(), expected.len());
+            for (index, (got, want)) in actual.iter().zip(&expected).enumerate() {
+                assert_eq!((got.kind, got.codec), (want.kind, want.codec));
+                assert_eq!((got.pts, got.dts, &got.payload), (want.pts, want.dts, &want.payload));
+                // Durations come from the next packet, so the last one has none
+                let duration = (index + 1 < expected.len()).then_some(want.duration.unwrap());
+                assert_eq!(got.duration, duration);
+            }
+        }
+        assert!(received.iter().find(|p| p.kind == StreamKind::Video).unwrap().keyframe);
+    }
+
+    #[tokio::test]
+    async fn muxed_streams_demux_to_the_same_packets() {
+        let sent = sample_packets();
+        let mut muxer = TsMuxer::default();
+        muxer.describe(&StreamInfo::new(1, StreamKind::Audio, Codec::Aac).with_language("eng"));
+        let mut data = Vec::new();
+        for packet in &sent {
+            muxer.mux(packet, &mut data);
+        }
+        assert_eq!(data.len() % TS_PACKET_SIZE, 0);
+
+        let (received, streams) = demux_streams("roundtrip", &data).await.unwrap();
+        assert_eq!(
+            streams,
+            vec![
+                StreamInfo::new(0x100, StreamKind::Video, Codec::H264),
+                StreamInfo::new(0x101, StreamKind::Audio, Codec::Aac).with_language("eng"),
+            ]
+        );
+        assert_same_packets(&received, &sent, [(2, 0x100), (1, 0x101)]);
+    }
+
+    #[tokio::test]
+    async fn ts_file_sink_records_synchronized_output() {
+        let path = std::env::temp_dir().join(format!("av_sync_{}_sink.ts", std::process::id()));
+        let sink = TsFileSink::create(&path).await.unwrap();
+        let sync = AudioVideoSync::with_sink(SyncConfig::default(), Box::new(sink));
+        let sent = sample_packets();
+        for packet in sent.clone() {
+            sync.push(packet).await.unwrap();
+        }
+        let stats = sync.close().await;
+        assert_eq!((stats.streams[&1].emitted, stats.streams[&2].emitted, stats.sink_errors), (10, 10, 0));
+
+        let data = tokio::fs::read(&path).await.unwrap();
+        let _ = tokio::fs::remove_file(&path).await;
+        // The audio master is emitted first and is assigned the first PID
+        let received = demux("sink", &data).await.unwrap();
+        assert_same_packets(&received, &sent, [(1, 0x100), (2, 0x101)]);
+    }
+
+    #[tokio::test]
+    async fn rtp_sink_sends_mp2t_datagrams() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut sink = RtpSink::connect(receiver.local_addr().unwrap()).await.unwrap();
+        let sent = sample_packets();
+        for packet in &sent {
+            sink.write_packet(packet).await.unwrap();
+        }
+
+        let mut data = Vec::new();
+        let mut buffer = [0u8; 2048];
+        let mut sequence = 0u16;
+        while let Ok(received) = time::timeout(Duration::from_millis(200), receiver.recv(&mut buffer)).await {
+            let datagram = &buffer[..received.unwrap()];
+            assert_eq!(datagram[..2], [0x80, RTP_PAYLOAD_TYPE_MP2T]);
+            assert_eq!(u16::from_be_bytes([datagram[2], datagram[3]]), sequence);
+            assert_eq!(u32::from_be_bytes(datagram[8..12].try_into().unwrap()), std::process::id());
+            let payload = &datagram[12..];
+            assert!(payload.len() % TS_PACKET_SIZE == 0 && payload.len() <= TS_PACKETS_PER_DATAGRAM * TS_PACKET_SIZE);
+            data.extend_from_slice(payload);
+            sequence += 1;
+        }
+        // The payloads form a complete transport stream
+        let received = demux("rtp", &data).await.unwrap();
+        assert_same_packets(&received, &sent, [(2, 0x100), (1, 0x101)]);
+    }
+
+    #[tokio::test]
+    async fn recording_sink_sees_packets_at_their_presentation_time() {
+        let sink = RecordingSink::new();
+        let sync = AudioVideoSync::with_sink(SyncConfig::default(), Box::new(sink.clone()));
+        for i in 0..10 {
+            sync.push(packet(StreamKind::Audio, 1800 * i)).await.unwrap();
+        }
+        sync.close().await;
+
+        let emitted = sink.emitted();
+        assert_eq!(emitted.len(), 10);
+        let first = &emitted[0];
+        for (index, packet) in emitted.iter().enumerate() {
+            assert!((packet.presentation_time - 0.02 * index as f64).abs() < 1e-9);
+            let wall = (packet.emitted_at - first.emitted_at).as_secs_f64();
+            assert!((wall - packet.presentation_time).abs() < 0.03, "packet {} emitted after {:.3}s", index, wall);
+        }
+    }
+
+    #[test]
+    fn least_squares_slope_fits_a_line() {
+        assert_eq!(least_squares_slope(&[(0.0, 1.0), (1.0, 3.0), (2.0, 5.0)]), Some(2.0));
+        assert_eq!(least_squares_slope(&[(1.0, 1.0)]), None);
+        assert_eq!(least_squares_slope(&[(1.0, 1.0), (1.0, 2.0)]), None);
+    }
+
+    #[test]
+    fn jitter_buffer_estimates_jitter_and_orders_packets() {
+        let mut buffer = JitterBuffer::default();
+        // Every other packet is delayed by 20 ms on top of a 50 ms network delay
+        for i in (0..200i64).rev() {
+            let mut packet = packet(StreamKind::Audio, 1800 * i);
+            packet.duration = Some(1800);
+            let delay = if i % 2 == 0 { 0.05 } else { 0.07 };
+            let arrival = packet.decode_time() + delay;
+            buffer.push(packet, arrival);
+        }
+        assert!((buffer.jitter - 0.02).abs() < 1e-3, "jitter {}", buffer.jitter);
+        assert!((buffer.base_transit().unwrap() - 0.05).abs() < 1e-9);
+        assert!(buffer.packets.iter().map(|p| p.pts).eq((0..200).map(|i| 1800 * i)));
+        assert!((buffer.depth() - 4.0).abs() < 1e-9);
+        // Four times the jitter is more than the 60 ms target latency
+        assert!((buffer.target_latency(&JitterConfig::default()) - 4.0 * buffer.jitter).abs() < 1e-9);
+    }
+
+    #[test]
+    fn jitter_buffer_estimates_sender_clock_skew() {
+        let mut buffer = JitterBuffer::default();
+        for i in 0..100i64 {
+            let packet = packet(StreamKind::Audio, 1800 * i);
+            // The sender's clock runs 100 ppm slow, with up to 10 ms of queuing delay
+            let time = packet.decode_time();
+            buffer.push(packet, time * (1.0 + 100e-6) + 0.05 + (i % 5) as f64 * 0.0025);
+            if time < MIN_SKEW_SPAN {
+                assert_eq!(buffer.skew(), None);
+            }
+        }
+        let skew = buffer.skew().unwrap();
+        assert!((skew - 100e-6).abs() < 20e-6, "skew {}", skew);
+    }
+
+    /// A packet of the given stream with a one-frame duration
+    fn stream_packet(stream_id: u32, kind: StreamKind, codec: Codec, pts: i64) -> MediaPacket {
+        MediaPacket { stream_id, kind, codec, duration: Some(1800), ..packet(StreamKind::Audio, pts) }
+    }
+
+    #[tokio::test]
+    async fn subtitles_and_metadata_demux_with_their_languages() {
+        let mut muxer = TsMuxer::default();
+        muxer.describe(&StreamInfo::new(4, StreamKind::Subtitle, Codec::DvbSubtitle).with_language("deu"));
+        let mut data = Vec::new();
+        muxer.mux(&packet(StreamKind::Audio, 0), &mut data);
+        muxer.mux(&stream_packet(4, StreamKind::Subtitle, Codec::DvbSubtitle, 0), &mut data);
+        muxer.mux(&stream_packet(5, StreamKind::Metadata, Codec::Id3, 900), &mut data);
+
+        let (received, streams) = demux_streams("sparse", &data).await.unwrap();
+        assert_eq!(
+            streams,
+            vec![
+                StreamInfo::new(0x100, StreamKind::Audio, Codec::Aac),
+                StreamInfo::new(0x101, StreamKind::Subtitle, Codec::DvbSubtitle).with_language("deu"),
+                StreamInfo::new(0x102, StreamKind::Metadata, Codec::Id3),
+            ]
+        );
+        let kinds: Vec<StreamKind> = received.iter().map(|p| p.kind).collect();
+        assert_eq!(kinds, vec![StreamKind::Subtitle, StreamKind::Metadata, StreamKind::Audio]);
+        // Sparse streams are returned without waiting for a successor to derive a duration from
+        assert!(received[..2].iter().all(|p| p.duration.is_none()));
+    }
+
+    #[tokio::test]
+    async fn demuxed_timestamps_stay_continuous_across_the_33_bit_wrap() {
+        let mut muxer = TsMuxer::default();
+        let start = (1i64 << 33) - 2 * 3000;
+        let sent: Vec<i64> = (0..5).map(|i| start + i * 3000).collect();
+        let mut data = Vec::new();
+        for &pts in &sent {
+            muxer.mux(&packet(StreamKind::Audio, pts), &mut data);
+        }
+        let received = demux("wrap", &data).await.unwrap();
+        let received: Vec<i64> = received.iter().map(|p| p.pts).collect();
+        assert_eq!(received, sent);
+    }
+
+    /// A state with English and German audio tracks, video and subtitles, and each track's first packet queued
+    fn multi_stream_state() -> SyncState {
+        let mut state = SyncState::new(SyncConfig::default());
+        state.register(StreamInfo::new(1, StreamKind::Audio, Codec::Aac).with_language("eng"));
+        state.register(StreamInfo::new(2, StreamKind::Video, Codec::H264));
+        state.register(StreamInfo::new(3, StreamKind::Audio, Codec::Aac).with_language("deu"));
+        state.register(StreamInfo::new(4, StreamKind::Subtitle, Codec::DvbSubtitle).with_language("eng"));
+        state
+    }
+
+    #[test]
+    fn active_audio_track_switches_without_resetting_the_clock() {
+        let mut state = multi_stream_state();
+        let start = state.epoch;
+        assert_eq!(state.active_audio, Some(1));
+        let result = state.poll(start);
+        assert_eq!(result.streams.iter().map(|s| s.stream_id).collect::<Vec<_>>(), vec![1, 2, 3, 4]);
+
+        let enqueue_frame = |state: &mut SyncState, i: i64| {
+            for (stream_id, kind, codec) in [(1, StreamKind::Audio, Codec::Aac), (3, StreamKind::Audio, Codec::Aac), (2, StreamKind::Video, Codec::H264)] {
+                state.enqueue(stream_packet(stream_id, kind, codec, 1800 * i), start + Duration::from_millis(20 * i as u64));
             }
+        };
+        enqueue_frame(&mut state, 0);
+        state.poll(start);
+        let result = state.poll(start + ms(60));
+        // Only the active track reaches the output; the other is consumed in step
+        let emitted: Vec<(u32, i64)> = result.packets.iter().map(|p| (p.stream_id, p.pts)).collect();
+        assert_eq!(emitted, vec![(1, 0), (2, 0)]);
+        assert!(state.streams[&3].buffer.packets.is_empty());
+        let anchor = state.clock.anchor;
+
+        state.set_active_audio(3).unwrap();
+        assert_eq!(state.clock.anchor, anchor);
+        enqueue_frame(&mut state, 1);
+        let result = state.poll(start + ms(80));
+        let emitted: Vec<(u32, i64)> = result.packets.iter().map(|p| (p.stream_id, p.pts)).collect();
+        assert_eq!(emitted, vec![(3, 1800), (2, 1800)]);
+        assert_eq!(state.stats.active_audio, Some(3));
+        assert_eq!((state.stats.streams[&1].emitted, state.stats.streams[&3].emitted), (1, 1));
+        assert_eq!(state.stats.dropped_late, 0);
+    }
+
+    #[test]
+    fn only_registered_audio_tracks_can_be_activated() {
+        let mut state = multi_stream_state();
+        assert!(state.set_active_audio(2).is_err());
+        assert_eq!(state.set_active_audio(9).unwrap_err().to_string(), "no such stream: 9");
+        assert_eq!(state.active_audio, Some(1));
+
+        // Removing the active track falls back to another audio track
+        assert_eq!(state.remove(1).map(|info| info.stream_id), Some(1));
+        assert_eq!(state.active_audio, Some(3));
+        assert_eq!(state.remove(1), None);
+        state.remove(3);
+        assert_eq!(state.active_audio, None);
+    }
+
+    #[test]
+    fn packets_of_unknown_streams_register_them() {
+        let mut state = SyncState::new(SyncConfig::default());
+        let start = state.epoch;
+        state.enqueue(stream_packet(7, StreamKind::Audio, Codec::Mp3, 0), start);
+        state.enqueue(stream_packet(8, StreamKind::Audio, Codec::Aac, 0), start);
+        // The first audio track becomes the active one
+        assert_eq!(state.active_audio, Some(7));
+        assert_eq!(
+            state.poll(start).streams,
+            vec![StreamInfo::new(7, StreamKind::Audio, Codec::Mp3), StreamInfo::new(8, StreamKind::Audio, Codec::Aac)]
+        );
+
+        // Only changed details are announced again
+        state.register(StreamInfo::new(8, StreamKind::Audio, Codec::Aac));
+        assert!(state.poll(start).streams.is_empty());
+        state.register(StreamInfo::new(8, StreamKind::Audio, Codec::Aac).with_language("fra"));
+        assert_eq!(state.poll(start).streams, vec![StreamInfo::new(8, StreamKind::Audio, Codec::Aac).with_language("fra")]);
+    }
+
+    #[tokio::test]
+    async fn streams_are_managed_through_the_handle() {
+        let sync = AudioVideoSync::with_sink(SyncConfig::default(), Box::new(RecordingSink::new()));
+        sync.register_stream(StreamInfo::new(2, StreamKind::Video, Codec::H264)).await;
+        sync.register_stream(StreamInfo::new(1, StreamKind::Audio, Codec::Aac).with_language("eng")).await;
+        sync.register_stream(StreamInfo::new(3, StreamKind::Audio, Codec::Aac).with_language("deu")).await;
+        assert_eq!(sync.active_audio().await, Some(1));
+        let streams = sync.streams().await;
+        assert_eq!(streams.iter().map(|s| s.stream_id).collect::<Vec<_>>(), vec![1, 2, 3]);
+
+        sync.set_active_audio(3).await.unwrap();
+        assert_eq!(sync.active_audio().await, Some(3));
+        assert_eq!(sync.remove_stream(3).await.and_then(|info| info.language), Some("deu".to_string()));
+        assert_eq!(sync.active_audio().await, Some(1));
+        sync.close().await;
+    }
+
+    /// Pushes packets paced by their decode time, as a live source would deliver them
+    async fn push_paced(sync: &AudioVideoSync, packets: Vec<MediaPacket>) {
+        let start = Instant::now();
+        for packet in packets {
+            time::sleep_until(start + Duration::from_secs_f64(packet.decode_time())).await;
+            sync.push(packet).await.unwrap();
         }
     }
 
-    /// Outputs a synchronized packet
-    async fn output_packet(audio_packets: &Arc<Mutex<VecDeque<(Instant, Vec<u8>)>>>, video_packets: &Arc<Mutex<VecDeque<(Instant, Vec<u8>)>>>, timestamp: Instant, packet: Vec<u8>) {
-        // Logic to output the synchronized packet, e.g., send it to a display or audio output
-        println!("Synchronized packet at {}: {:?}
\ No newline at end of file
+    #[tokio::test]
+    async fn video_only_input_falls_back_from_audio_master() {
+        let sink = RecordingSink::new();
+        let sync = AudioVideoSync::with_sink(SyncConfig::default(), Box::new(sink.clone()));
+        let packets = (0..30).map(|i| MediaPacket { duration: Some(900), ..packet(StreamKind::Video, i * 900) }).collect();
+        push_paced(&sync, packets).await;
+        let stats = sync.close().await;
+
+        let emitted = sink.emitted();
+        assert_eq!(emitted.len(), 30);
+        assert!(emitted.windows(2).all(|pair| pair[0].presentation_time < pair[1].presentation_time));
+        assert_eq!(stats.dropped_late, 0);
+    }
+}
@@ -1,71 +1,1986 @@
-use std::fs;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local, NaiveDateTime};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fmt::Write as _;
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
+use structopt::StructOpt;
 use tokio::fs;
 use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
-use tokio::io::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::io::Result;
-use tokio::process;
-use tokio::task;
+
+/// 默认的重命名模板：保留原文件名和扩展名，在末尾追加序号
+const DEFAULT_TEMPLATE: &str = "{stem}_{n}.{ext}";
+
+/// 未指定格式时日期占位符使用的格式
+const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// 读取 EXIF 信息时最多读取的文件头字节数
+const EXIF_SCAN_LIMIT: u64 = 128 * 1024;
+
+/// 模板解析或渲染错误
+#[derive(Debug)]
+pub enum TemplateError {
+    /// 模板语法错误，position 为出错处的字符偏移
+    Syntax { position: usize, message: String },
+    /// 未知的大小写转换
+    UnknownFilter(String),
+    /// 模板引用了正则中不存在的捕获组
+    MissingCapture(String),
+    /// 渲染结果不是合法的文件名
+    InvalidName(String),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::Syntax { position, message } => {
+                write!(f, "模板语法错误（位置 {}）：{}", position, message)
+            }
+            TemplateError::UnknownFilter(name) => write!(f, "未知的大小写转换：{}", name),
+            TemplateError::MissingCapture(name) => write!(f, "未知的占位符或正则中不存在的捕获组：{}", name),
+            TemplateError::InvalidName(name) => write!(f, "生成的文件名不合法：{:?}", name),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+impl From<TemplateError> for io::Error {
+    fn from(err: TemplateError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
+
+/// 模板中的占位符
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// 不含扩展名的原文件名
+    Stem,
+    /// 原扩展名（不含点）
+    Ext,
+    /// 序号，按 width 左侧补零
+    Counter { width: usize },
+    /// 文件修改时间
+    FileDate { format: String },
+    /// EXIF 拍摄时间，缺失时退回文件修改时间
+    ExifDate { format: String },
+    /// 所在目录名
+    Parent,
+    /// 正则捕获组，编号或命名
+    Capture(String),
+}
+
+/// 作用在占位符结果上的大小写转换
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CaseFilter {
+    Upper,
+    Lower,
+    Title,
+    Snake,
+    Kebab,
+}
+
+impl CaseFilter {
+    fn parse(name: &str) -> std::result::Result<Self, TemplateError> {
+        match name {
+            "upper" => Ok(CaseFilter::Upper),
+            "lower" => Ok(CaseFilter::Lower),
+            "title" => Ok(CaseFilter::Title),
+            "snake" => Ok(CaseFilter::Snake),
+            "kebab" => Ok(CaseFilter::Kebab),
+            other => Err(TemplateError::UnknownFilter(other.to_string())),
+        }
+    }
+
+    fn apply(self, value: &str) -> String {
+        match self {
+            CaseFilter::Upper => value.to_uppercase(),
+            CaseFilter::Lower => value.to_lowercase(),
+            CaseFilter::Title => {
+                // 字母数字串的首字母大写，其余小写，分隔符保持原样
+                let mut result = String::with_capacity(value.len());
+                let mut at_word_start = true;
+                for c in value.chars() {
+                    if c.is_alphanumeric() {
+                        if at_word_start {
+                            result.extend(c.to_uppercase());
+                        } else {
+                            result.extend(c.to_lowercase());
+                        }
+                        at_word_start = false;
+                    } else {
+                        result.push(c);
+                        at_word_start = true;
+                    }
+                }
+                result
+            }
+            CaseFilter::Snake => split_words(value).join("_"),
+            CaseFilter::Kebab => split_words(value).join("-"),
+        }
+    }
+}
+
+/// 按非字母数字字符和小写到大写的驼峰边界拆分单词，结果统一为小写
+fn split_words(value: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut current = String::new();
+    let mut previous_lower = false;
+    for c in value.chars() {
+        if !c.is_alphanumeric() {
+            if !current.is_empty() {
+                words.push(std::mem::take(&mut current));
+            }
+            previous_lower = false;
+            continue;
+        }
+        if c.is_uppercase() && previous_lower && !current.is_empty() {
+            words.push(std::mem::take(&mut current));
+        }
+        previous_lower = c.is_lowercase() || c.is_numeric();
+        current.extend(c.to_lowercase());
+    }
+    if !current.is_empty() {
+        words.push(current);
+    }
+    words
+}
+
+/// 模板片段
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Token { token: Token, filters: Vec<CaseFilter> },
+}
+
+/// 重命名模板
+///
+/// 语法为普通文本夹杂 `{占位符[:参数][|转换...]}`，`{{` 和 `}}` 表示字面花括号。
+/// 支持的占位符：
+/// - `{stem}` 原文件名（不含扩展名），`{ext}` 原扩展名（不含点）
+/// - `{n}` 序号，`{n:3}` 补零到 3 位
+/// - `{date}` 文件修改日期，`{date:%Y%m%d}` 指定 strftime 格式
+/// - `{exif}` 照片拍摄日期，格式参数同 `{date}` 但不能含时区，没有 EXIF 时使用修改日期
+/// - `{parent}` 所在目录名
+/// - `{1}`、`{year}` 等：文件名正则的编号或命名捕获组
+///
+/// 转换可以串联，例如 `{stem|snake|upper}`，可选 upper、lower、title、snake、kebab。
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenameTemplate {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+impl RenameTemplate {
+    /// 解析模板字符串
+    pub fn parse(template: &str) -> std::result::Result<Self, TemplateError> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.char_indices().peekable();
+
+        while let Some((position, c)) = chars.next() {
+            match c {
+                '{' if matches!(chars.peek(), Some((_, '{'))) => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if matches!(chars.peek(), Some((_, '}'))) => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut body = String::new();
+                    let mut closed = false;
+                    for (_, c) in chars.by_ref() {
+                        if c == '}' {
+                            closed = true;
+                            break;
+                        }
+                        body.push(c);
+                    }
+                    if !closed {
+                        return Err(TemplateError::Syntax {
+                            position,
+                            message: "占位符缺少 '}'".to_string(),
+                        });
+                    }
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Self::parse_token(position, &body)?);
+                }
+                '}' => {
+                    return Err(TemplateError::Syntax {
+                        position,
+                        message: "多余的 '}'，字面花括号请写成 '}}'".to_string(),
+                    });
+                }
+                _ => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        Ok(RenameTemplate {
+            source: template.to_string(),
+            segments,
+        })
+    }
+
+    fn parse_token(position: usize, body: &str) -> std::result::Result<Segment, TemplateError> {
+        let mut parts = body.split('|');
+        let head = parts.next().unwrap_or("").trim();
+        let filters = parts
+            .map(|name| CaseFilter::parse(name.trim()))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        let (name, argument) = match head.split_once(':') {
+            Some((name, argument)) => (name.trim(), Some(argument)),
+            None => (head, None),
+        };
+        let syntax = |message: String| TemplateError::Syntax { position, message };
+
+        let token = match name {
+            "" => return Err(syntax("空的占位符".to_string())),
+            "stem" => Token::Stem,
+            "ext" => Token::Ext,
+            "parent" => Token::Parent,
+            "n" => {
+                let width = match argument {
+                    Some(width) => width
+                        .trim()
+                        .parse()
+                        .map_err(|_| syntax(format!("序号宽度不是数字：{}", width)))?,
+                    None => 0,
+                };
+                Token::Counter { width }
+            }
+            "date" | "exif" => {
+                let format = argument.unwrap_or(DEFAULT_DATE_FORMAT).to_string();
+                // 渲染时遇到无效的格式说明符会 panic，解析时提前拒绝
+                if StrftimeItems::new(&format).any(|item| matches!(item, Item::Error)) {
+                    return Err(syntax(format!("无效的日期格式：{}", format)));
+                }
+                if format.contains('/') {
+                    return Err(syntax(format!("日期格式不能包含 '/'：{}", format)));
+                }
+                if name == "date" {
+                    Token::FileDate { format }
+                } else {
+                    // EXIF 日期不带时区，%z、%Z 等时区说明符在渲染时会失败
+                    let mut sample = String::new();
+                    if write!(sample, "{}", NaiveDateTime::default().format(&format)).is_err() {
+                        return Err(syntax(format!("EXIF 日期没有时区，不能使用格式：{}", format)));
+                    }
+                    Token::ExifDate { format }
+                }
+            }
+            capture if capture.chars().all(|c| c.is_alphanumeric() || c == '_') => {
+                if argument.is_some() {
+                    return Err(syntax(format!("捕获组 {} 不接受参数", capture)));
+                }
+                Token::Capture(capture.to_string())
+            }
+            other => return Err(syntax(format!("未知的占位符：{}", other))),
+        };
+
+        Ok(Segment::Token { token, filters })
+    }
+
+    /// 原始模板字符串
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// 模板是否需要读取 EXIF 信息
+    pub fn uses_exif(&self) -> bool {
+        self.tokens().any(|token| matches!(token, Token::ExifDate { .. }))
+    }
+
+    /// 检查模板引用的捕获组都存在于正则中
+    pub fn check_captures(&self, pattern: Option<&Regex>) -> std::result::Result<(), TemplateError> {
+        for token in self.tokens() {
+            let Token::Capture(name) = token else {
+                continue;
+            };
+            let exists = match (pattern, name.parse::<usize>()) {
+                (None, _) => false,
+                (Some(pattern), Ok(index)) => index < pattern.captures_len(),
+                (Some(pattern), Err(_)) => pattern.capture_names().flatten().any(|n| n == name),
+            };
+            if !exists {
+                return Err(TemplateError::MissingCapture(name.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    fn tokens(&self) -> impl Iterator<Item = &Token> {
+        self.segments.iter().filter_map(|segment| match segment {
+            Segment::Token { token, .. } => Some(token),
+            Segment::Literal(_) => None,
+        })
+    }
+
+    /// 为单个文件生成新文件名
+    pub fn render(&self, context: &RenameContext) -> std::result::Result<String, TemplateError> {
+        let mut name = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(text) => name.push_str(text),
+                Segment::Token { token, filters } => {
+                    let mut value = self.render_token(token, context)?;
+                    for filter in filters {
+                        value = filter.apply(&value);
+                    }
+                    name.push_str(&value);
+                }
+            }
+        }
+
+        // 没有扩展名的文件套用 "{stem}.{ext}" 时会留下结尾的点
+        let name = name.trim_end_matches('.').to_string();
+        if name.is_empty() || name == ".." || name.contains('/') || name.contains('\0') {
+            return Err(TemplateError::InvalidName(name));
+        }
+        Ok(name)
+    }
+
+    fn render_token(&self, token: &Token, context: &RenameContext) -> std::result::Result<String, TemplateError> {
+        let path = context.path;
+        let value = match token {
+            Token::Stem => os_str_lossy(path.file_stem()),
+            Token::Ext => os_str_lossy(path.extension()),
+            Token::Parent => os_str_lossy(path.parent().and_then(Path::file_name)),
+            Token::Counter { width } => format!("{:0width$}", context.index, width = *width),
+            Token::FileDate { format } => context
+                .modified
+                .map(|date| date.format(format).to_string())
+                .unwrap_or_default(),
+            Token::ExifDate { format } => match context.exif_date {
+                Some(date) => date.format(format).to_string(),
+                None => context
+                    .modified
+                    .map(|date| date.format(format).to_string())
+                    .unwrap_or_default(),
+            },
+            Token::Capture(name) => context
+                .captures
+                .get(name)
+                .ok_or_else(|| TemplateError::MissingCapture(name.clone()))?
+                .to_string(),
+        };
+        Ok(value)
+    }
+}
+
+impl FromStr for RenameTemplate {
+    type Err = TemplateError;
+
+    fn from_str(template: &str) -> std::result::Result<Self, Self::Err> {
+        RenameTemplate::parse(template)
+    }
+}
+
+impl fmt::Display for RenameTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+fn os_str_lossy(value: Option<&std::ffi::OsStr>) -> String {
+    value.map(|s| s.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+/// 正则匹配文件名得到的捕获组，编号和命名组都按字符串键保存
+#[derive(Debug, Clone, Default)]
+pub struct CaptureGroups {
+    groups: HashMap<String, String>,
+}
+
+impl CaptureGroups {
+    /// 用正则匹配文件名，不匹配时返回 None
+    pub fn capture(pattern: &Regex, file_name: &str) -> Option<Self> {
+        let captures = pattern.captures(file_name)?;
+        let mut groups = HashMap::new();
+        for index in 0..captures.len() {
+            // 未参与匹配的可选组按空串处理
+            let value = captures.get(index).map(|m| m.as_str()).unwrap_or("");
+            groups.insert(index.to_string(), value.to_string());
+        }
+        for name in pattern.capture_names().flatten() {
+            let value = captures.name(name).map(|m| m.as_str()).unwrap_or("");
+            groups.insert(name.to_string(), value.to_string());
+        }
+        Some(CaptureGroups { groups })
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.groups.get(name).map(String::as_str)
+    }
+}
+
+/// 渲染单个文件名所需的上下文
+#[derive(Debug, Clone)]
+pub struct RenameContext<'a> {
+    pub path: &'a Path,
+    pub index: usize,
+    pub modified: Option<DateTime<Local>>,
+    pub exif_date: Option<NaiveDateTime>,
+    pub captures: CaptureGroups,
+}
+
+/// 读取图片的 EXIF 拍摄时间，支持 JPEG 和 TIFF 结构的文件（多数相机 RAW 格式）
+pub async fn read_exif_date(path: &Path) -> Option<NaiveDateTime> {
+    let file = File::open(path).await.ok()?;
+    let mut data = Vec::new();
+    file.take(EXIF_SCAN_LIMIT).read_to_end(&mut data).await.ok()?;
+    parse_exif_date(&data)
+}
+
+/// 从文件头中解析 EXIF 时间：优先 DateTimeOriginal，其次 DateTimeDigitized，最后 IFD0 的 DateTime
+fn parse_exif_date(data: &[u8]) -> Option<NaiveDateTime> {
+    let tiff = Tiff::locate(data)?;
+    let ifd0 = tiff.u32(4)? as usize;
+
+    let exif_ifd = tiff.entry(ifd0, 0x8769).and_then(|entry| tiff.u32(entry + 8));
+    let from_exif = exif_ifd.and_then(|ifd| {
+        let ifd = ifd as usize;
+        tiff.ascii(ifd, 0x9003).or_else(|| tiff.ascii(ifd, 0x9004))
+    });
+    let text = from_exif.or_else(|| tiff.ascii(ifd0, 0x0132))?;
+    NaiveDateTime::parse_from_str(text.trim(), "%Y:%m:%d %H:%M:%S").ok()
+}
+
+/// TIFF 结构（EXIF 数据的容器）的只读视图
+struct Tiff<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    /// 在 JPEG 的 APP1 段或文件开头查找 TIFF 头
+    fn locate(data: &'a [u8]) -> Option<Self> {
+        if data.starts_with(&[0xFF, 0xD8]) {
+            let mut offset = 2;
+            while offset + 4 <= data.len() && data[offset] == 0xFF {
+                let marker = data[offset + 1];
+                // SOS 之后是压缩数据，不会再有元数据段
+                if marker == 0xDA {
+                    return None;
+                }
+                let length = u16::from_be_bytes([data[offset + 2], data[offset + 3]]) as usize;
+                let segment = data.get(offset + 4..offset + 2 + length)?;
+                if marker == 0xE1 && segment.starts_with(b"Exif\0\0") {
+                    return Self::from_header(&segment[6..]);
+                }
+                offset += 2 + length;
+            }
+            None
+        } else {
+            Self::from_header(data)
+        }
+    }
+
+    fn from_header(data: &'a [u8]) -> Option<Self> {
+        let little_endian = match data.get(..4)? {
+            [b'I', b'I', 42, 0] => true,
+            [b'M', b'M', 0, 42] => false,
+            _ => return None,
+        };
+        Some(Tiff { data, little_endian })
+    }
+
+    fn u16(&self, offset: usize) -> Option<u16> {
+        let bytes = [*self.data.get(offset)?, *self.data.get(offset + 1)?];
+        Some(if self.little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        let bytes: [u8; 4] = self.data.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if self.little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    }
+
+    /// 在 IFD 中查找标签，返回该 12 字节条目的偏移
+    fn entry(&self, ifd: usize, tag: u16) -> Option<usize> {
+        let count = self.u16(ifd)? as usize;
+        (0..count)
+            .map(|i| ifd + 2 + i * 12)
+            .find(|&entry| self.u16(entry) == Some(tag))
+    }
+
+    /// 读取 ASCII 类型（类型 2）的标签值
+    fn ascii(&self, ifd: usize, tag: u16) -> Option<&'a str> {
+        let entry = self.entry(ifd, tag)?;
+        if self.u16(entry + 2)? != 2 {
+            return None;
+        }
+        let count = self.u32(entry + 4)? as usize;
+        // 不超过 4 字节的值直接存放在条目中，否则条目中是值的偏移
+        let start = if count <= 4 {
+            entry + 8
+        } else {
+            self.u32(entry + 8)? as usize
+        };
+        let bytes = self.data.get(start..start.checked_add(count)?)?;
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        std::str::from_utf8(&bytes[..end]).ok()
+    }
+}
+
+/// 一次重命名：把 from 移动到 to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rename {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+impl fmt::Display for Rename {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} -> {}", self.from.display(), self.to.display())
+    }
+}
+
+/// 重命名计划中无法自动解决的冲突
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Conflict {
+    /// 多个文件生成了相同的目标名
+    DuplicateTarget { target: PathBuf, sources: Vec<PathBuf> },
+    /// 目标文件已存在，且不会在本批次中被移走
+    TargetExists { source: PathBuf, target: PathBuf },
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Conflict::DuplicateTarget { target, sources } => {
+                let sources: Vec<_> = sources.iter().map(|s| s.display().to_string()).collect();
+                write!(f, "{} 个文件都会重命名为 {}：{}", sources.len(), target.display(), sources.join("、"))
+            }
+            Conflict::TargetExists { source, target } => {
+                write!(f, "{} 的目标 {} 已存在", source.display(), target.display())
+            }
+        }
+    }
+}
+
+/// 完整的重命名计划
+///
+/// renames 是每个文件的最终去向；steps 是实际执行顺序，
+/// 其中互相占用目标名的文件（如 a->b、b->a）会先移到临时名再归位。
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RenamePlan {
+    pub renames: Vec<Rename>,
+    pub steps: Vec<Rename>,
+    pub conflicts: Vec<Conflict>,
+    /// 新名与原名相同而无需处理的文件数
+    pub unchanged: usize,
+}
+
+impl RenamePlan {
+    /// 根据每个文件的最终去向生成计划，existing 判断目标是否已被批次外的文件占用
+    fn build(renames: Vec<Rename>, unchanged: usize, existing: &HashSet<PathBuf>) -> Self {
+        let sources: HashSet<&PathBuf> = renames.iter().map(|r| &r.from).collect();
+
+        let mut by_target: HashMap<&PathBuf, Vec<&PathBuf>> = HashMap::new();
+        for rename in &renames {
+            by_target.entry(&rename.to).or_default().push(&rename.from);
+        }
+
+        let mut conflicts = Vec::new();
+        for rename in &renames {
+            if let Some(sources) = by_target.remove(&rename.to) {
+                if sources.len() > 1 {
+                    conflicts.push(Conflict::DuplicateTarget {
+                        target: rename.to.clone(),
+                        sources: sources.into_iter().cloned().collect(),
+                    });
+                }
+            }
+            if existing.contains(&rename.to) && !sources.contains(&rename.to) {
+                conflicts.push(Conflict::TargetExists {
+                    source: rename.from.clone(),
+                    target: rename.to.clone(),
+                });
+            }
+        }
+
+        // 有冲突时整批不执行，也就不需要排出执行顺序
+        let steps = if conflicts.is_empty() {
+            schedule(&renames)
+        } else {
+            Vec::new()
+        };
+        RenamePlan {
+            renames,
+            steps,
+            conflicts,
+            unchanged,
+        }
+    }
+
+    /// 计划是否可以执行
+    pub fn is_executable(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+}
+
+impl fmt::Display for RenamePlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for rename in &self.renames {
+            writeln!(f, "{}", rename)?;
+        }
+        if self.steps.len() > self.renames.len() {
+            writeln!(f, "存在循环重命名，将通过临时文件名分 {} 步完成", self.steps.len())?;
+        }
+        for conflict in &self.conflicts {
+            writeln!(f, "冲突：{}", conflict)?;
+        }
+        write!(
+            f,
+            "共 {} 个文件需要重命名，{} 个文件名不变，{} 处冲突",
+            self.renames.len(),
+            self.unchanged,
+            self.conflicts.len()
+        )
+    }
+}
+
+/// 排出不会覆盖任何待处理文件的执行顺序
+///
+/// 目标名不再被其他待处理文件占用的可以直接移动；全部卡住时剩下的必然成环，
+/// 把其中一个文件先移到临时名打断环路。
+fn schedule(renames: &[Rename]) -> Vec<Rename> {
+    let mut pending = renames.to_vec();
+    let mut occupied: HashSet<PathBuf> = pending.iter().map(|r| r.from.clone()).collect();
+    let mut steps = Vec::with_capacity(pending.len());
+    let mut temp_count = 0;
+
+    while !pending.is_empty() {
+        let before = pending.len();
+        pending.retain(|rename| {
+            if occupied.contains(&rename.to) {
+                return true;
+            }
+            occupied.remove(&rename.from);
+            occupied.insert(rename.to.clone());
+            steps.push(rename.clone());
+            false
+        });
+
+        if pending.len() == before {
+            let rename = &mut pending[0];
+            let temp = loop {
+                let temp = temp_path(&rename.from, temp_count);
+                temp_count += 1;
+                if !occupied.contains(&temp) {
+                    break temp;
+                }
+            };
+            steps.push(Rename {
+                from: rename.from.clone(),
+                to: temp.clone(),
+            });
+            occupied.remove(&rename.from);
+            occupied.insert(temp.clone());
+            rename.from = temp;
+        }
+    }
+    steps
+}
+
+/// 打断循环时使用的临时文件名，与原文件位于同一目录以保证 rename 不跨文件系统
+fn temp_path(path: &Path, index: usize) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.bulk-rename-{}-{}", name, std::process::id(), index))
+}
+
+/// 执行失败的一步
+#[derive(Debug, Clone, Serialize)]
+pub struct FailedRename {
+    #[serde(flatten)]
+    pub rename: Rename,
+    pub error: String,
+}
+
+/// 执行或撤销一批重命名的结果
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RenameReport {
+    pub completed: Vec<Rename>,
+    pub failed: Vec<FailedRename>,
+}
+
+impl RenameReport {
+    /// 是否所有步骤都成功
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// 移动文件，目标已存在时拒绝执行而不是覆盖。
+///
+/// 先创建硬链接再删除原名：创建链接在目标已存在时原子地失败，不会覆盖并发出现的文件。
+/// 文件系统不支持硬链接时（如 FAT、部分网络文件系统）退回到先检查再 `rename`，
+/// 检查与重命名之间若有其他进程创建了目标，该文件会被覆盖。
+async fn rename_no_clobber(from: &Path, to: &Path) -> Result<()> {
+    let already_exists = || {
+        io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("目标已存在：{}", to.display()),
+        )
+    };
+    match fs::hard_link(from, to).await {
+        Ok(()) => {
+            if let Err(err) = fs::remove_file(from).await {
+                // 删除原名失败时撤销链接，保持原状
+                let _ = fs::remove_file(to).await;
+                return Err(err);
+            }
+            Ok(())
+        }
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Err(already_exists()),
+        // 源文件不存在等与链接无关的错误直接返回
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Err(err),
+        Err(_) => {
+            if fs::symlink_metadata(to).await.is_ok() {
+                return Err(already_exists());
+            }
+            fs::rename(from, to).await
+        }
+    }
+}
+
+/// glob 模式解析错误
+#[derive(Debug)]
+pub struct GlobError {
+    pub pattern: String,
+    pub message: String,
+}
+
+impl fmt::Display for GlobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "glob 模式 {:?} 不合法：{}", self.pattern, self.message)
+    }
+}
+
+impl std::error::Error for GlobError {}
+
+impl From<GlobError> for io::Error {
+    fn from(err: GlobError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum GlobToken {
+    Literal(char),
+    /// `?`：除 `/` 外的单个字符
+    AnyChar,
+    /// `*`：除 `/` 外的任意字符串
+    Star,
+    /// `**/`：零个或多个完整的目录层级
+    AnyDirs,
+    /// `[...]`：字符集，`[!...]` 取反
+    Class { negated: bool, ranges: Vec<(char, char)> },
+}
+
+/// glob 过滤模式
+///
+/// 不含 `/` 的模式只匹配文件名（如 `*.jpg`），含 `/` 的模式匹配相对 base_path 的路径
+/// （如 `raw/**/*.cr2`）。
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlobPattern {
+    source: String,
+    tokens: Vec<GlobToken>,
+    match_path: bool,
+}
+
+impl GlobPattern {
+    pub fn new(pattern: &str) -> std::result::Result<Self, GlobError> {
+        let error = |message: &str| GlobError {
+            pattern: pattern.to_string(),
+            message: message.to_string(),
+        };
+        let mut tokens = Vec::new();
+        let mut chars = pattern.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            let token = match c {
+                '?' => GlobToken::AnyChar,
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    match chars.next() {
+                        Some('/') => GlobToken::AnyDirs,
+                        None => {
+                            // 结尾的 `**` 匹配剩余的任意路径
+                            tokens.push(GlobToken::AnyDirs);
+                            GlobToken::Star
+                        }
+                        Some(_) => return Err(error("`**` 只能单独作为一级目录使用")),
+                    }
+                }
+                '*' => GlobToken::Star,
+                '[' => {
+                    let negated = matches!(chars.peek(), Some('!') | Some('^'));
+                    if negated {
+                        chars.next();
+                    }
+                    let mut ranges = Vec::new();
+                    let mut closed = false;
+                    while let Some(c) = chars.next() {
+                        // 紧跟在 `[` 后的 `]` 是普通字符
+                        if c == ']' && !ranges.is_empty() {
+                            closed = true;
+                            break;
+                        }
+                        if chars.peek() == Some(&'-') {
+                            chars.next();
+                            match chars.next() {
+                                Some(']') => {
+                                    ranges.push((c, c));
+                                    ranges.push(('-', '-'));
+                                    closed = true;
+                                    break;
+                                }
+                                Some(end) => ranges.push((c, end)),
+                                None => break,
+                            }
+                        } else {
+                            ranges.push((c, c));
+                        }
+                    }
+                    if !closed {
+                        return Err(error("字符集缺少 ']'"));
+                    }
+                    GlobToken::Class { negated, ranges }
+                }
+                '\\' => GlobToken::Literal(chars.next().ok_or_else(|| error("结尾的 '\\' 没有转义任何字符"))?),
+                c => GlobToken::Literal(c),
+            };
+            tokens.push(token);
+        }
+
+        Ok(GlobPattern {
+            source: pattern.to_string(),
+            tokens,
+            match_path: pattern.contains('/'),
+        })
+    }
+
+    /// relative 为相对 base_path 的路径
+    pub fn matches(&self, relative: &Path) -> bool {
+        let text = if self.match_path {
+            relative
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/")
+        } else {
+            os_str_lossy(relative.file_name())
+        };
+        let text: Vec<char> = text.chars().collect();
+        glob_match(&self.tokens, &text)
+    }
+}
+
+impl FromStr for GlobPattern {
+    type Err = GlobError;
+
+    fn from_str(pattern: &str) -> std::result::Result<Self, Self::Err> {
+        GlobPattern::new(pattern)
+    }
+}
+
+impl fmt::Display for GlobPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+fn glob_match(tokens: &[GlobToken], text: &[char]) -> bool {
+    let Some((token, rest)) = tokens.split_first() else {
+        return text.is_empty();
+    };
+    match token {
+        GlobToken::Literal(c) => text.first() == Some(c) && glob_match(rest, &text[1..]),
+        GlobToken::AnyChar => matches!(text.first(), Some(&c) if c != '/') && glob_match(rest, &text[1..]),
+        GlobToken::Class { negated, ranges } => match text.first() {
+            Some(&c) if c != '/' => {
+                let inside = ranges.iter().any(|&(start, end)| start <= c && c <= end);
+                inside != *negated && glob_match(rest, &text[1..])
+            }
+            _ => false,
+        },
+        GlobToken::Star => {
+            let limit = text.iter().position(|&c| c == '/').unwrap_or(text.len());
+            (0..=limit).any(|i| glob_match(rest, &text[i..]))
+        }
+        GlobToken::AnyDirs => {
+            glob_match(rest, text)
+                || text
+                    .iter()
+                    .enumerate()
+                    .filter(|&(_, &c)| c == '/')
+                    .any(|(i, _)| glob_match(rest, &text[i + 1..]))
+        }
+    }
+}
+
+/// 文件的处理顺序，决定序号的分配
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortOrder {
+    /// 按相对路径的字典序
+    #[default]
+    Name,
+    /// 按相对路径排序，其中的数字按数值比较（file2 排在 file10 之前）
+    Natural,
+    /// 按修改时间从旧到新
+    Modified,
+    /// 按文件大小从小到大
+    Size,
+}
+
+impl SortOrder {
+    /// 比较两个文件，次序相同时按路径比较，保证结果稳定
+    fn compare(self, a: &FileEntry, b: &FileEntry) -> Ordering {
+        let primary = match self {
+            SortOrder::Name => Ordering::Equal,
+            SortOrder::Natural => natural_cmp(&a.relative.to_string_lossy(), &b.relative.to_string_lossy()),
+            SortOrder::Modified => a.modified.cmp(&b.modified),
+            SortOrder::Size => a.size.cmp(&b.size),
+        };
+        primary.then_with(|| a.relative.cmp(&b.relative))
+    }
+}
+
+impl FromStr for SortOrder {
+    type Err = io::Error;
+
+    fn from_str(order: &str) -> std::result::Result<Self, Self::Err> {
+        match order {
+            "name" => Ok(SortOrder::Name),
+            "natural" => Ok(SortOrder::Natural),
+            "mtime" => Ok(SortOrder::Modified),
+            "size" => Ok(SortOrder::Size),
+            other => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("未知的排序方式：{}（可选 name、natural、mtime、size）", other),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SortOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SortOrder::Name => "name",
+            SortOrder::Natural => "natural",
+            SortOrder::Modified => "mtime",
+            SortOrder::Size => "size",
+        })
+    }
+}
+
+/// 自然排序：连续的数字按数值比较，其余字符逐个比较
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a = a.chars().peekable();
+    let mut b = b.chars().peekable();
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let x = take_digits(&mut a);
+                let y = take_digits(&mut b);
+                // 去掉前导零后先比位数再比字面值，不会溢出
+                let (xs, ys) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+                let ordering = xs.len().cmp(&ys.len()).then_with(|| xs.cmp(ys));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(&y);
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+fn take_digits(chars: &mut std::iter::Peekable<std::str::Chars<'_>>) -> String {
+    let mut digits = String::new();
+    while let Some(c) = chars.next_if(char::is_ascii_digit) {
+        digits.push(c);
+    }
+    digits
+}
+
+/// 扫描得到的待重命名文件
+#[derive(Debug, Clone)]
+pub struct FileEntry {
+    pub path: PathBuf,
+    /// 相对 base_path 的路径，过滤和排序都基于它
+    pub relative: PathBuf,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+fn is_hidden(name: &std::ffi::OsStr) -> bool {
+    name.to_string_lossy().starts_with('.')
+}
+
+/// 撤销日志的文件名，保存在 base_path 下，只保留最近一批重命名；
+/// 日志中的路径相对于 base_path，从其他工作目录撤销也能找到文件
+const JOURNAL_FILE: &str = ".bulk_rename_journal.jsonl";
 
 /// 批量文件重命名工具
 #[derive(Debug, Clone)]
 struct BulkRenamer {
-    base_path: String,
-    extension: String,
+    base_path: PathBuf,
+    template: RenameTemplate,
+    pattern: Option<Regex>,
+    exclude_patterns: Vec<Regex>,
+    include_globs: Vec<GlobPattern>,
+    exclude_globs: Vec<GlobPattern>,
+    /// 最多进入的子目录层数，0 表示不递归，None 表示不限
+    max_depth: Option<usize>,
+    include_hidden: bool,
+    sort: SortOrder,
     start_index: usize,
 }
 
 impl BulkRenamer {
-    /// 创建一个新的批量重命名器
-    pub fn new(base_path: &str, extension: &str, start_index: usize) -> Self {
-        BulkRenamer {
-            base_path: base_path.to_string(),
-            extension: extension.to_string(),
+    /// 创建一个新的批量重命名器，模板语法见 [`RenameTemplate`]
+    pub fn new(base_path: impl Into<PathBuf>, template: &str, start_index: usize) -> std::result::Result<Self, TemplateError> {
+        let template = RenameTemplate::parse(template)?;
+        Ok(BulkRenamer {
+            base_path: base_path.into(),
+            template,
+            pattern: None,
+            exclude_patterns: Vec::new(),
+            include_globs: Vec::new(),
+            exclude_globs: Vec::new(),
+            max_depth: Some(0),
+            include_hidden: false,
+            sort: SortOrder::default(),
             start_index,
+        })
+    }
+
+    /// 只处理文件名匹配该正则的文件，模板中可以引用它的捕获组
+    pub fn with_pattern(mut self, pattern: Regex) -> std::result::Result<Self, TemplateError> {
+        self.template.check_captures(Some(&pattern))?;
+        self.pattern = Some(pattern);
+        Ok(self)
+    }
+
+    /// 跳过文件名匹配该正则的文件
+    pub fn exclude_pattern(mut self, pattern: Regex) -> Self {
+        self.exclude_patterns.push(pattern);
+        self
+    }
+
+    /// 只处理匹配任一 include glob 的文件，可以多次调用
+    pub fn include_glob(mut self, glob: GlobPattern) -> Self {
+        self.include_globs.push(glob);
+        self
+    }
+
+    /// 跳过匹配该 glob 的文件；匹配的目录整个跳过
+    pub fn exclude_glob(mut self, glob: GlobPattern) -> Self {
+        self.exclude_globs.push(glob);
+        self
+    }
+
+    /// 递归处理子目录，max_depth 为最多进入的层数，None 表示不限
+    pub fn recursive(mut self, max_depth: Option<usize>) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// 是否处理以 `.` 开头的隐藏文件和目录，默认跳过
+    pub fn include_hidden(mut self, include: bool) -> Self {
+        self.include_hidden = include;
+        self
+    }
+
+    /// 设置文件的处理顺序，序号按该顺序分配
+    pub fn sort_by(mut self, order: SortOrder) -> Self {
+        self.sort = order;
+        self
+    }
+
+    /// 撤销日志的位置
+    pub fn journal_path(&self) -> PathBuf {
+        self.base_path.join(JOURNAL_FILE)
+    }
+
+    fn relative(&self, path: &Path) -> PathBuf {
+        path.strip_prefix(&self.base_path).unwrap_or(path).to_path_buf()
+    }
+
+    /// 扫描 base_path，返回通过过滤的文件（已排序）和扫描到的所有路径
+    ///
+    /// 所有路径（包括被过滤掉的）用于判断目标名是否已被占用。
+    pub async fn scan(&self) -> Result<(Vec<FileEntry>, HashSet<PathBuf>)> {
+        let base = self.base_path.as_path();
+        let journal = self.journal_path();
+        let mut files = Vec::new();
+        let mut existing = HashSet::new();
+        let mut directories = vec![(base.to_path_buf(), 0)];
+
+        while let Some((directory, depth)) = directories.pop() {
+            let mut entries = fs::read_dir(&directory).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                existing.insert(path.clone());
+                if path == journal || (!self.include_hidden && is_hidden(&entry.file_name())) {
+                    continue;
+                }
+
+                let relative = self.relative(&path);
+                if self.exclude_globs.iter().any(|glob| glob.matches(&relative)) {
+                    continue;
+                }
+
+                // 不跟随符号链接，避免目录环路和重命名链接指向的文件
+                let file_type = entry.file_type().await?;
+                if file_type.is_dir() {
+                    if self.max_depth.is_none_or(|max| depth < max) {
+                        directories.push((path, depth + 1));
+                    }
+                    continue;
+                }
+                if !file_type.is_file() {
+                    continue;
+                }
+
+                let file_name = entry.file_name().to_string_lossy().into_owned();
+                if self.exclude_patterns.iter().any(|pattern| pattern.is_match(&file_name)) {
+                    continue;
+                }
+                if !self.include_globs.is_empty() && !self.include_globs.iter().any(|glob| glob.matches(&relative)) {
+                    continue;
+                }
+                if let Some(pattern) = &self.pattern {
+                    if !pattern.is_match(&file_name) {
+                        continue;
+                    }
+                }
+
+                let metadata = entry.metadata().await?;
+                files.push(FileEntry {
+                    path,
+                    relative,
+                    size: metadata.len(),
+                    modified: metadata.modified().ok(),
+                });
+            }
+        }
+
+        files.sort_by(|a, b| self.sort.compare(a, b));
+        Ok((files, existing))
+    }
+
+    /// 计算重命名计划，不修改任何文件
+    pub async fn plan(&self) -> Result<RenamePlan> {
+        self.template.check_captures(self.pattern.as_ref())?;
+        let (files, existing) = self.scan().await?;
+        let mut renames = Vec::new();
+        let mut unchanged = 0;
+
+        for (index, file) in (self.start_index..).zip(files) {
+            let file_name = os_str_lossy(file.path.file_name());
+            let captures = match &self.pattern {
+                Some(pattern) => CaptureGroups::capture(pattern, &file_name).unwrap_or_default(),
+                None => CaptureGroups::default(),
+            };
+            let exif_date = if self.template.uses_exif() {
+                read_exif_date(&file.path).await
+            } else {
+                None
+            };
+
+            let context = RenameContext {
+                path: &file.path,
+                index,
+                modified: file.modified.map(DateTime::<Local>::from),
+                exif_date,
+                captures,
+            };
+            let new_path = file.path.with_file_name(self.template.render(&context)?);
+
+            if new_path == file.path {
+                unchanged += 1;
+            } else {
+                renames.push(Rename {
+                    from: file.path,
+                    to: new_path,
+                });
+            }
+        }
+
+        Ok(RenamePlan::build(renames, unchanged, &existing))
+    }
+
+    /// 按计划执行重命名，每完成一步就写入撤销日志
+    ///
+    /// 单步失败不会中止整批，但任何一步都不会覆盖已存在的文件。
+    pub async fn execute(&self, plan: &RenamePlan) -> Result<RenameReport> {
+        if !plan.is_executable() {
+            let conflicts: Vec<_> = plan.conflicts.iter().map(ToString::to_string).collect();
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("重命名计划存在冲突：{}", conflicts.join("；")),
+            ));
         }
+
+        let mut report = RenameReport::default();
+        if plan.steps.is_empty() {
+            return Ok(report);
+        }
+
+        let mut journal = File::create(self.journal_path()).await?;
+        for step in &plan.steps {
+            match rename_no_clobber(&step.from, &step.to).await {
+                Ok(()) => {
+                    let entry = Rename {
+                        from: self.relative(&step.from),
+                        to: self.relative(&step.to),
+                    };
+                    let mut line = serde_json::to_string(&entry)?;
+                    line.push('\n');
+                    journal.write_all(line.as_bytes()).await?;
+                    journal.flush().await?;
+                    report.completed.push(step.clone());
+                }
+                Err(e) => report.failed.push(FailedRename {
+                    rename: step.clone(),
+                    error: e.to_string(),
+                }),
+            }
+        }
+        journal.sync_all().await?;
+
+        Ok(report)
     }
 
     /// 执行批量重命名操作
-    pub async fn rename_files(&self) -> Result<()> {
-        let mut index = self.start_index;
-        let entries = fs::read_dir(&self.base_path).await?;
-        let mut futures = Vec::new();
+    pub async fn rename_files(&self) -> Result<RenameReport> {
+        let plan = self.plan().await?;
+        self.execute(&plan).await
+    }
 
-        for entry in entries {
-            let entry = entry.await?;
-            let path = entry.path();
-            if path.is_file() {
-                let file_name = path.file_name().unwrap().to_str().unwrap();
-                let new_name = format!("{}_{}.{}", file_name, index, self.extension);
-                let new_path = path.with_file_name(new_name);
-                futures.push(fs::rename(&path, new_path));
-                index += 1;
+    /// 按撤销日志逆序回滚最近一批重命名
+    ///
+    /// 全部回滚成功后删除日志；否则日志中只保留未能回滚的步骤，修复后可以再次撤销。
+    pub async fn undo(&self) -> Result<RenameReport> {
+        let journal = self.journal_path();
+        let content = fs::read_to_string(&journal).await.map_err(|e| {
+            if e.kind() == io::ErrorKind::NotFound {
+                io::Error::new(io::ErrorKind::NotFound, "没有可撤销的重命名记录")
+            } else {
+                e
             }
+        })?;
+        let steps = content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str::<Rename>)
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        let base = self.base_path.as_path();
+        let mut report = RenameReport::default();
+        for step in steps.into_iter().rev() {
+            let reverse = Rename {
+                from: base.join(step.to),
+                to: base.join(step.from),
+            };
+            match rename_no_clobber(&reverse.from, &reverse.to).await {
+                Ok(()) => report.completed.push(reverse),
+                Err(e) => report.failed.push(FailedRename {
+                    rename: reverse,
+                    error: e.to_string(),
+                }),
+            }
+        }
+
+        if report.is_success() {
+            fs::remove_file(&journal).await?;
+        } else {
+            // 剩余步骤按原执行顺序写回，下次撤销仍然逆序处理
+            let mut remaining = String::new();
+            for failed in report.failed.iter().rev() {
+                let original = Rename {
+                    from: self.relative(&failed.rename.to),
+                    to: self.relative(&failed.rename.from),
+                };
+                remaining.push_str(&serde_json::to_string(&original)?);
+                remaining.push('\n');
+            }
+            fs::write(&journal, remaining).await?;
         }
 
-        // 等待所有重命名操作完成
-        let results = futures::future::join_all(futures).await;
+        Ok(report)
+    }
+}
+
+/// 退出码：全部成功（或无事可做）
+const EXIT_SUCCESS: i32 = 0;
+/// 退出码：没有任何文件被重命名（参数错误、计划冲突或每一步都失败）
+const EXIT_FAILURE: i32 = 1;
+/// 退出码：部分步骤成功、部分失败，可以用 --undo 回滚已完成的部分
+const EXIT_PARTIAL: i32 = 2;
+
+/// 按模板批量重命名目录中的文件
+#[derive(StructOpt, Debug)]
+#[structopt(name = "bulk_file_renamer")]
+struct Opt {
+    /// 需要重命名的文件所在目录
+    #[structopt(parse(from_os_str), default_value = ".")]
+    base_path: PathBuf,
+
+    /// 重命名模板，例如 "{date:%Y%m%d}_{n:3}.{ext|lower}"；默认在原文件名后追加 "_序号"
+    // clap 的帮助文本会把 "{n}" 当作换行符，所以不显示默认值本身
+    #[structopt(short, long, default_value = DEFAULT_TEMPLATE, hide_default_value = true)]
+    template: String,
+
+    /// 只处理文件名匹配该正则的文件，模板中可用 {1}、{name} 引用捕获组
+    #[structopt(short, long)]
+    pattern: Option<Regex>,
+
+    /// 跳过文件名匹配该正则的文件，可重复
+    #[structopt(long, number_of_values = 1)]
+    exclude_regex: Vec<Regex>,
+
+    /// 只处理匹配该 glob 的文件，可重复
+    #[structopt(short, long, number_of_values = 1)]
+    include: Vec<GlobPattern>,
+
+    /// 跳过匹配该 glob 的文件或目录，可重复
+    #[structopt(short, long, number_of_values = 1)]
+    exclude: Vec<GlobPattern>,
+
+    /// 递归处理子目录
+    #[structopt(short, long)]
+    recursive: bool,
+
+    /// 递归时最多进入的子目录层数，指定后自动启用递归
+    #[structopt(long)]
+    max_depth: Option<usize>,
+
+    /// 同时处理以 `.` 开头的隐藏文件和目录
+    #[structopt(long)]
+    hidden: bool,
+
+    /// 文件排序方式，决定序号分配：name、natural、mtime、size
+    #[structopt(long, default_value = "name", possible_values = &["name", "natural", "mtime", "size"])]
+    sort: SortOrder,
+
+    /// 起始序号
+    #[structopt(short = "n", long, default_value = "1")]
+    start_index: usize,
+
+    /// 只输出重命名计划，不修改文件
+    #[structopt(long)]
+    dry_run: bool,
+
+    /// 回滚上一批重命名
+    #[structopt(long, conflicts_with = "dry-run")]
+    undo: bool,
+
+    /// 以 JSON 格式输出计划或结果
+    #[structopt(long)]
+    json: bool,
 
-        for result in results {
-            result?;
+    /// 输出每一步操作，包括打断循环时使用的临时文件名
+    #[structopt(short, long)]
+    verbose: bool,
+}
+
+impl Opt {
+    fn renamer(&self) -> Result<BulkRenamer> {
+        let mut renamer = BulkRenamer::new(&self.base_path, &self.template, self.start_index)?
+            .include_hidden(self.hidden)
+            .sort_by(self.sort);
+        if self.recursive || self.max_depth.is_some() {
+            renamer = renamer.recursive(self.max_depth);
+        }
+        if let Some(pattern) = &self.pattern {
+            renamer = renamer.with_pattern(pattern.clone())?;
         }
+        for glob in &self.include {
+            renamer = renamer.include_glob(glob.clone());
+        }
+        for glob in &self.exclude {
+            renamer = renamer.exclude_glob(glob.clone());
+        }
+        for pattern in &self.exclude_regex {
+            renamer = renamer.exclude_pattern(pattern.clone());
+        }
+        Ok(renamer)
+    }
 
-        Ok(())
+    fn mode(&self) -> &'static str {
+        if self.undo {
+            "undo"
+        } else if self.dry_run {
+            "dry_run"
+        } else {
+            "rename"
+        }
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let base_path = "./"; // 需要重命名的文件所在目录
-    let extension = "txt"; // 重命名后文件的扩展名
-    let start_index = 1; // 重命名的起始索引
+/// --json 模式下的输出
+#[derive(Serialize)]
+struct JsonOutput<'a> {
+    mode: &'static str,
+    exit_code: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    plan: Option<&'a RenamePlan>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    report: Option<&'a RenameReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
 
-    let renamer = BulkRenamer::new(base_path, extension, start_index);
-    renamer.rename_files().await?;
+/// 根据执行结果区分全部成功、全部失败和部分失败
+fn exit_code(report: &RenameReport) -> i32 {
+    if report.failed.is_empty() {
+        EXIT_SUCCESS
+    } else if report.completed.is_empty() {
+        EXIT_FAILURE
+    } else {
+        EXIT_PARTIAL
+    }
+}
 
-    println!("文件重命名完成");
+async fn run(opt: &Opt) -> Result<i32> {
+    let renamer = opt.renamer()?;
+    let mode = opt.mode();
+
+    if opt.dry_run {
+        let plan = renamer.plan().await?;
+        let exit_code = if plan.is_executable() { EXIT_SUCCESS } else { EXIT_FAILURE };
+        if opt.json {
+            print_json(&JsonOutput {
+                mode,
+                exit_code,
+                plan: Some(&plan),
+                report: None,
+                error: None,
+            })?;
+        } else {
+            println!("{}", plan);
+            if opt.verbose && plan.steps.len() > plan.renames.len() {
+                println!("执行步骤：");
+                for step in &plan.steps {
+                    println!("  {}", step);
+                }
+            }
+        }
+        return Ok(exit_code);
+    }
+
+    let report = if opt.undo {
+        renamer.undo().await?
+    } else {
+        renamer.rename_files().await?
+    };
+    let exit_code = exit_code(&report);
+
+    if opt.json {
+        print_json(&JsonOutput {
+            mode,
+            exit_code,
+            plan: None,
+            report: Some(&report),
+            error: None,
+        })?;
+    } else {
+        if opt.verbose {
+            for step in &report.completed {
+                println!("{}", step);
+            }
+        }
+        for failed in &report.failed {
+            eprintln!("失败：{}（{}）", failed.rename, failed.error);
+        }
+        let action = if opt.undo { "撤销" } else { "重命名" };
+        println!("{}完成：成功 {} 步，失败 {} 步", action, report.completed.len(), report.failed.len());
+        if exit_code == EXIT_PARTIAL && !opt.undo {
+            println!("可以使用 --undo 回滚已完成的步骤");
+        }
+    }
+    Ok(exit_code)
+}
 
+fn print_json(output: &JsonOutput) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(output)?);
     Ok(())
 }
+
+#[tokio::main]
+async fn main() {
+    let opt = Opt::from_args();
+
+    let exit_code = match run(&opt).await {
+        Ok(code) => code,
+        Err(e) => {
+            if opt.json {
+                let output = JsonOutput {
+                    mode: opt.mode(),
+                    exit_code: EXIT_FAILURE,
+                    plan: None,
+                    report: None,
+                    error: Some(e.to_string()),
+                };
+                // 序列化失败时退回纯文本
+                if print_json(&output).is_err() {
+                    eprintln!("错误：{}", e);
+                }
+            } else {
+                eprintln!("错误：{}", e);
+            }
+            EXIT_FAILURE
+        }
+    };
+
+    std::process::exit(exit_code);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rename(from: &str, to: &str) -> Rename {
+        Rename {
+            from: PathBuf::from(from),
+            to: PathBuf::from(to),
+        }
+    }
+
+    // 在内存中按顺序执行 steps，任何一步覆盖已有文件都视为失败；返回每个文件最终的原名
+    fn simulate(files: &[&str], steps: &[Rename]) -> HashMap<PathBuf, PathBuf> {
+        let mut contents: HashMap<PathBuf, PathBuf> = files.iter().map(|f| (PathBuf::from(f), PathBuf::from(f))).collect();
+        for step in steps {
+            assert!(!contents.contains_key(&step.to), "{} 覆盖了已有文件", step);
+            let original = contents.remove(&step.from).unwrap_or_else(|| panic!("{} 的源文件不存在", step));
+            contents.insert(step.to.clone(), original);
+        }
+        contents
+    }
+
+    // 在临时目录中创建内容为自身文件名的文件
+    async fn temp_dir(name: &str, files: &[&str]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bulk_renamer_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir).await;
+        fs::create_dir_all(&dir).await.unwrap();
+        for file in files {
+            fs::write(dir.join(file), file).await.unwrap();
+        }
+        dir
+    }
+
+    fn render(template: &str, path: &str, index: usize, captures: CaptureGroups) -> String {
+        let context = RenameContext {
+            path: Path::new(path),
+            index,
+            modified: None,
+            exif_date: None,
+            captures,
+        };
+        RenameTemplate::parse(template).unwrap().render(&context).unwrap()
+    }
+
+    // 构造最小的 TIFF 结构：IFD0 带 DateTime，可选的 EXIF 子 IFD 带 DateTimeOriginal
+    fn tiff(little_endian: bool, date_time: &str, original: Option<&str>) -> Vec<u8> {
+        let u16_bytes = |v: u16| if little_endian { v.to_le_bytes() } else { v.to_be_bytes() };
+        let u32_bytes = |v: u32| if little_endian { v.to_le_bytes() } else { v.to_be_bytes() };
+        let entry = |data: &mut Vec<u8>, tag: u16, kind: u16, count: u32, value: u32| {
+            data.extend_from_slice(&u16_bytes(tag));
+            data.extend_from_slice(&u16_bytes(kind));
+            data.extend_from_slice(&u32_bytes(count));
+            data.extend_from_slice(&u32_bytes(value));
+        };
+
+        let mut data = if little_endian { b"II\x2a\x00".to_vec() } else { b"MM\x00\x2a".to_vec() };
+        data.extend_from_slice(&u32_bytes(8));
+        // IFD0 之后依次是 EXIF IFD 和两个各 20 字节的日期字符串
+        let entries = if original.is_some() { 2 } else { 1 };
+        let exif_ifd = 8 + 2 + 12 * entries + 4;
+        let strings = exif_ifd + if original.is_some() { 2 + 12 + 4 } else { 0 };
+        data.extend_from_slice(&u16_bytes(entries as u16));
+        entry(&mut data, 0x0132, 2, 20, strings);
+        if original.is_some() {
+            entry(&mut data, 0x8769, 4, 1, exif_ifd);
+        }
+        data.extend_from_slice(&u32_bytes(0));
+        if original.is_some() {
+            data.extend_from_slice(&u16_bytes(1));
+            entry(&mut data, 0x9003, 2, 20, strings + 20);
+            data.extend_from_slice(&u32_bytes(0));
+        }
+        for date in std::iter::once(date_time).chain(original) {
+            data.extend_from_slice(date.as_bytes());
+            data.push(0);
+        }
+        data
+    }
+
+    // 把 TIFF 数据放进 JPEG 的 APP1 段，前面带一个 APP0 段
+    fn jpeg(tiff: &[u8]) -> Vec<u8> {
+        let mut data = vec![0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x04, 0x00, 0x00, 0xFF, 0xE1];
+        data.extend_from_slice(&((2 + 6 + tiff.len()) as u16).to_be_bytes());
+        data.extend_from_slice(b"Exif\0\0");
+        data.extend_from_slice(tiff);
+        data.extend_from_slice(&[0xFF, 0xDA, 0x00, 0x02]);
+        data
+    }
+
+    fn exif_time(text: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(text, "%Y:%m:%d %H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn renders_captures_filters_and_counters() {
+        let pattern = Regex::new(r"^IMG_(?P<day>\d{8})_(\w+)").unwrap();
+        let captures = CaptureGroups::capture(&pattern, "IMG_20240501_beachTrip.JPG").unwrap();
+        assert_eq!(
+            render("{day}-{2|snake}-{n:3}.{ext|lower}", "/photos/IMG_20240501_beachTrip.JPG", 7, captures),
+            "20240501-beach_trip-007.jpg"
+        );
+        assert_eq!(render("{stem|title}.{ext}", "/a/hello world", 1, CaptureGroups::default()), "Hello World");
+        assert_eq!(render("{parent|kebab}_{n}", "/a/My Photos/x.png", 12, CaptureGroups::default()), "my-photos_12");
+        assert_eq!(render("{{{stem|upper}}}", "/a/x.png", 1, CaptureGroups::default()), "{X}");
+
+        let context = RenameContext {
+            path: Path::new("/a/x.png"),
+            index: 1,
+            modified: None,
+            exif_date: None,
+            captures: CaptureGroups::default(),
+        };
+        let error = RenameTemplate::parse("{year}").unwrap().render(&context).unwrap_err();
+        assert!(matches!(error, TemplateError::MissingCapture(name) if name == "year"));
+        assert!(RenameTemplate::parse("{stem").is_err());
+        assert!(RenameTemplate::parse("{stem|shout}").is_err());
+    }
+
+    #[test]
+    fn exif_date_prefers_date_time_original() {
+        // RAW 文件通常直接以 TIFF 头开始
+        let raw = tiff(false, "2020:01:02 03:04:05", None);
+        assert_eq!(parse_exif_date(&raw), Some(exif_time("2020:01:02 03:04:05")));
+
+        let photo = jpeg(&tiff(true, "2020:01:02 03:04:05", Some("2019:12:31 23:59:58")));
+        assert_eq!(parse_exif_date(&photo), Some(exif_time("2019:12:31 23:59:58")));
+
+        // 压缩数据之前没有 EXIF 段
+        assert_eq!(parse_exif_date(&[0xFF, 0xD8, 0xFF, 0xDA, 0x00, 0x02]), None);
+        assert_eq!(parse_exif_date(b"plain text"), None);
+    }
+
+    #[tokio::test]
+    async fn renames_matching_files_in_directory() {
+        let dir = temp_dir("pattern", &["IMG_0002.JPG", "notes.txt"]).await;
+        let photo = jpeg(&tiff(true, "2020:01:02 03:04:05", Some("2019:12:31 23:59:58")));
+        fs::write(dir.join("IMG_0001.JPG"), &photo).await.unwrap();
+        let modified: DateTime<Local> = fs::metadata(dir.join("IMG_0002.JPG")).await.unwrap().modified().unwrap().into();
+
+        let pattern = Regex::new(r"^IMG_(\d+)").unwrap();
+        let renamer = BulkRenamer::new(&dir, "{exif:%Y%m%d}_{1}.{ext|lower}", 1)
+            .unwrap()
+            .with_pattern(pattern.clone())
+            .unwrap();
+        renamer.rename_files().await.unwrap();
+
+        assert_eq!(fs::read(dir.join("20191231_0001.jpg")).await.unwrap(), photo);
+        // 没有 EXIF 的文件使用修改日期
+        let fallback = format!("{}_0002.jpg", modified.format("%Y%m%d"));
+        assert_eq!(fs::read_to_string(dir.join(fallback)).await.unwrap(), "IMG_0002.JPG");
+        assert_eq!(fs::read_to_string(dir.join("notes.txt")).await.unwrap(), "notes.txt");
+
+        // 模板引用了正则中不存在的捕获组
+        let error = BulkRenamer::new(&dir, "{2}", 1).unwrap().with_pattern(pattern).unwrap_err();
+        assert!(matches!(error, TemplateError::MissingCapture(name) if name == "2"));
+        let _ = fs::remove_dir_all(&dir).await;
+    }
+
+    #[test]
+    fn schedule_orders_chains_without_temporaries() {
+        let renames = vec![rename("a", "b"), rename("b", "c")];
+        let steps = schedule(&renames);
+        assert_eq!(steps, vec![rename("b", "c"), rename("a", "b")]);
+    }
+
+    #[test]
+    fn schedule_breaks_cycles_with_temporary_names() {
+        for renames in [
+            vec![rename("a", "b"), rename("b", "a")],
+            vec![rename("a", "b"), rename("b", "c"), rename("c", "a")],
+        ] {
+            let steps = schedule(&renames);
+            assert_eq!(steps.len(), renames.len() + 1);
+            let result = simulate(&["a", "b", "c"][..renames.len()], &steps);
+            for r in &renames {
+                assert_eq!(result[&r.to], r.from);
+            }
+        }
+    }
+
+    #[test]
+    fn plan_reports_duplicates_and_existing_targets() {
+        let existing: HashSet<PathBuf> = ["a", "b", "c", "taken"].iter().map(PathBuf::from).collect();
+        let plan = RenamePlan::build(vec![rename("a", "x"), rename("b", "x"), rename("c", "taken")], 0, &existing);
+        assert!(!plan.is_executable());
+        assert!(plan.steps.is_empty());
+        assert_eq!(
+            plan.conflicts,
+            vec![
+                Conflict::DuplicateTarget {
+                    target: PathBuf::from("x"),
+                    sources: vec![PathBuf::from("a"), PathBuf::from("b")],
+                },
+                Conflict::TargetExists {
+                    source: PathBuf::from("c"),
+                    target: PathBuf::from("taken"),
+                },
+            ]
+        );
+
+        // 目标会在本批次中被移走时不算冲突
+        let plan = RenamePlan::build(vec![rename("a", "b"), rename("b", "a")], 1, &existing);
+        assert!(plan.is_executable());
+        assert_eq!(plan.steps.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn execute_and_undo_round_trip() {
+        let dir = temp_dir("undo", &["a.txt", "b.txt", "c.txt"]).await;
+        let renamer = BulkRenamer::new(&dir, "{stem|upper}_new.{ext}", 1).unwrap();
+
+        let plan = renamer.plan().await.unwrap();
+        assert_eq!(plan.renames.len(), 3);
+        // 生成计划不会改动文件
+        assert!(fs::metadata(dir.join("a.txt")).await.is_ok());
+        let report = renamer.execute(&plan).await.unwrap();
+        assert!(report.is_success());
+        assert_eq!(fs::read_to_string(dir.join("A_new.txt")).await.unwrap(), "a.txt");
+        assert_eq!(fs::read_to_string(dir.join("C_new.txt")).await.unwrap(), "c.txt");
+
+        let report = renamer.undo().await.unwrap();
+        assert!(report.is_success());
+        for file in ["a.txt", "b.txt", "c.txt"] {
+            assert_eq!(fs::read_to_string(dir.join(file)).await.unwrap(), file);
+        }
+        assert!(fs::metadata(renamer.journal_path()).await.is_err());
+        assert_eq!(renamer.undo().await.unwrap_err().kind(), io::ErrorKind::NotFound);
+        let _ = fs::remove_dir_all(&dir).await;
+    }
+
+    #[tokio::test]
+    async fn rename_never_replaces_an_existing_target() {
+        let dir = temp_dir("no_clobber", &["a.txt", "b.txt"]).await;
+        let error = rename_no_clobber(&dir.join("a.txt"), &dir.join("b.txt")).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(dir.join("a.txt")).await.unwrap(), "a.txt");
+        assert_eq!(fs::read_to_string(dir.join("b.txt")).await.unwrap(), "b.txt");
+
+        rename_no_clobber(&dir.join("a.txt"), &dir.join("c.txt")).await.unwrap();
+        assert!(fs::symlink_metadata(dir.join("a.txt")).await.is_err());
+        assert_eq!(fs::read_to_string(dir.join("c.txt")).await.unwrap(), "a.txt");
+        let error = rename_no_clobber(&dir.join("a.txt"), &dir.join("d.txt")).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        let _ = fs::remove_dir_all(&dir).await;
+    }
+
+    #[tokio::test]
+    async fn execute_swaps_names_through_temporaries() {
+        let dir = temp_dir("swap", &["a_b.txt", "b_a.txt"]).await;
+        let renamer = BulkRenamer::new(&dir, "{2}_{1}.txt", 1)
+            .unwrap()
+            .with_pattern(Regex::new(r"^(\w)_(\w)").unwrap())
+            .unwrap();
+
+        let plan = renamer.plan().await.unwrap();
+        assert!(plan.is_executable());
+        assert_eq!(plan.steps.len(), 3);
+        let report = renamer.execute(&plan).await.unwrap();
+        assert!(report.is_success());
+        assert_eq!(fs::read_to_string(dir.join("a_b.txt")).await.unwrap(), "b_a.txt");
+        assert_eq!(fs::read_to_string(dir.join("b_a.txt")).await.unwrap(), "a_b.txt");
+
+        renamer.undo().await.unwrap();
+        assert_eq!(fs::read_to_string(dir.join("a_b.txt")).await.unwrap(), "a_b.txt");
+        let _ = fs::remove_dir_all(&dir).await;
+    }
+
+    #[tokio::test]
+    async fn conflicting_plan_is_not_executed() {
+        let dir = temp_dir("conflict", &["a.txt", "b.txt", "same.txt"]).await;
+        let renamer = BulkRenamer::new(&dir, "same.{ext}", 1).unwrap();
+
+        let plan = renamer.plan().await.unwrap();
+        assert!(!plan.is_executable());
+        let error = renamer.execute(&plan).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        for file in ["a.txt", "b.txt", "same.txt"] {
+            assert_eq!(fs::read_to_string(dir.join(file)).await.unwrap(), file);
+        }
+        assert!(fs::metadata(renamer.journal_path()).await.is_err());
+        let _ = fs::remove_dir_all(&dir).await;
+    }
+
+    #[test]
+    fn glob_matches_names_and_paths() {
+        let glob = |pattern: &str, path: &str| GlobPattern::new(pattern).unwrap().matches(Path::new(path));
+        assert!(glob("*.jpg", "raw/2024/a.jpg"));
+        assert!(!glob("*.jpg", "a.jpeg"));
+        assert!(glob("img_??.[jp]ng", "img_01.png"));
+        assert!(!glob("img_[!0-9]*", "img_1.png"));
+        assert!(glob("raw/**/*.cr2", "raw/a.cr2"));
+        assert!(glob("raw/**/*.cr2", "raw/2024/05/a.cr2"));
+        assert!(!glob("raw/*.cr2", "raw/2024/a.cr2"));
+        assert!(glob("raw/**", "raw/2024/a.cr2"));
+        assert!(glob(r"\*.txt", "*.txt"));
+        assert!(GlobPattern::new("a**b").is_err());
+        assert!(GlobPattern::new("[abc").is_err());
+    }
+
+    #[test]
+    fn natural_order_compares_numbers_by_value() {
+        let mut names = vec!["file10.txt", "file2.txt", "file002.txt", "file1.txt", "file.txt"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(names, vec!["file.txt", "file1.txt", "file2.txt", "file002.txt", "file10.txt"]);
+        assert_eq!(natural_cmp("a99999999999999999999999", "a100000000000000000000000"), Ordering::Less);
+        assert_eq!("natural".parse::<SortOrder>().unwrap(), SortOrder::Natural);
+        assert!("random".parse::<SortOrder>().is_err());
+    }
+
+    #[tokio::test]
+    async fn scan_combines_globs_depth_and_sort_order() {
+        let dir = temp_dir("scan", &["b10.jpg", "b2.jpg", "notes.txt", ".hidden.jpg"]).await;
+        fs::create_dir_all(dir.join("sub/deep")).await.unwrap();
+        fs::write(dir.join("sub/b1.jpg"), "sub/b1.jpg").await.unwrap();
+        fs::write(dir.join("sub/deep/b0.jpg"), "sub/deep/b0.jpg").await.unwrap();
+
+        // 只进入一层子目录，按自然顺序编号
+        let renamer = BulkRenamer::new(&dir, "{n}_{stem}.{ext}", 1)
+            .unwrap()
+            .include_glob(GlobPattern::new("*.jpg").unwrap())
+            .recursive(Some(1))
+            .sort_by(SortOrder::Natural);
+        let (files, _) = renamer.scan().await.unwrap();
+        let relative: Vec<_> = files.iter().map(|file| file.relative.clone()).collect();
+        assert_eq!(relative, vec![PathBuf::from("b2.jpg"), PathBuf::from("b10.jpg"), PathBuf::from("sub/b1.jpg")]);
+
+        let plan = renamer.plan().await.unwrap();
+        assert!(renamer.execute(&plan).await.unwrap().is_success());
+        assert_eq!(fs::read_to_string(dir.join("1_b2.jpg")).await.unwrap(), "b2.jpg");
+        assert_eq!(fs::read_to_string(dir.join("2_b10.jpg")).await.unwrap(), "b10.jpg");
+        assert_eq!(fs::read_to_string(dir.join("sub/3_b1.jpg")).await.unwrap(), "sub/b1.jpg");
+        // 超出深度、不匹配 glob 和隐藏的文件保持不变
+        for file in ["sub/deep/b0.jpg", "notes.txt", ".hidden.jpg"] {
+            assert_eq!(fs::read_to_string(dir.join(file)).await.unwrap(), file);
+        }
+
+        // 不限深度时被排除的目录整个跳过；按大小排序时隐藏文件排在最后
+        let renamer = BulkRenamer::new(&dir, "{n}.{ext}", 1)
+            .unwrap()
+            .recursive(None)
+            .include_hidden(true)
+            .exclude_glob(GlobPattern::new("sub").unwrap())
+            .sort_by(SortOrder::Size);
+        let (files, _) = renamer.scan().await.unwrap();
+        let relative: Vec<_> = files.iter().map(|file| file.relative.clone()).collect();
+        assert_eq!(
+            relative,
+            vec![
+                PathBuf::from("1_b2.jpg"),
+                PathBuf::from("2_b10.jpg"),
+                PathBuf::from("notes.txt"),
+                PathBuf::from(".hidden.jpg"),
+            ]
+        );
+        let _ = fs::remove_dir_all(&dir).await;
+    }
+
+    #[tokio::test]
+    async fn exit_codes_distinguish_success_partial_and_failure() {
+        let dir = temp_dir("exit", &["a.txt", "b.txt"]).await;
+        let renamer = BulkRenamer::new(&dir, "{stem}_new.{ext}", 1).unwrap();
+
+        // 计划生成后源文件被删除，只有部分步骤能完成
+        let plan = renamer.plan().await.unwrap();
+        fs::remove_file(dir.join("a.txt")).await.unwrap();
+        let report = renamer.execute(&plan).await.unwrap();
+        assert_eq!((report.completed.len(), report.failed.len()), (1, 1));
+        assert_eq!(exit_code(&report), EXIT_PARTIAL);
+        renamer.undo().await.unwrap();
+
+        fs::remove_file(dir.join("b.txt")).await.unwrap();
+        let report = renamer.execute(&plan).await.unwrap();
+        assert_eq!(exit_code(&report), EXIT_FAILURE);
+        assert_eq!(exit_code(&RenameReport::default()), EXIT_SUCCESS);
+
+        let dir_arg = dir.to_str().unwrap();
+        fs::write(dir.join("c.txt"), "c.txt").await.unwrap();
+        fs::write(dir.join("d.txt"), "d.txt").await.unwrap();
+        // 计划冲突时 --dry-run 也返回失败，且不修改文件
+        let opt = Opt::from_iter(["bulk_file_renamer", dir_arg, "--template", "same.txt", "--dry-run"]);
+        assert_eq!(run(&opt).await.unwrap(), EXIT_FAILURE);
+        let opt = Opt::from_iter(["bulk_file_renamer", dir_arg, "--template", "{n}.txt", "--json"]);
+        assert_eq!(run(&opt).await.unwrap(), EXIT_SUCCESS);
+        assert_eq!(fs::read_to_string(dir.join("1.txt")).await.unwrap(), "c.txt");
+        assert_eq!(fs::read_to_string(dir.join("2.txt")).await.unwrap(), "d.txt");
+        let opt = Opt::from_iter(["bulk_file_renamer", dir_arg, "--undo", "--json"]);
+        assert_eq!(run(&opt).await.unwrap(), EXIT_SUCCESS);
+        assert_eq!(fs::read_to_string(dir.join("c.txt")).await.unwrap(), "c.txt");
+        let _ = fs::remove_dir_all(&dir).await;
+    }
+
+    #[test]
+    fn rejects_invalid_date_formats() {
+        for template in ["{date:%Q}_{n}.{ext}", "{exif:%Y-%}", "{date:%E}"] {
+            let error = RenameTemplate::parse(template).unwrap_err();
+            assert!(matches!(error, TemplateError::Syntax { .. }), "{}: {:?}", template, error);
+        }
+        assert!(RenameTemplate::parse("{date:%Y%m%d_%H%M%S}_{n:3}.{ext}").is_ok());
+        assert!(RenameTemplate::parse("{date:%%Y}").is_ok());
+    }
+
+    #[test]
+    fn rejects_time_zones_in_exif_formats() {
+        for template in ["{exif:%z}.{ext}", "{exif:%Y%m%d_%Z}", "{exif:%:z}", "{exif:%+}"] {
+            let error = RenameTemplate::parse(template).unwrap_err();
+            assert!(matches!(error, TemplateError::Syntax { .. }), "{}: {:?}", template, error);
+        }
+        // 文件修改日期带本地时区
+        assert!(RenameTemplate::parse("{date:%Y%m%d%z}.{ext}").is_ok());
+        assert!(RenameTemplate::parse("{exif:%Y%m%d_%H%M%S}.{ext}").is_ok());
+    }
+}
@@ -1,45 +1,1890 @@
 // data_validator.rs
 
-use serde::Deserialize;
-use serde_json::Result;
+use async_trait::async_trait;
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::error::Error;
-use tokio;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs::File;
+use tokio::io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::sync::{mpsc, Mutex};
 
-// 定义一个简单的数据结构来表示待验证的数据
-#[derive(Deserialize, Debug)]
-struct Data {
-    name: String,
-    age: u32,
+// JSON 值的类型，对应 JSON Schema 中 "type" 的取值
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum JsonType {
+    Null,
+    Boolean,
+    Integer,
+    Number,
+    String,
+    Array,
+    Object,
 }
 
-// 创建一个函数来验证数据
-async fn validate_data(data_str: &str) -> Result<(), Box<dyn Error>> {
-    let data: Data = serde_json::from_str(data_str)?;
-    
-    // 验证名称是否不为空
-    if data.name.is_empty() {
-        return Err("Name cannot be empty".into());
-    }
-    
-    // 验证年龄是否在合理的范围内
-    if data.age < 0 || data.age > 120 {
-        return Err("Age must be between 0 and 120".into());
-    }
-    
+impl JsonType {
+    // integer 也满足 number，与 JSON Schema 一致
+    fn matches(self, value: &Value) -> bool {
+        match self {
+            JsonType::Null => value.is_null(),
+            JsonType::Boolean => value.is_boolean(),
+            JsonType::Integer => is_integer(value),
+            JsonType::Number => value.is_number(),
+            JsonType::String => value.is_string(),
+            JsonType::Array => value.is_array(),
+            JsonType::Object => value.is_object(),
+        }
+    }
+
+    fn of(value: &Value) -> JsonType {
+        match value {
+            Value::Null => JsonType::Null,
+            Value::Bool(_) => JsonType::Boolean,
+            Value::Number(_) if is_integer(value) => JsonType::Integer,
+            Value::Number(_) => JsonType::Number,
+            Value::String(_) => JsonType::String,
+            Value::Array(_) => JsonType::Array,
+            Value::Object(_) => JsonType::Object,
+        }
+    }
+}
+
+impl fmt::Display for JsonType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            JsonType::Null => "null",
+            JsonType::Boolean => "boolean",
+            JsonType::Integer => "integer",
+            JsonType::Number => "number",
+            JsonType::String => "string",
+            JsonType::Array => "array",
+            JsonType::Object => "object",
+        };
+        f.write_str(name)
+    }
+}
+
+// 1.0 这样小数部分为零的数也视为整数
+fn is_integer(value: &Value) -> bool {
+    match value {
+        Value::Number(n) => n.is_i64() || n.is_u64() || n.as_f64().is_some_and(|f| f.fract() == 0.0),
+        _ => false,
+    }
+}
+
+// "type" 可以是单个类型，也可以是类型列表，如 ["string", "null"]
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum TypeSet {
+    One(JsonType),
+    Many(Vec<JsonType>),
+}
+
+impl TypeSet {
+    fn types(&self) -> &[JsonType] {
+        match self {
+            TypeSet::One(t) => std::slice::from_ref(t),
+            TypeSet::Many(types) => types,
+        }
+    }
+}
+
+// 加载 schema 时编译好的正则，非法的正则在加载阶段就报错
+#[derive(Debug, Clone)]
+struct Pattern(Regex);
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        Regex::new(&source).map(Pattern).map_err(serde::de::Error::custom)
+    }
+}
+
+// 数据的声明式 schema，支持 JSON Schema 的一个子集：
+// type、required、properties、additionalProperties、minimum、maximum、
+// exclusiveMinimum、exclusiveMaximum、minLength、maxLength、pattern、enum、
+// items、minItems、maxItems、uniqueItems。
+// 同一结构也可以写成 TOML，关键字可以使用 snake_case（如 min_length）。
+// 与 JSON Schema 一样，只对相应类型的值生效，例如 minimum 不会作用于字符串。
+// 另外支持扩展关键字 severity（"error" 或 "warning"）和 validators（引用注册的自定义规则）。
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+struct Schema {
+    #[serde(rename = "type")]
+    types: Option<TypeSet>,
+    #[serde(default)]
+    required: Vec<String>,
+    #[serde(default)]
+    properties: BTreeMap<String, Schema>,
+    #[serde(alias = "additional_properties")]
+    additional_properties: Option<bool>,
+    minimum: Option<f64>,
+    maximum: Option<f64>,
+    #[serde(alias = "exclusive_minimum")]
+    exclusive_minimum: Option<f64>,
+    #[serde(alias = "exclusive_maximum")]
+    exclusive_maximum: Option<f64>,
+    #[serde(alias = "min_length")]
+    min_length: Option<usize>,
+    #[serde(alias = "max_length")]
+    max_length: Option<usize>,
+    pattern: Option<Pattern>,
+    #[serde(rename = "enum")]
+    allowed: Option<Vec<Value>>,
+    items: Option<Box<Schema>>,
+    #[serde(alias = "min_items")]
+    min_items: Option<usize>,
+    #[serde(alias = "max_items")]
+    max_items: Option<usize>,
+    #[serde(default, alias = "unique_items")]
+    unique_items: bool,
+    // 扩展关键字：本节点规则的违规严重程度，默认为 error，不向子节点继承
+    #[serde(default)]
+    severity: Severity,
+    // 扩展关键字：作用在本节点上的自定义规则，按注册名引用
+    #[serde(default)]
+    validators: Vec<RuleRef>,
+}
+
+// schema 中对自定义规则的引用，可以只写名称：
+//   "validators": ["email_domain"]
+// 也可以带参数和严重程度：
+//   "validators": [{ "name": "ordered_fields", "params": { "before": "start", "after": "end" } }]
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum RuleRef {
+    Name(String),
+    Full {
+        name: String,
+        #[serde(default)]
+        params: Value,
+        severity: Option<Severity>,
+    },
+}
+
+static NO_PARAMS: Value = Value::Null;
+
+impl RuleRef {
+    fn name(&self) -> &str {
+        match self {
+            RuleRef::Name(name) | RuleRef::Full { name, .. } => name,
+        }
+    }
+
+    fn params(&self) -> &Value {
+        match self {
+            RuleRef::Name(_) => &NO_PARAMS,
+            RuleRef::Full { params, .. } => params,
+        }
+    }
+}
+
+// 违规的严重程度，warning 不影响数据是否有效
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "lowercase")]
+enum Severity {
+    Warning,
+    #[default]
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Warning => f.write_str("warning"),
+            Severity::Error => f.write_str("error"),
+        }
+    }
+}
+
+// 一条违规记录：path 为出错位置的 JSON Pointer（根为空串），
+// rule 为违反的规则（即 schema 关键字，如 minLength），expected 和 actual 为规则要求的值与实际值
+#[derive(Serialize, Debug, Clone, PartialEq)]
+struct Violation {
+    path: String,
+    rule: String,
+    severity: Severity,
+    message: String,
+    expected: Value,
+    actual: Value,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path = if self.path.is_empty() { "(root)" } else { &self.path };
+        write!(f, "[{}] {}: {} ({})", self.severity, path, self.message, self.rule)
+    }
+}
+
+// 一个文档的完整验证结果
+#[derive(Serialize, Debug, Clone, Default)]
+struct ValidationReport {
+    valid: bool,
+    error_count: usize,
+    warning_count: usize,
+    violations: Vec<Violation>,
+}
+
+impl ValidationReport {
+    fn new(violations: Vec<Violation>) -> Self {
+        let error_count = violations.iter().filter(|v| v.severity == Severity::Error).count();
+        ValidationReport {
+            valid: error_count == 0,
+            error_count,
+            warning_count: violations.len() - error_count,
+            violations,
+        }
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for violation in &self.violations {
+            writeln!(f, "{}", violation)?;
+        }
+        write!(
+            f,
+            "{}: {} error(s), {} warning(s)",
+            if self.valid { "valid" } else { "invalid" },
+            self.error_count,
+            self.warning_count
+        )
+    }
+}
+
+// 一次遍历 schema 的结果：内置规则的违规，以及待执行的自定义规则
+#[derive(Default)]
+struct Collector<'a> {
+    violations: Vec<Violation>,
+    pending: Vec<PendingRule<'a>>,
+}
+
+impl Collector<'_> {
+    fn push(&mut self, violation: Violation) {
+        self.violations.push(violation);
+    }
+}
+
+// 待执行的自定义规则及其作用的位置
+struct PendingRule<'a> {
+    rule: &'a RuleRef,
+    severity: Severity,
+    path: String,
+    value: &'a Value,
+}
+
+impl PendingRule<'_> {
+    fn context<'a>(&'a self, record: &'a Value) -> RuleContext<'a> {
+        RuleContext {
+            record,
+            value: self.value,
+            path: &self.path,
+            params: self.rule.params(),
+        }
+    }
+
+    fn violation(&self, failure: RuleFailure) -> Violation {
+        Violation {
+            path: failure.path.unwrap_or_else(|| self.path.clone()),
+            rule: self.rule.name().to_string(),
+            severity: self.severity,
+            message: failure.message,
+            expected: failure.expected,
+            actual: failure.actual,
+        }
+    }
+}
+
+// 按 RFC 6901 转义 JSON Pointer 中的一段
+fn pointer_segment(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+// schema 中的数值限制以 f64 保存，输出时整数去掉小数部分
+fn number(n: f64) -> Value {
+    if n.fract() == 0.0 && n.abs() < i64::MAX as f64 {
+        Value::from(n as i64)
+    } else {
+        Value::from(n)
+    }
+}
+
+impl Schema {
+    // 从 JSON 或 TOML 文件加载 schema，按扩展名区分格式
+    async fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let contents = tokio::fs::read_to_string(path).await?;
+        let schema = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&contents)?,
+            Some("json") => serde_json::from_str(&contents)?,
+            _ => return Err(format!("Unsupported schema file format: {}", path.display()).into()),
+        };
+        Ok(schema)
+    }
+
+    // 用内置规则验证一个 JSON 值，收集所有违规而不是在第一处失败时返回；
+    // 引用的自定义规则只记录下来，由 DataValidator 异步执行
+    fn collect<'a>(&'a self, value: &'a Value) -> Collector<'a> {
+        let mut out = Collector::default();
+        self.validate_at(value, "", &mut out);
+        out
+    }
+
+    // schema 中引用的所有自定义规则
+    fn rule_refs<'a>(&'a self, out: &mut Vec<&'a RuleRef>) {
+        out.extend(&self.validators);
+        for schema in self.properties.values() {
+            schema.rule_refs(out);
+        }
+        if let Some(items) = &self.items {
+            items.rule_refs(out);
+        }
+    }
+
+    fn violation(&self, path: &str, rule: &str, message: String, expected: Value, actual: Value) -> Violation {
+        Violation {
+            path: path.to_string(),
+            rule: rule.to_string(),
+            severity: self.severity,
+            message,
+            expected,
+            actual,
+        }
+    }
+
+    fn validate_at<'a>(&'a self, value: &'a Value, path: &str, out: &mut Collector<'a>) {
+        if let Some(types) = &self.types {
+            if !types.types().iter().any(|t| t.matches(value)) {
+                let expected: Vec<String> = types.types().iter().map(ToString::to_string).collect();
+                let actual = JsonType::of(value).to_string();
+                let message = format!("expected {}, found {}", expected.join(" or "), actual);
+                let expected = match types {
+                    TypeSet::One(t) => Value::from(t.to_string()),
+                    TypeSet::Many(_) => Value::from(expected),
+                };
+                out.push(self.violation(path, "type", message, expected, Value::from(actual)));
+            }
+        }
+
+        if let Some(allowed) = &self.allowed {
+            if !allowed.iter().any(|candidate| values_equal(candidate, value)) {
+                out.push(self.violation(
+                    path,
+                    "enum",
+                    format!("value {} is not one of the allowed values", value),
+                    Value::from(allowed.clone()),
+                    value.clone(),
+                ));
+            }
+        }
+
+        match value {
+            Value::Number(_) => self.validate_number(value, path, out),
+            Value::String(s) => self.validate_string(s, path, out),
+            Value::Array(items) => self.validate_array(items, path, out),
+            Value::Object(fields) => self.validate_object(fields, path, out),
+            Value::Null | Value::Bool(_) => {}
+        }
+
+        for rule in &self.validators {
+            let severity = match rule {
+                RuleRef::Full { severity: Some(severity), .. } => *severity,
+                _ => self.severity,
+            };
+            out.pending.push(PendingRule {
+                rule,
+                severity,
+                path: path.to_string(),
+                value,
+            });
+        }
+    }
+
+    fn validate_number(&self, value: &Value, path: &str, out: &mut Collector<'_>) {
+        let Some(n) = value.as_f64() else {
+            return;
+        };
+        let mut check = |rule: &str, limit: Option<f64>, violates: fn(f64, f64) -> bool, relation: &str| {
+            if let Some(limit) = limit.filter(|&limit| violates(n, limit)) {
+                out.push(self.violation(
+                    path,
+                    rule,
+                    format!("{} is {} {}", value, relation, number(limit)),
+                    number(limit),
+                    value.clone(),
+                ));
+            }
+        };
+        check("minimum", self.minimum, |n, limit| n < limit, "less than the minimum of");
+        check("maximum", self.maximum, |n, limit| n > limit, "greater than the maximum of");
+        check("exclusiveMinimum", self.exclusive_minimum, |n, limit| n <= limit, "not greater than");
+        check("exclusiveMaximum", self.exclusive_maximum, |n, limit| n >= limit, "not less than");
+    }
+
+    fn validate_string(&self, s: &str, path: &str, out: &mut Collector<'_>) {
+        // 长度按 Unicode 字符计算
+        let length = s.chars().count();
+        if let Some(min_length) = self.min_length {
+            if length < min_length {
+                out.push(self.violation(
+                    path,
+                    "minLength",
+                    format!("string is shorter than {} characters", min_length),
+                    Value::from(min_length),
+                    Value::from(length),
+                ));
+            }
+        }
+        if let Some(max_length) = self.max_length {
+            if length > max_length {
+                out.push(self.violation(
+                    path,
+                    "maxLength",
+                    format!("string is longer than {} characters", max_length),
+                    Value::from(max_length),
+                    Value::from(length),
+                ));
+            }
+        }
+        if let Some(Pattern(regex)) = &self.pattern {
+            if !regex.is_match(s) {
+                out.push(self.violation(
+                    path,
+                    "pattern",
+                    format!("string does not match pattern {}", regex.as_str()),
+                    Value::from(regex.as_str()),
+                    Value::from(s),
+                ));
+            }
+        }
+    }
+
+    fn validate_array<'a>(&'a self, items: &'a [Value], path: &str, out: &mut Collector<'a>) {
+        if let Some(min_items) = self.min_items {
+            if items.len() < min_items {
+                out.push(self.violation(
+                    path,
+                    "minItems",
+                    format!("array has fewer than {} items", min_items),
+                    Value::from(min_items),
+                    Value::from(items.len()),
+                ));
+            }
+        }
+        if let Some(max_items) = self.max_items {
+            if items.len() > max_items {
+                out.push(self.violation(
+                    path,
+                    "maxItems",
+                    format!("array has more than {} items", max_items),
+                    Value::from(max_items),
+                    Value::from(items.len()),
+                ));
+            }
+        }
+        if self.unique_items {
+            for (i, item) in items.iter().enumerate() {
+                if items[..i].iter().any(|earlier| values_equal(earlier, item)) {
+                    out.push(self.violation(
+                        &format!("{}/{}", path, i),
+                        "uniqueItems",
+                        format!("array items are not unique: {} is repeated", item),
+                        Value::Bool(true),
+                        item.clone(),
+                    ));
+                }
+            }
+        }
+        if let Some(schema) = &self.items {
+            for (i, item) in items.iter().enumerate() {
+                schema.validate_at(item, &format!("{}/{}", path, i), out);
+            }
+        }
+    }
+
+    fn validate_object<'a>(&'a self, fields: &'a serde_json::Map<String, Value>, path: &str, out: &mut Collector<'a>) {
+        for name in &self.required {
+            if !fields.contains_key(name) {
+                out.push(self.violation(
+                    &format!("{}/{}", path, pointer_segment(name)),
+                    "required",
+                    format!("missing required field \"{}\"", name),
+                    Value::from(name.as_str()),
+                    Value::Null,
+                ));
+            }
+        }
+
+        for (name, value) in fields {
+            let field_path = format!("{}/{}", path, pointer_segment(name));
+            match self.properties.get(name) {
+                Some(schema) => schema.validate_at(value, &field_path, out),
+                None if self.additional_properties == Some(false) => {
+                    let allowed: Vec<&String> = self.properties.keys().collect();
+                    out.push(self.violation(
+                        &field_path,
+                        "additionalProperties",
+                        format!("unexpected field \"{}\"", name),
+                        serde_json::json!(allowed),
+                        Value::from(name.as_str()),
+                    ));
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+// 数值按大小比较（1 与 1.0 相等），其余按结构比较
+fn values_equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => x.as_f64() == y.as_f64(),
+        (Value::Array(x), Value::Array(y)) => x.len() == y.len() && x.iter().zip(y).all(|(x, y)| values_equal(x, y)),
+        (Value::Object(x), Value::Object(y)) => {
+            x.len() == y.len() && x.iter().all(|(k, v)| y.get(k).is_some_and(|w| values_equal(v, w)))
+        }
+        _ => a == b,
+    }
+}
+
+// 自定义规则执行时可见的上下文
+struct RuleContext<'a> {
+    // 整条记录，用于跨字段规则
+    record: &'a Value,
+    // 引用该规则的 schema 节点对应的值
+    value: &'a Value,
+    // value 的 JSON Pointer
+    path: &'a str,
+    // schema 中为该规则配置的参数
+    params: &'a Value,
+}
+
+impl RuleContext<'_> {
+    // 以 "/" 开头的引用是相对整条记录的 JSON Pointer，否则是 value 中的字段名
+    fn lookup(&self, reference: &str) -> Option<&Value> {
+        if reference.starts_with('/') {
+            self.record.pointer(reference)
+        } else {
+            self.value.get(reference)
+        }
+    }
+
+    // lookup 所引用位置的 JSON Pointer
+    fn reference_path(&self, reference: &str) -> String {
+        if reference.starts_with('/') {
+            reference.to_string()
+        } else {
+            format!("{}/{}", self.path, pointer_segment(reference))
+        }
+    }
+}
+
+// 自定义规则的失败结果，path 为空时使用引用规则的节点位置
+#[derive(Debug, Clone)]
+struct RuleFailure {
+    path: Option<String>,
+    message: String,
+    expected: Value,
+    actual: Value,
+}
+
+impl RuleFailure {
+    fn new(message: impl Into<String>) -> Self {
+        RuleFailure {
+            path: None,
+            message: message.into(),
+            expected: Value::Null,
+            actual: Value::Null,
+        }
+    }
+
+    fn at(mut self, path: String) -> Self {
+        self.path = Some(path);
+        self
+    }
+
+    fn expected(mut self, expected: Value) -> Self {
+        self.expected = expected;
+        self
+    }
+
+    fn actual(mut self, actual: Value) -> Self {
+        self.actual = actual;
+        self
+    }
+}
+
+// 自定义验证规则，以注册名在 schema 的 validators 关键字中引用
+#[async_trait]
+trait Validator: Send + Sync {
+    async fn validate(&self, context: &RuleContext<'_>) -> Result<(), RuleFailure>;
+
+    // 记录的所有规则执行完后，对通过的规则调用，accepted 表示整条记录是否有效。
+    // 规则可以在 validate 中预留状态，在这里提交或撤销
+    async fn finish(&self, _context: &RuleContext<'_>, _accepted: bool) -> Result<(), RuleFailure> {
+        Ok(())
+    }
+
+    // 加载 schema 时检查参数，参数有误的 schema 不会被接受
+    fn check_params(&self, _params: &Value) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// 按名称注册的自定义规则
+#[derive(Default, Clone)]
+struct ValidatorRegistry {
+    validators: HashMap<String, Arc<dyn Validator>>,
+}
+
+impl ValidatorRegistry {
+    fn register(&mut self, name: &str, validator: impl Validator + 'static) -> &mut Self {
+        self.validators.insert(name.to_string(), Arc::new(validator));
+        self
+    }
+
+    fn get(&self, name: &str) -> Option<&Arc<dyn Validator>> {
+        self.validators.get(name)
+    }
+}
+
+// schema 与自定义规则的组合，负责完整地验证一条记录
+#[derive(Clone)]
+struct DataValidator {
+    schema: Arc<Schema>,
+    registry: Arc<ValidatorRegistry>,
+}
+
+impl DataValidator {
+    // 检查 schema 引用的规则都已注册且参数正确
+    fn new(schema: Schema, registry: ValidatorRegistry) -> Result<Self, String> {
+        let mut refs = Vec::new();
+        schema.rule_refs(&mut refs);
+        for rule in refs {
+            let validator = registry
+                .get(rule.name())
+                .ok_or_else(|| format!("Unknown validator \"{}\" referenced by the schema", rule.name()))?;
+            validator
+                .check_params(rule.params())
+                .map_err(|e| format!("Invalid params for validator \"{}\": {}", rule.name(), e))?;
+        }
+        Ok(DataValidator {
+            schema: Arc::new(schema),
+            registry: Arc::new(registry),
+        })
+    }
+
+    async fn validate(&self, record: &Value) -> ValidationReport {
+        let Collector { mut violations, pending } = self.schema.collect(record);
+
+        let mut passed = Vec::new();
+        for pending in &pending {
+            // 构造时已检查所有引用的规则都存在
+            let Some(validator) = self.registry.get(pending.rule.name()) else {
+                continue;
+            };
+            match validator.validate(&pending.context(record)).await {
+                Ok(()) => passed.push((pending, validator)),
+                Err(failure) => violations.push(pending.violation(failure)),
+            }
+        }
+
+        let report = ValidationReport::new(violations);
+        // 记录是否有效确定之后才提交规则的状态，无效记录不会占用 unique 的值
+        let mut failures = Vec::new();
+        for (pending, validator) in passed {
+            if let Err(failure) = validator.finish(&pending.context(record), report.valid).await {
+                failures.push(pending.violation(failure));
+            }
+        }
+        if failures.is_empty() {
+            return report;
+        }
+        let mut violations = report.violations;
+        violations.extend(failures);
+        ValidationReport::new(violations)
+    }
+}
+
+fn string_param<'a>(params: &'a Value, name: &str) -> Result<&'a str, String> {
+    params
+        .get(name)
+        .and_then(Value::as_str)
+        .ok_or_else(|| format!("missing string parameter \"{}\"", name))
+}
+
+// 跨字段规则：after 字段的值不能早于 before 字段，
+// 如 { "before": "start_date", "after": "end_date", "strict": true }。
+// 字段名相对引用规则的对象，也可以写成相对整条记录的 JSON Pointer（如 "/contract/start"）。
+// 数字按数值比较，字符串按字典序比较（适用于 ISO 8601 日期和时间）；缺少任一字段时不检查。
+struct OrderedFields;
+
+#[async_trait]
+impl Validator for OrderedFields {
+    async fn validate(&self, context: &RuleContext<'_>) -> Result<(), RuleFailure> {
+        let (Ok(before), Ok(after)) = (string_param(context.params, "before"), string_param(context.params, "after")) else {
+            return Ok(());
+        };
+        let (Some(first), Some(second)) = (context.lookup(before), context.lookup(after)) else {
+            return Ok(());
+        };
+        let strict = context.params.get("strict").and_then(Value::as_bool).unwrap_or(false);
+
+        let ordering = match (first, second) {
+            (Value::Number(a), Value::Number(b)) => a.as_f64().partial_cmp(&b.as_f64()),
+            (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+            _ => None,
+        };
+        let ok = match ordering {
+            Some(std::cmp::Ordering::Less) => true,
+            Some(std::cmp::Ordering::Equal) => !strict,
+            Some(std::cmp::Ordering::Greater) => false,
+            None => {
+                return Err(RuleFailure::new(format!("\"{}\" and \"{}\" cannot be compared", before, after))
+                    .at(context.reference_path(after))
+                    .actual(second.clone()));
+            }
+        };
+        if ok {
+            return Ok(());
+        }
+        let relation = if strict { "after" } else { "on or after" };
+        Err(RuleFailure::new(format!("\"{}\" must be {} \"{}\"", after, relation, before))
+            .at(context.reference_path(after))
+            .expected(first.clone())
+            .actual(second.clone()))
+    }
+
+    fn check_params(&self, params: &Value) -> Result<(), String> {
+        string_param(params, "before")?;
+        string_param(params, "after")?;
+        Ok(())
+    }
+}
+
+// 邮箱域名必须在允许列表中，如 { "allow": ["example.com"] }，比较时忽略大小写
+struct EmailDomain;
+
+#[async_trait]
+impl Validator for EmailDomain {
+    async fn validate(&self, context: &RuleContext<'_>) -> Result<(), RuleFailure> {
+        // 非字符串的值交给 type 规则报告
+        let Some(email) = context.value.as_str() else {
+            return Ok(());
+        };
+        let allowed = context.params.get("allow").and_then(Value::as_array).map(Vec::as_slice).unwrap_or(&[]);
+        let domain = email.rsplit_once('@').map(|(_, domain)| domain.to_lowercase());
+        let permitted = domain.as_deref().is_some_and(|domain| {
+            allowed
+                .iter()
+                .filter_map(Value::as_str)
+                .any(|allowed| allowed.eq_ignore_ascii_case(domain))
+        });
+        if permitted {
+            return Ok(());
+        }
+        let message = match &domain {
+            Some(domain) => format!("email domain \"{}\" is not allowed", domain),
+            None => format!("\"{}\" is not an email address", email),
+        };
+        Err(RuleFailure::new(message)
+            .expected(Value::from(allowed.to_vec()))
+            .actual(Value::from(domain.unwrap_or_default())))
+    }
+
+    fn check_params(&self, params: &Value) -> Result<(), String> {
+        match params.get("allow").and_then(Value::as_array) {
+            Some(allowed) if allowed.iter().all(Value::is_string) => Ok(()),
+            _ => Err("\"allow\" must be a list of domains".to_string()),
+        }
+    }
+}
+
+// 本地的唯一值存储，可选地追加写入文件以便跨次运行保持唯一
+struct LocalStore {
+    keys: Mutex<StoreKeys>,
+    file: Option<Mutex<File>>,
+}
+
+#[derive(Default)]
+struct StoreKeys {
+    // 已提交的键，来自有效记录或存储文件
+    seen: HashSet<String>,
+    // 正在验证的记录预留的键
+    reserved: HashSet<String>,
+}
+
+impl LocalStore {
+    fn in_memory() -> Self {
+        LocalStore {
+            keys: Mutex::new(StoreKeys::default()),
+            file: None,
+        }
+    }
+
+    // 文件中每行一个键，不存在时创建
+    async fn open(path: &Path) -> io::Result<Self> {
+        let mut seen = HashSet::new();
+        match tokio::fs::read_to_string(path).await {
+            Ok(contents) => seen.extend(contents.lines().map(str::to_string)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+        Ok(LocalStore {
+            keys: Mutex::new(StoreKeys { seen, reserved: HashSet::new() }),
+            file: Some(Mutex::new(file)),
+        })
+    }
+
+    // 键既未提交也未被预留时预留并返回 true；检查和预留在同一把锁内完成，并发的重复值只有一个能通过
+    async fn reserve(&self, key: &str) -> bool {
+        let mut keys = self.keys.lock().await;
+        if keys.seen.contains(key) || keys.reserved.contains(key) {
+            return false;
+        }
+        keys.reserved.insert(key.to_string())
+    }
+
+    // 记录无效时撤销预留，之后的记录可以使用这个值
+    async fn release(&self, key: &str) {
+        self.keys.lock().await.reserved.remove(key);
+    }
+
+    // 记录有效时提交预留的键，写入失败时撤销预留
+    async fn commit(&self, key: &str) -> io::Result<()> {
+        let mut keys = self.keys.lock().await;
+        keys.reserved.remove(key);
+        if let Some(file) = &self.file {
+            let mut file = file.lock().await;
+            file.write_all(format!("{}\n", key).as_bytes()).await?;
+            file.flush().await?;
+        }
+        keys.seen.insert(key.to_string());
+        Ok(())
+    }
+}
+
+// 值在所有有效记录中唯一，params 中的 scope 可以让多个位置共享同一个唯一性范围，默认按位置区分。
+// 值在验证时预留，记录有效才提交，无效记录中的值不会占用。
+// 并行验证时，与仍在验证中的记录重复的值也报告违规，即使那条记录最终无效；
+// 需要确定的结果时使用 --workers 1。
+struct Unique {
+    store: LocalStore,
+}
+
+impl Unique {
+    // 序列化成 JSON 保证键中没有换行；null 不参与唯一性检查
+    fn key(context: &RuleContext<'_>) -> Option<String> {
+        if context.value.is_null() {
+            return None;
+        }
+        let scope = context.params.get("scope").and_then(Value::as_str).unwrap_or(context.path);
+        serde_json::to_string(&(scope, context.value)).ok()
+    }
+}
+
+#[async_trait]
+impl Validator for Unique {
+    async fn validate(&self, context: &RuleContext<'_>) -> Result<(), RuleFailure> {
+        let Some(key) = Unique::key(context) else {
+            return Ok(());
+        };
+        if self.store.reserve(&key).await {
+            return Ok(());
+        }
+        Err(RuleFailure::new(format!("value {} is not unique", context.value)).actual(context.value.clone()))
+    }
+
+    async fn finish(&self, context: &RuleContext<'_>, accepted: bool) -> Result<(), RuleFailure> {
+        let Some(key) = Unique::key(context) else {
+            return Ok(());
+        };
+        if !accepted {
+            self.store.release(&key).await;
+            return Ok(());
+        }
+        self.store
+            .commit(&key)
+            .await
+            .map_err(|e| RuleFailure::new(format!("uniqueness store is unavailable: {}", e)))
+    }
+}
+
+// 内置的自定义规则：ordered_fields、email_domain、unique
+fn builtin_validators(store: LocalStore) -> ValidatorRegistry {
+    let mut registry = ValidatorRegistry::default();
+    registry
+        .register("ordered_fields", OrderedFields)
+        .register("email_domain", EmailDomain)
+        .register("unique", Unique { store });
+    registry
+}
+
+// 批量验证的输入格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RecordFormat {
+    // 每行一个 JSON 文档
+    Ndjson,
+    // 首行为表头的 CSV，字段按 schema 中声明的类型转换
+    Csv,
+}
+
+impl RecordFormat {
+    // .json 通常是单个 JSON 文档而不是每行一条记录，需要用 --format 显式指定
+    fn from_path(path: &Path) -> Option<Self> {
+        path.extension().and_then(|ext| ext.to_str()).and_then(Self::from_name)
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ndjson" | "jsonl" => Some(RecordFormat::Ndjson),
+            "csv" => Some(RecordFormat::Csv),
+            _ => None,
+        }
+    }
+
+    // 记录无法解析时报告的规则名
+    fn syntax_rule(self) -> &'static str {
+        match self {
+            RecordFormat::Ndjson => "json",
+            RecordFormat::Csv => "csv",
+        }
+    }
+}
+
+// 批量验证的选项
+#[derive(Debug, Clone)]
+struct BatchOptions {
+    format: Option<RecordFormat>,
+    workers: usize,
+    // 每条记录的验证结果，以 NDJSON 写入
+    results: Option<PathBuf>,
+    // 有效和无效记录分别原样写入的文件
+    valid_output: Option<PathBuf>,
+    invalid_output: Option<PathBuf>,
+    top_rules: usize,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        BatchOptions {
+            format: None,
+            workers: std::thread::available_parallelism().map_or(4, |n| n.get()),
+            results: None,
+            valid_output: None,
+            invalid_output: None,
+            top_rules: 10,
+        }
+    }
+}
+
+// 每个 worker 在途的记录数，决定了批量验证的内存上限
+const RECORDS_PER_WORKER: usize = 64;
+
+// 单条记录的最大字节数，超出的记录报告为语法错误而不是继续读入内存
+const MAX_RECORD_BYTES: usize = 1 << 20;
+
+type BatchError = Box<dyn Error + Send + Sync>;
+
+// 从输入中读出的一条原始记录
+#[derive(Debug)]
+struct RawRecord {
+    // 从 1 开始的记录序号（不含 CSV 表头）
+    number: u64,
+    // 记录在文件中的起始行号
+    line: u64,
+    text: String,
+    // 读取时发现的问题（规则名, 说明），这样的记录不再解析
+    problem: Option<(&'static str, String)>,
+}
+
+impl RawRecord {
+    fn problem(line: u64, text: String, rule: &'static str, message: String) -> Self {
+        RawRecord { number: 0, line, text, problem: Some((rule, message)) }
+    }
+}
+
+// 一个物理行及其行号，超过 MAX_RECORD_BYTES 的行只保留行号
+type Line = (u64, Option<Vec<u8>>);
+
+// 按记录读取输入文件，CSV 引号内的换行不会拆开记录
+//
+// 每条记录最多 MAX_RECORD_BYTES 字节。未闭合的引号读到文件末尾或超出上限时，
+// 只把起始行报告为 csv 错误，其后的行放回 pending 重新按记录读取。
+struct RecordReader {
+    reader: BufReader<File>,
+    format: RecordFormat,
+    line: u64,
+    pending: VecDeque<Line>,
+}
+
+impl RecordReader {
+    async fn open(path: &Path, format: RecordFormat) -> io::Result<Self> {
+        Ok(RecordReader {
+            reader: BufReader::new(File::open(path).await?),
+            format,
+            line: 0,
+            pending: VecDeque::new(),
+        })
+    }
+
+    // 读取下一个物理行（含换行符），过长的行丢弃其内容
+    async fn next_line(&mut self) -> io::Result<Option<Line>> {
+        if let Some(line) = self.pending.pop_front() {
+            return Ok(Some(line));
+        }
+        let mut bytes = Vec::new();
+        let limit = MAX_RECORD_BYTES as u64 + 1;
+        if (&mut self.reader).take(limit).read_until(b'\n', &mut bytes).await? == 0 {
+            return Ok(None);
+        }
+        self.line += 1;
+        if bytes.len() <= MAX_RECORD_BYTES {
+            return Ok(Some((self.line, Some(bytes))));
+        }
+        // 跳过该行剩余的部分
+        while !bytes.ends_with(b"\n") {
+            bytes.clear();
+            if (&mut self.reader).take(limit).read_until(b'\n', &mut bytes).await? == 0 {
+                break;
+            }
+        }
+        Ok(Some((self.line, None)))
+    }
+
+    // 返回下一条记录，序号由调用方填写；原文保留结尾的换行符以便原样写出
+    async fn next_record(&mut self) -> io::Result<Option<RawRecord>> {
+        let rule = self.format.syntax_rule();
+        loop {
+            let Some((start, first)) = self.next_line().await? else {
+                return Ok(None);
+            };
+            let Some(mut bytes) = first else {
+                let message = format!("record exceeds {} bytes", MAX_RECORD_BYTES);
+                return Ok(Some(RawRecord::problem(start, String::new(), rule, message)));
+            };
+
+            if self.format == RecordFormat::Csv {
+                // 引号个数为奇数说明字段里有换行，继续读到引号闭合
+                let first_len = bytes.len();
+                let mut quotes = bytes.iter().filter(|&&b| b == b'"').count();
+                let mut continuation = Vec::new();
+                while quotes % 2 == 1 {
+                    match self.next_line().await? {
+                        Some((line, Some(more))) if bytes.len() + more.len() <= MAX_RECORD_BYTES => {
+                            quotes += more.iter().filter(|&&b| b == b'"').count();
+                            bytes.extend_from_slice(&more);
+                            continuation.push((line, Some(more)));
+                        }
+                        next => {
+                            let message = match next {
+                                None => "unterminated quoted field at end of file".to_string(),
+                                Some(_) => format!("quoted field exceeds {} bytes", MAX_RECORD_BYTES),
+                            };
+                            // 放回已读的行，只报告起始行
+                            for line in next.into_iter().chain(continuation.into_iter().rev()) {
+                                self.pending.push_front(line);
+                            }
+                            bytes.truncate(first_len);
+                            let text = String::from_utf8_lossy(&bytes).into_owned();
+                            return Ok(Some(RawRecord::problem(start, text, rule, message)));
+                        }
+                    }
+                }
+            }
+
+            match String::from_utf8(bytes) {
+                Ok(text) if text.trim().is_empty() => continue,
+                Ok(text) => return Ok(Some(RawRecord { number: 0, line: start, text, problem: None })),
+                Err(e) => {
+                    let message = format!("invalid UTF-8 at byte {}", e.utf8_error().valid_up_to());
+                    let text = String::from_utf8_lossy(e.as_bytes()).into_owned();
+                    return Ok(Some(RawRecord::problem(start, text, "encoding", message)));
+                }
+            }
+        }
+    }
+}
+
+// 按 RFC 4180 拆分一条 CSV 记录：引号内可以包含逗号和换行，"" 表示一个引号
+fn parse_csv_record(record: &str) -> Vec<String> {
+    let record = record.trim_end_matches(['\r', '\n']);
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = record.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+// 把 CSV 单元格转换成 schema 声明的类型，无法转换时保留字符串交给 type 规则报告
+fn coerce_csv_field(schema: Option<&Schema>, text: &str) -> Value {
+    let types = schema.and_then(|s| s.types.as_ref()).map(TypeSet::types).unwrap_or(&[]);
+    for t in types {
+        let value = match t {
+            JsonType::Integer => text.parse::<i64>().ok().map(Value::from),
+            JsonType::Number => text.parse::<f64>().ok().and_then(|n| serde_json::Number::from_f64(n).map(Value::Number)),
+            JsonType::Boolean => text.parse::<bool>().ok().map(Value::Bool),
+            JsonType::Null if text == "null" => Some(Value::Null),
+            _ => None,
+        };
+        if let Some(value) = value {
+            return value;
+        }
+    }
+    Value::from(text)
+}
+
+// 无法解析的记录只产生一条位于根的违规
+fn syntax_report(rule: &str, message: String) -> ValidationReport {
+    ValidationReport::new(vec![Violation {
+        path: String::new(),
+        rule: rule.to_string(),
+        severity: Severity::Error,
+        message,
+        expected: Value::Null,
+        actual: Value::Null,
+    }])
+}
+
+// 解析并验证一条原始记录
+async fn validate_record(validator: &DataValidator, format: RecordFormat, header: &[String], text: &str) -> ValidationReport {
+    match format {
+        RecordFormat::Ndjson => match serde_json::from_str::<Value>(text) {
+            Ok(value) => validator.validate(&value).await,
+            Err(e) => syntax_report("json", e.to_string()),
+        },
+        RecordFormat::Csv => {
+            let fields = parse_csv_record(text);
+            if fields.len() != header.len() {
+                return syntax_report(
+                    "csv",
+                    format!("expected {} fields, found {}", header.len(), fields.len()),
+                );
+            }
+            // 空单元格视为缺少该字段，由 required 规则判断
+            let object = header
+                .iter()
+                .zip(fields)
+                .filter(|(_, text)| !text.is_empty())
+                .map(|(name, text)| {
+                    let value = coerce_csv_field(validator.schema.properties.get(name), &text);
+                    (name.clone(), value)
+                })
+                .collect();
+            validator.validate(&Value::Object(object)).await
+        }
+    }
+}
+
+// 一条记录的验证结果，写入 results 文件
+#[derive(Serialize)]
+struct RecordResult<'a> {
+    record: u64,
+    line: u64,
+    #[serde(flatten)]
+    report: &'a ValidationReport,
+}
+
+// 规则的违规次数
+#[derive(Serialize, Debug, Clone)]
+struct RuleCount {
+    rule: String,
+    violations: u64,
+}
+
+// 批量验证的汇总统计
+#[derive(Serialize, Debug, Clone, Default)]
+struct BatchSummary {
+    total: u64,
+    valid: u64,
+    invalid: u64,
+    // 有效但带有 warning 的记录数
+    with_warnings: u64,
+    top_rules: Vec<RuleCount>,
+}
+
+impl fmt::Display for BatchSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} records: {} valid, {} invalid, {} valid with warnings",
+            self.total, self.valid, self.invalid, self.with_warnings
+        )?;
+        for rule in &self.top_rules {
+            write!(f, "\n  {}: {} violation(s)", rule.rule, rule.violations)?;
+        }
+        Ok(())
+    }
+}
+
+async fn create_output(path: &Option<PathBuf>) -> io::Result<Option<BufWriter<File>>> {
+    match path {
+        Some(path) => Ok(Some(BufWriter::new(File::create(path).await?))),
+        None => Ok(None),
+    }
+}
+
+async fn write_record(output: &mut Option<BufWriter<File>>, text: &str) -> io::Result<()> {
+    if let Some(output) = output {
+        output.write_all(text.as_bytes()).await?;
+        if !text.ends_with('\n') {
+            output.write_all(b"\n").await?;
+        }
+    }
     Ok(())
 }
 
+// 流式验证 NDJSON 或 CSV 文件
+//
+// 读取、验证和写出由独立的任务完成，之间用有界通道连接，
+// 因此内存占用只取决于 worker 数量而与文件大小无关。
+// 结果按验证完成的顺序写出，每条结果都带有记录序号和行号。
+async fn validate_file(validator: DataValidator, input: &Path, options: &BatchOptions) -> Result<BatchSummary, BatchError> {
+    let format = options
+        .format
+        .or_else(|| RecordFormat::from_path(input))
+        .ok_or_else(|| format!("Cannot tell the record format of {}; use --format ndjson or --format csv", input.display()))?;
+    let mut reader = RecordReader::open(input, format).await?;
+
+    let mut header_text = None;
+    let header = match format {
+        RecordFormat::Csv => match reader.next_record().await? {
+            Some(RawRecord { problem: Some((_, message)), .. }) => {
+                return Err(format!("Invalid CSV header: {}", message).into());
+            }
+            Some(record) => {
+                let header = parse_csv_record(&record.text);
+                header_text = Some(record.text);
+                header
+            }
+            None => Vec::new(),
+        },
+        RecordFormat::Ndjson => Vec::new(),
+    };
+    let header = Arc::new(header);
+
+    let workers = options.workers.max(1);
+    let (record_tx, record_rx) = mpsc::channel::<RawRecord>(workers * RECORDS_PER_WORKER);
+    let (result_tx, mut result_rx) = mpsc::channel::<(RawRecord, ValidationReport)>(workers * RECORDS_PER_WORKER);
+
+    let reader_task = tokio::spawn(async move {
+        let mut number = 0;
+        while let Some(mut record) = reader.next_record().await? {
+            number += 1;
+            record.number = number;
+            if record_tx.send(record).await.is_err() {
+                break;
+            }
+        }
+        Ok::<_, io::Error>(())
+    });
+
+    let record_rx = Arc::new(Mutex::new(record_rx));
+    let mut worker_tasks = Vec::with_capacity(workers);
+    for _ in 0..workers {
+        let record_rx = Arc::clone(&record_rx);
+        let result_tx = result_tx.clone();
+        let validator = validator.clone();
+        let header = Arc::clone(&header);
+        worker_tasks.push(tokio::spawn(async move {
+            loop {
+                let record = record_rx.lock().await.recv().await;
+                let Some(record) = record else {
+                    break;
+                };
+                let report = match &record.problem {
+                    Some((rule, message)) => syntax_report(rule, message.clone()),
+                    None => validate_record(&validator, format, &header, &record.text).await,
+                };
+                if result_tx.send((record, report)).await.is_err() {
+                    break;
+                }
+            }
+        }));
+    }
+    drop(result_tx);
+
+    let mut results = create_output(&options.results).await?;
+    let mut valid_output = create_output(&options.valid_output).await?;
+    let mut invalid_output = create_output(&options.invalid_output).await?;
+    if let Some(header_text) = &header_text {
+        write_record(&mut valid_output, header_text).await?;
+        write_record(&mut invalid_output, header_text).await?;
+    }
+
+    let mut summary = BatchSummary::default();
+    let mut rule_counts: HashMap<String, u64> = HashMap::new();
+    while let Some((record, report)) = result_rx.recv().await {
+        summary.total += 1;
+        if report.valid {
+            summary.valid += 1;
+            if report.warning_count > 0 {
+                summary.with_warnings += 1;
+            }
+            write_record(&mut valid_output, &record.text).await?;
+        } else {
+            summary.invalid += 1;
+            write_record(&mut invalid_output, &record.text).await?;
+        }
+        for violation in &report.violations {
+            *rule_counts.entry(violation.rule.clone()).or_default() += 1;
+        }
+
+        if let Some(results) = &mut results {
+            let result = RecordResult {
+                record: record.number,
+                line: record.line,
+                report: &report,
+            };
+            let mut line = serde_json::to_string(&result)?;
+            line.push('\n');
+            results.write_all(line.as_bytes()).await?;
+        }
+    }
+
+    // 先写出已有的结果，再检查读取任务和 worker 是否失败，
+    // 崩溃的 worker 会丢失记录，因此整次运行报错而不是给出不完整的统计
+    for output in [&mut results, &mut valid_output, &mut invalid_output].into_iter().flatten() {
+        output.flush().await?;
+    }
+    reader_task.await??;
+    for worker in worker_tasks {
+        worker.await.map_err(|e| format!("Validation worker failed: {}", e))?;
+    }
+
+    let mut top_rules: Vec<RuleCount> = rule_counts
+        .into_iter()
+        .map(|(rule, violations)| RuleCount { rule, violations })
+        .collect();
+    top_rules.sort_by(|a, b| b.violations.cmp(&a.violations).then_with(|| a.rule.cmp(&b.rule)));
+    top_rules.truncate(options.top_rules);
+    summary.top_rules = top_rules;
+
+    Ok(summary)
+}
+
+// 内置的示例 schema，对应原先硬编码的规则：名称不能为空，年龄在 0 到 120 之间
+const DEFAULT_SCHEMA: &str = r#"{
+    "type": "object",
+    "required": ["name", "age"],
+    "properties": {
+        "name": { "type": "string", "minLength": 1 },
+        "age": { "type": "integer", "minimum": 0, "maximum": 120 }
+    }
+}"#;
+
+// 创建一个函数来验证数据
+async fn validate_data(validator: &DataValidator, data_str: &str) -> Result<ValidationReport, Box<dyn Error>> {
+    let data: Value = serde_json::from_str(data_str)?;
+    Ok(validator.validate(&data).await)
+}
+
 #[tokio::main]
-async fn main() {
+async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+    // 用法：
+    //   data_validator [schema.json|schema.toml] [data.json]
+    //   data_validator <schema> --batch <records.ndjson|records.jsonl|records.csv> [--format ndjson|csv]
+    //                  [--workers N] [--results results.ndjson] [--valid valid.out] [--invalid invalid.out]
+    // 不指定 --format 时按扩展名判断格式。
+    // --store 指定 unique 规则使用的存储文件，不指定时只在本次运行内保证唯一。
+    // 只有有效记录的值会写入存储；并行验证时 unique 的结果取决于记录完成的先后，需要确定的结果时使用 --workers 1
+    let mut positional = Vec::new();
+    let mut batch = None;
+    let mut store = None;
+    let mut options = BatchOptions::default();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} requires a value", arg));
+        match arg.as_str() {
+            "--batch" => batch = Some(PathBuf::from(value()?)),
+            "--format" => {
+                let name = value()?;
+                options.format = Some(RecordFormat::from_name(&name).ok_or_else(|| format!("Unknown record format: {}", name))?);
+            }
+            "--workers" => options.workers = value()?.parse()?,
+            "--results" => options.results = Some(PathBuf::from(value()?)),
+            "--valid" => options.valid_output = Some(PathBuf::from(value()?)),
+            "--invalid" => options.invalid_output = Some(PathBuf::from(value()?)),
+            "--store" => store = Some(PathBuf::from(value()?)),
+            _ => positional.push(arg),
+        }
+    }
+    let mut positional = positional.into_iter();
+
+    let schema = match positional.next() {
+        Some(path) => Schema::from_file(Path::new(&path)).await.map_err(|e| e.to_string())?,
+        None => serde_json::from_str(DEFAULT_SCHEMA)?,
+    };
+    let store = match store {
+        Some(path) => LocalStore::open(&path).await?,
+        None => LocalStore::in_memory(),
+    };
+    let validator = DataValidator::new(schema, builtin_validators(store))?;
+
+    if let Some(input) = batch {
+        let summary = validate_file(validator, &input, &options).await?;
+        eprintln!("{}", summary);
+        println!("{}", serde_json::to_string_pretty(&summary)?);
+        return Ok(());
+    }
+
     // 测试数据，这部分代码应该被替换为实际的数据源
-    let test_data = r#"{
+    let test_data = match positional.next() {
+        Some(path) => tokio::fs::read_to_string(path).await?,
+        None => r#"{
         "name": "John Doe",
         "age": 30
-    }"#;
-    
+    }"#
+        .to_string(),
+    };
+
     // 调用验证函数并处理可能的错误
-    match validate_data(test_data).await {
-        Ok(_) => println!("Data is valid"),
+    match validate_data(&validator, &test_data).await {
+        Ok(report) => {
+            eprintln!("{}", report);
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
         Err(e) => println!("Error validating data: {}", e),
     }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn validator(schema: &str) -> DataValidator {
+        let schema: Schema = serde_json::from_str(schema).unwrap();
+        DataValidator::new(schema, builtin_validators(LocalStore::in_memory())).unwrap()
+    }
+
+    // 违规的 (path, rule)，按出现顺序
+    fn violated(report: &ValidationReport) -> Vec<(&str, &str)> {
+        report.violations.iter().map(|v| (v.path.as_str(), v.rule.as_str())).collect()
+    }
+
+    // 在临时目录中写入测试文件
+    fn temp_file(name: &str, contents: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("data_validator_{}_{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    async fn read_all(path: &Path, format: RecordFormat) -> Vec<RawRecord> {
+        let mut reader = RecordReader::open(path, format).await.unwrap();
+        let mut records = Vec::new();
+        while let Some(record) = reader.next_record().await.unwrap() {
+            records.push(record);
+        }
+        records
+    }
+
+    #[tokio::test]
+    async fn schema_keywords_report_every_violation() {
+        let validator = validator(
+            r#"{
+                "type": "object",
+                "required": ["id", "name"],
+                "additionalProperties": false,
+                "properties": {
+                    "id": {"type": "integer"},
+                    "name": {"type": "string", "minLength": 2, "maxLength": 4, "pattern": "^[A-Z]"},
+                    "age": {"type": "integer", "minimum": 0, "maximum": 150},
+                    "score": {"type": "number", "exclusiveMinimum": 0, "exclusiveMaximum": 1},
+                    "status": {"enum": ["active", "disabled"]},
+                    "tags": {"type": "array", "minItems": 1, "maxItems": 3, "uniqueItems": true, "items": {"type": "string"}},
+                    "a/b": {"type": ["string", "null"]}
+                }
+            }"#,
+        );
+
+        let report = validator
+            .validate(&json!({
+                "name": "x",
+                "age": 200,
+                "score": 1,
+                "status": "gone",
+                "tags": ["a", 2, "a", "b"],
+                "a/b": 3,
+                "extra": true
+            }))
+            .await;
+        assert!(!report.valid);
+        assert_eq!(
+            violated(&report),
+            vec![
+                ("/id", "required"),
+                ("/a~1b", "type"),
+                ("/age", "maximum"),
+                ("/extra", "additionalProperties"),
+                ("/name", "minLength"),
+                ("/name", "pattern"),
+                ("/score", "exclusiveMaximum"),
+                ("/status", "enum"),
+                ("/tags", "maxItems"),
+                ("/tags/2", "uniqueItems"),
+                ("/tags/1", "type"),
+            ]
+        );
+        assert_eq!(report.error_count, 11);
+
+        let report = validator
+            .validate(&json!({"id": 1, "name": "Ann", "age": 30.0, "score": 0.5, "tags": ["a"], "a/b": null}))
+            .await;
+        assert!(report.valid, "{}", report);
+        assert!(!validator.validate(&json!([1])).await.valid);
+    }
+
+    #[tokio::test]
+    async fn violations_carry_expected_and_actual_values() {
+        let report = validator(r#"{"properties": {"age": {"minimum": 18}, "kind": {"type": ["string", "null"]}}}"#)
+            .validate(&json!({"age": 10.5, "kind": 1}))
+            .await;
+        let violations: Vec<Value> = report.violations.iter().map(|v| serde_json::to_value(v).unwrap()).collect();
+        assert_eq!(
+            violations,
+            vec![
+                json!({
+                    "path": "/age",
+                    "rule": "minimum",
+                    "severity": "error",
+                    "message": "10.5 is less than the minimum of 18",
+                    "expected": 18,
+                    "actual": 10.5
+                }),
+                json!({
+                    "path": "/kind",
+                    "rule": "type",
+                    "severity": "error",
+                    "message": "expected string or null, found integer",
+                    "expected": ["string", "null"],
+                    "actual": "integer"
+                }),
+            ]
+        );
+        assert_eq!(report.violations[0].to_string(), "[error] /age: 10.5 is less than the minimum of 18 (minimum)");
+    }
+
+    #[tokio::test]
+    async fn warnings_do_not_invalidate_records() {
+        let validator = validator(
+            r#"{
+                "properties": {
+                    "nickname": {"maxLength": 3, "severity": "warning"},
+                    "age": {"minimum": 18}
+                }
+            }"#,
+        );
+        let report = validator.validate(&json!({"nickname": "Johnny", "age": 20})).await;
+        assert!(report.valid);
+        assert_eq!((report.error_count, report.warning_count), (0, 1));
+        assert_eq!(report.violations[0].severity, Severity::Warning);
+
+        let report = validator.validate(&json!({"nickname": "Johnny", "age": 10})).await;
+        assert!(!report.valid);
+        assert_eq!((report.error_count, report.warning_count), (1, 1));
+        assert!(report.to_string().ends_with("invalid: 1 error(s), 1 warning(s)"));
+    }
+
+    #[tokio::test]
+    async fn numbers_compare_by_value() {
+        // 1.0 既是整数也等于枚举中的 1
+        let integer = validator(r#"{"type": "integer", "enum": [1, 2]}"#);
+        assert!(integer.validate(&json!(1.0)).await.valid);
+        assert!(!integer.validate(&json!(3)).await.valid);
+
+        let unique = validator(r#"{"uniqueItems": true}"#);
+        assert!(!unique.validate(&json!([[1, {"a": 2}], [1.0, {"a": 2.0}]])).await.valid);
+        assert!(unique.validate(&json!([{"a": 1}, {"a": 1, "b": 2}])).await.valid);
+        // minimum 等关键字不作用于其他类型的值
+        assert!(validator(r#"{"minimum": 5, "minLength": 3}"#).validate(&json!("abc")).await.valid);
+    }
+
+    #[test]
+    fn toml_schemas_accept_snake_case_keywords() {
+        let schema: Schema = toml::from_str(
+            r#"
+            required = ["name"]
+            additional_properties = false
+
+            [properties.name]
+            type = "string"
+            min_length = 2
+            severity = "warning"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(schema.additional_properties, Some(false));
+        assert_eq!(schema.properties["name"].min_length, Some(2));
+        assert_eq!(schema.properties["name"].severity, Severity::Warning);
+        assert_eq!(schema.collect(&json!({"name": "A"})).violations.len(), 1);
+        assert!(serde_json::from_str::<Schema>(r#"{"pattern": "("}"#).is_err());
+        assert!(serde_json::from_str::<Schema>(r#"{"type": "decimal"}"#).is_err());
+    }
+
+    #[test]
+    fn parses_rfc4180_quoting() {
+        assert_eq!(parse_csv_record("a,b,c\r\n"), vec!["a", "b", "c"]);
+        assert_eq!(parse_csv_record("\"x, y\",\"say \"\"hi\"\"\",\n"), vec!["x, y", "say \"hi\"", ""]);
+        assert_eq!(parse_csv_record("\"multi\nline\",2\n"), vec!["multi\nline", "2"]);
+        assert_eq!(parse_csv_record(""), vec![""]);
+    }
+
+    #[tokio::test]
+    async fn quoted_newlines_stay_in_one_record() {
+        let path = temp_file("multiline.csv", b"name,note\nAl,\"two\nlines\"\n\nBo,plain\n");
+        let records = read_all(&path, RecordFormat::Csv).await;
+        let _ = std::fs::remove_file(&path);
+        // 空行被跳过，行号仍按文件计算
+        let lines: Vec<u64> = records.iter().map(|record| record.line).collect();
+        assert_eq!(lines, vec![1, 2, 5]);
+        assert_eq!(records[1].text, "Al,\"two\nlines\"\n");
+        assert!(records.iter().all(|record| record.problem.is_none()));
+    }
+
+    #[tokio::test]
+    async fn csv_fields_are_coerced_to_schema_types() {
+        let validator = validator(
+            r#"{
+                "required": ["name", "age"],
+                "properties": {"age": {"type": "integer"}, "ratio": {"type": ["number", "null"]}, "ok": {"type": "boolean"}}
+            }"#,
+        );
+        let header: Vec<String> = ["name", "age", "ratio", "ok"].iter().map(ToString::to_string).collect();
+        let report = validate_record(&validator, RecordFormat::Csv, &header, "Al,42,0.5,true\n").await;
+        assert!(report.valid, "{}", report);
+
+        let report = validate_record(&validator, RecordFormat::Csv, &header, "Al,,null,yes\n").await;
+        assert_eq!(violated(&report), vec![("/age", "required"), ("/ok", "type")]);
+
+        let report = validate_record(&validator, RecordFormat::Csv, &header, "Al,42\n").await;
+        assert_eq!(violated(&report), vec![("", "csv")]);
+        let report = validate_record(&validator, RecordFormat::Ndjson, &[], "{\"name\": ").await;
+        assert_eq!(violated(&report), vec![("", "json")]);
+    }
+
+    #[tokio::test]
+    async fn batch_validation_splits_records_and_summarizes_rules() {
+        let path = temp_file(
+            "batch.ndjson",
+            b"{\"name\": \"Al\", \"age\": 30}\n{\"age\": -1}\nnot json\n\n{\"name\": \"Bo\", \"age\": 200}\n{\"name\": \"Cy\"}\n",
+        );
+        let options = BatchOptions {
+            format: None,
+            workers: 3,
+            results: Some(path.with_extension("results")),
+            valid_output: Some(path.with_extension("valid")),
+            invalid_output: Some(path.with_extension("invalid")),
+            top_rules: 2,
+        };
+        let schema = r#"{"required": ["name"], "properties": {"age": {"type": "integer", "minimum": 0, "maximum": 150}}}"#;
+        let summary = validate_file(validator(schema), &path, &options).await.unwrap();
+        let read = |extension: &str| std::fs::read_to_string(path.with_extension(extension)).unwrap();
+        let (results, valid, invalid) = (read("results"), read("valid"), read("invalid"));
+        for extension in ["ndjson", "results", "valid", "invalid"] {
+            let _ = std::fs::remove_file(path.with_extension(extension));
+        }
+
+        assert_eq!((summary.total, summary.valid, summary.invalid), (5, 2, 3));
+        let top: Vec<(&str, u64)> = summary.top_rules.iter().map(|r| (r.rule.as_str(), r.violations)).collect();
+        assert_eq!(top, vec![("json", 1), ("maximum", 1)]);
+
+        // 结果按完成顺序写出，按记录序号排序后检查行号
+        let mut results: Vec<Value> = results.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        results.sort_by_key(|result| result["record"].as_u64());
+        let lines: Vec<u64> = results.iter().map(|result| result["line"].as_u64().unwrap()).collect();
+        assert_eq!(lines, vec![1, 2, 3, 5, 6]);
+        assert_eq!(results[1]["violations"].as_array().unwrap().len(), 2);
+
+        let mut valid: Vec<&str> = valid.lines().collect();
+        valid.sort_unstable();
+        assert_eq!(valid, vec!["{\"name\": \"Al\", \"age\": 30}", "{\"name\": \"Cy\"}"]);
+        assert_eq!(invalid.lines().count(), 3);
+
+        let unknown = temp_file("batch.txt", b"{}\n");
+        assert!(validate_file(validator("{}"), &unknown, &BatchOptions::default()).await.is_err());
+        let _ = std::fs::remove_file(&unknown);
+    }
+
+    #[tokio::test]
+    async fn json_files_need_an_explicit_format() {
+        assert_eq!(RecordFormat::from_path(Path::new("a.jsonl")), Some(RecordFormat::Ndjson));
+        assert_eq!(RecordFormat::from_path(Path::new("a.ndjson")), Some(RecordFormat::Ndjson));
+        assert_eq!(RecordFormat::from_path(Path::new("a.csv")), Some(RecordFormat::Csv));
+        assert_eq!(RecordFormat::from_path(Path::new("a.json")), None);
+        assert_eq!(RecordFormat::from_name("json"), None);
+
+        let path = temp_file("records.json", b"{\"name\": \"Al\"}\n{\"name\": \"Bo\"}\n");
+        assert!(validate_file(validator("{}"), &path, &BatchOptions::default()).await.is_err());
+        let options = BatchOptions { format: Some(RecordFormat::Ndjson), ..BatchOptions::default() };
+        let summary = validate_file(validator("{}"), &path, &options).await.unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!((summary.total, summary.valid), (2, 2));
+    }
+
+    #[tokio::test]
+    async fn custom_validators_check_params_and_fields() {
+        let validator = validator(
+            r#"{
+                "properties": {
+                    "email": {"validators": [{"name": "email_domain", "params": {"allow": ["Example.com"]}}]},
+                    "period": {
+                        "validators": [{"name": "ordered_fields", "params": {"before": "/created", "after": "end", "strict": true}}]
+                    }
+                }
+            }"#,
+        );
+        let report = validator
+            .validate(&json!({
+                "email": "ann@example.COM",
+                "created": "2024-01-01",
+                "period": {"end": "2024-02-01"}
+            }))
+            .await;
+        assert!(report.valid, "{}", report);
+
+        let report = validator
+            .validate(&json!({
+                "email": "ann@elsewhere.org",
+                "created": "2024-01-01",
+                "period": {"end": "2024-01-01"}
+            }))
+            .await;
+        assert_eq!(violated(&report), vec![("/email", "email_domain"), ("/period/end", "ordered_fields")]);
+        assert_eq!(report.violations[1].expected, Value::from("2024-01-01"));
+
+        // 缺少字段时不检查
+        assert!(validator.validate(&json!({"period": {}})).await.valid);
+
+        let registry = || builtin_validators(LocalStore::in_memory());
+        let schema = |json: &str| serde_json::from_str::<Schema>(json).unwrap();
+        assert!(DataValidator::new(schema(r#"{"validators": ["no_such_rule"]}"#), registry()).is_err());
+        assert!(DataValidator::new(schema(r#"{"validators": [{"name": "email_domain", "params": {"allow": "x"}}]}"#), registry()).is_err());
+        assert!(DataValidator::new(schema(r#"{"validators": [{"name": "ordered_fields", "params": {"before": "a"}}]}"#), registry()).is_err());
+    }
+
+    #[tokio::test]
+    async fn unique_values_persist_in_the_store_file() {
+        let schema = r#"{
+            "properties": {
+                "id": {"validators": ["unique"]},
+                "email": {"validators": [{"name": "unique", "params": {"scope": "contact"}, "severity": "warning"}]},
+                "backup_email": {"validators": [{"name": "unique", "params": {"scope": "contact"}}]}
+            }
+        }"#;
+        let path = temp_file("unique.store", b"");
+        let open = || async {
+            let store = LocalStore::open(&path).await.unwrap();
+            DataValidator::new(serde_json::from_str(schema).unwrap(), builtin_validators(store)).unwrap()
+        };
+
+        let validator = open().await;
+        assert!(validator.validate(&json!({"id": 1, "email": "a@x.org"})).await.valid);
+        // 同一 scope 内的不同字段共享唯一性，违规的严重程度取自引用处
+        let report = validator.validate(&json!({"id": 2, "email": "b@x.org", "backup_email": "a@x.org"})).await;
+        assert_eq!(violated(&report), vec![("/backup_email", "unique")]);
+        // 上一条记录无效，其中的值没有被占用
+        let report = validator.validate(&json!({"id": 3, "email": "b@x.org"})).await;
+        assert!(report.violations.is_empty());
+        let report = validator.validate(&json!({"id": 4, "email": "b@x.org"})).await;
+        assert_eq!((report.error_count, report.warning_count), (0, 1));
+        assert!(validator.validate(&json!({"id": null})).await.valid);
+        drop(validator);
+
+        // 重新打开存储后仍能识别之前的值；不同 scope 中的相同值互不影响
+        let validator = open().await;
+        let report = validator.validate(&json!({"id": 1, "email": "c@x.org"})).await;
+        assert_eq!(violated(&report), vec![("/id", "unique")]);
+        assert!(validator.validate(&json!({"id": 5, "email": "1"})).await.valid);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn unterminated_quote_reports_only_its_record() {
+        let path = temp_file(
+            "unterminated.csv",
+            b"name,email\nAl,a@example.com\nBo,\"b@example.com\nCy,c@example.com\nDi,d@example.com\n",
+        );
+        let summary = validate_file(validator(r#"{"required": ["name"]}"#), &path, &BatchOptions::default()).await.unwrap();
+        let records = read_all(&path, RecordFormat::Csv).await;
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!((summary.total, summary.valid, summary.invalid), (4, 3, 1));
+        assert_eq!(records[2].problem.as_ref().map(|(rule, _)| *rule), Some("csv"));
+        assert_eq!(records[2].text, "Bo,\"b@example.com\n");
+        assert_eq!(records[3].text, "Cy,c@example.com\n");
+        assert_eq!(records[3].line, 4);
+    }
+
+    #[tokio::test]
+    async fn oversized_records_are_reported_and_skipped() {
+        let mut contents = b"{\"name\": \"a\"}\n{\"name\": \"".to_vec();
+        contents.extend(std::iter::repeat_n(b'x', MAX_RECORD_BYTES + 10));
+        contents.extend_from_slice(b"\"}\n{\"name\": \"c\"}\n");
+        let path = temp_file("oversized.ndjson", &contents);
+        let records = read_all(&path, RecordFormat::Ndjson).await;
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[1].problem.as_ref().map(|(rule, _)| *rule), Some("json"));
+        assert!(records[1].text.is_empty());
+        assert_eq!((records[2].line, records[2].text.as_str()), (3, "{\"name\": \"c\"}\n"));
+    }
+
+    #[tokio::test]
+    async fn invalid_utf8_is_an_encoding_violation() {
+        let path = temp_file("encoding.csv", b"name,email\nAl,a@example.com\nB\xff\xfe,b@example.com\nCy,c@example.com\n");
+        let options = BatchOptions { results: Some(path.with_extension("results")), ..BatchOptions::default() };
+        let summary = validate_file(validator(r#"{"required": ["name"]}"#), &path, &options).await.unwrap();
+        let results = std::fs::read_to_string(path.with_extension("results")).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(path.with_extension("results"));
+
+        assert_eq!((summary.total, summary.valid, summary.invalid), (3, 2, 1));
+        assert_eq!(summary.top_rules[0].rule, "encoding");
+        assert_eq!(results.lines().count(), 3);
+    }
+
+    #[tokio::test]
+    async fn rejected_records_do_not_consume_unique_values() {
+        let path = std::env::temp_dir().join(format!("data_validator_{}_unique.store", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let schema: Schema = serde_json::from_str(
+            r#"{"properties": {"id": {"validators": [{"name": "unique"}]}, "age": {"type": "integer"}}}"#,
+        )
+        .unwrap();
+        let store = LocalStore::open(&path).await.unwrap();
+        let validator = DataValidator::new(schema, builtin_validators(store)).unwrap();
+
+        let rejected = validator.validate(&serde_json::json!({"id": 1, "age": "old"})).await;
+        let accepted = validator.validate(&serde_json::json!({"id": 1, "age": 30})).await;
+        let duplicate = validator.validate(&serde_json::json!({"id": 1, "age": 31})).await;
+        let stored = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert!(!rejected.valid);
+        assert!(accepted.valid, "{}", accepted);
+        assert!(!duplicate.valid);
+        assert_eq!(duplicate.violations[0].rule, "unique");
+        assert_eq!(stored.lines().count(), 1);
+    }
 }